@@ -0,0 +1,11 @@
+-- Sessions get a creation time for the absolute timeout and a public id so devices can be
+-- listed and revoked without handing the session token itself back to the dashboard.
+ALTER TABLE lunar_buffxnte_psu.sessions
+    ADD COLUMN IF NOT EXISTS created_at BIGINT,
+    ADD COLUMN IF NOT EXISTS public_id VARCHAR(16);
+
+UPDATE lunar_buffxnte_psu.sessions SET created_at = last_activity WHERE created_at IS NULL;
+UPDATE lunar_buffxnte_psu.sessions SET public_id = substr(md5(random()::text), 1, 16) WHERE public_id IS NULL;
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON lunar_buffxnte_psu.sessions (user_id);
+CREATE INDEX IF NOT EXISTS sessions_last_activity_idx ON lunar_buffxnte_psu.sessions (last_activity);
@@ -0,0 +1,23 @@
+-- TOTP secret (base32) for the user. `two_factor_options` is set to 'totp' once enrolment
+-- has been confirmed, until then the secret is pending. `two_factor_last_step` stops a code
+-- from being replayed inside its 30 second window.
+ALTER TABLE lunar_buffxnte_psu.users
+    ADD COLUMN IF NOT EXISTS two_factor_secret VARCHAR(64),
+    ADD COLUMN IF NOT EXISTS two_factor_last_step BIGINT;
+
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.two_factor_recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_id VARCHAR(40) NOT NULL,
+    code_hash VARCHAR(60) NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS two_factor_recovery_codes_user_id_idx
+    ON lunar_buffxnte_psu.two_factor_recovery_codes (user_id);
+
+-- Issued by login when the password was correct but a second factor is still needed.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.two_factor_challenges (
+    id VARCHAR(40) PRIMARY KEY,
+    user_id VARCHAR(40) NOT NULL,
+    created_at BIGINT NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.email_verifications (
+    token VARCHAR(64) PRIMARY KEY,
+    user_id VARCHAR(40) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS email_verifications_user_id_idx
+    ON lunar_buffxnte_psu.email_verifications (user_id);
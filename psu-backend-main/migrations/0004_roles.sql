@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.roles (
+    id BIGSERIAL PRIMARY KEY,
+    name VARCHAR(64) NOT NULL UNIQUE,
+    description TEXT NOT NULL DEFAULT '',
+    permissions TEXT[] NOT NULL DEFAULT '{}',
+    level BIGINT NOT NULL DEFAULT 0
+);
+
+-- Role 2 has always been the one new users get, keep it around for existing accounts.
+INSERT INTO lunar_buffxnte_psu.roles (id, name, description, permissions, level)
+    VALUES (1, 'Administrator', 'Full access', '{system.admin}', 100),
+           (2, 'User', 'Default role for new accounts', '{}', 0)
+    ON CONFLICT (id) DO NOTHING;
+
+SELECT setval(pg_get_serial_sequence('lunar_buffxnte_psu.roles', 'id'),
+    GREATEST((SELECT MAX(id) FROM lunar_buffxnte_psu.roles), 2));
@@ -0,0 +1,10 @@
+-- Per-user overrides on top of the role's permissions. A leading '-' makes the entry a deny,
+-- e.g. '-user.premium.*'.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.user_permissions (
+    id SERIAL PRIMARY KEY,
+    user_id VARCHAR(40) NOT NULL,
+    permission VARCHAR(128) NOT NULL,
+    granted_by VARCHAR(40),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (user_id, permission)
+);
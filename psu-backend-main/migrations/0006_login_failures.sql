@@ -0,0 +1,8 @@
+-- Failed login counters. Keys are 'account:<submitted username or email>' and 'ip:<address>',
+-- so rows exist for names that don't belong to any account and reveal nothing.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.login_failures (
+    key VARCHAR(300) PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMPTZ NOT NULL,
+    locked_until TIMESTAMPTZ
+);
@@ -0,0 +1,13 @@
+-- Accounts waiting out the deletion grace period. Removing the row cancels the deletion.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.account_deletions (
+    user_id VARCHAR(40) PRIMARY KEY,
+    requested_at TIMESTAMPTZ NOT NULL,
+    scheduled_for TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS account_deletions_scheduled_for_idx
+    ON lunar_buffxnte_psu.account_deletions (scheduled_for);
+
+-- Purchases are kept for accounting once the account is gone, just without the owner.
+ALTER TABLE lunar_buffxnte_psu.purchases ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE lunar_buffxnte_psu.purchases ADD COLUMN IF NOT EXISTS anonymised_at TIMESTAMPTZ;
@@ -0,0 +1,26 @@
+-- Append-only record of security relevant account activity. `user_id` is whose log the entry
+-- belongs to, `actor_id` is who did it when that's someone else (e.g. staff granting premium).
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    user_id VARCHAR(40),
+    actor_id VARCHAR(40),
+    event VARCHAR(64) NOT NULL,
+    details TEXT NOT NULL DEFAULT '{}',
+    ip_address VARCHAR(64),
+    user_agent TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user_id_idx
+    ON lunar_buffxnte_psu.audit_log (user_id, created_at DESC);
+
+-- Entries can't be edited. Deletes stay possible so account deletion can erase them.
+CREATE OR REPLACE FUNCTION lunar_buffxnte_psu.audit_log_no_update() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log entries are immutable';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_log_no_update ON lunar_buffxnte_psu.audit_log;
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON lunar_buffxnte_psu.audit_log
+    FOR EACH ROW EXECUTE PROCEDURE lunar_buffxnte_psu.audit_log_no_update();
@@ -0,0 +1,19 @@
+-- Users can hold several named keys, each limited to a set of scopes and optionally expiring.
+-- Existing keys become a 'Default' key with every scope so current integrations keep working.
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS public_id VARCHAR(16);
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS name VARCHAR(64) NOT NULL DEFAULT 'Default';
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS scopes TEXT[] NOT NULL
+    DEFAULT '{obfuscate,scripts:read,scripts:write,licenses:check}';
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS last_used_at TIMESTAMPTZ;
+
+UPDATE lunar_buffxnte_psu.api_keys
+    SET public_id = substr(md5(random()::text || api_key), 1, 16)
+    WHERE public_id IS NULL;
+
+ALTER TABLE lunar_buffxnte_psu.api_keys ALTER COLUMN public_id SET NOT NULL;
+ALTER TABLE lunar_buffxnte_psu.api_keys ALTER COLUMN name DROP DEFAULT;
+ALTER TABLE lunar_buffxnte_psu.api_keys ALTER COLUMN scopes DROP DEFAULT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS api_keys_public_id_idx ON lunar_buffxnte_psu.api_keys (public_id);
+CREATE INDEX IF NOT EXISTS api_keys_uid_idx ON lunar_buffxnte_psu.api_keys (uid);
@@ -0,0 +1,2 @@
+-- The UTC day `todays_requests` belongs to. A request on a later day starts the count again.
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS quota_date DATE;
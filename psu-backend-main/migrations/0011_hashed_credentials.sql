@@ -0,0 +1,34 @@
+-- API keys, sessions and password reset tokens are stored as SHA-256 hashes (hex) from now on.
+-- Existing credentials are hashed in place so nobody gets logged out or loses a working key.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+-- API keys are looked up by prefix. Old keys don't have the psu_<prefix>_<secret> shape, so
+-- their first 12 characters become the prefix and the whole key is hashed.
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS key_prefix VARCHAR(16);
+ALTER TABLE lunar_buffxnte_psu.api_keys ADD COLUMN IF NOT EXISTS key_hash CHAR(64);
+ALTER TABLE lunar_buffxnte_psu.api_keys ALTER COLUMN api_key DROP NOT NULL;
+
+UPDATE lunar_buffxnte_psu.api_keys
+    SET key_prefix = substr(api_key, 1, 12),
+        key_hash = encode(digest(api_key, 'sha256'), 'hex'),
+        api_key = NULL
+    WHERE key_hash IS NULL AND api_key IS NOT NULL;
+
+ALTER TABLE lunar_buffxnte_psu.api_keys ALTER COLUMN key_prefix SET NOT NULL;
+ALTER TABLE lunar_buffxnte_psu.api_keys ALTER COLUMN key_hash SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS api_keys_key_prefix_idx ON lunar_buffxnte_psu.api_keys (key_prefix);
+
+-- Session ids were 30 characters, hashes are 64. `payload` held a second copy of the token.
+ALTER TABLE lunar_buffxnte_psu.sessions ALTER COLUMN id TYPE VARCHAR(255);
+
+UPDATE lunar_buffxnte_psu.sessions
+    SET id = encode(digest(id, 'sha256'), 'hex'),
+        payload = ''
+    WHERE length(id) <> 64;
+
+ALTER TABLE lunar_buffxnte_psu.password_resets ALTER COLUMN token TYPE VARCHAR(255);
+
+UPDATE lunar_buffxnte_psu.password_resets
+    SET token = encode(digest(token, 'sha256'), 'hex')
+    WHERE length(token) <> 64;
@@ -0,0 +1,17 @@
+-- Discord logins find the account by discord_id, so a Discord account can only be linked once.
+-- Where it is linked to several accounts today, only the most recently updated keeps the link.
+UPDATE lunar_buffxnte_psu.users SET discord_id = NULL, discord_username = NULL, discord_avatar = NULL
+    WHERE id IN (
+        SELECT id FROM (
+            SELECT id, ROW_NUMBER() OVER (PARTITION BY discord_id ORDER BY updated_at DESC) AS position
+            FROM lunar_buffxnte_psu.users
+            WHERE discord_id IS NOT NULL
+        ) linked
+        WHERE linked.position > 1
+    );
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_discord_id_idx
+    ON lunar_buffxnte_psu.users (discord_id) WHERE discord_id IS NOT NULL;
+
+-- Accounts created through Discord have no password until the owner sets one with a reset.
+ALTER TABLE lunar_buffxnte_psu.users ALTER COLUMN password DROP NOT NULL;
@@ -0,0 +1,10 @@
+-- Whether the linked Discord member currently holds the premium role, as far as we know.
+-- The sync sweep revisits anyone where this disagrees with their purchases.
+ALTER TABLE lunar_buffxnte_psu.users ADD COLUMN IF NOT EXISTS discord_premium_role BOOLEAN NOT NULL DEFAULT false;
+
+-- Roles were only ever set at link time, so nothing recorded can be trusted. Flag every linked
+-- account as out of sync so the first sweep checks each of them once.
+UPDATE lunar_buffxnte_psu.users u SET discord_premium_role = NOT EXISTS (
+        SELECT 1 FROM lunar_buffxnte_psu.purchases p WHERE p.user_id = u.id AND p.expires_at > now()::date
+    )
+    WHERE u.discord_id IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Storage keys making up the current avatar, so they can be removed when it is replaced.
+ALTER TABLE lunar_buffxnte_psu.users ADD COLUMN IF NOT EXISTS avatar_objects TEXT[];
+
+-- Avatars uploaded before this were a single object behind the CDN URL.
+UPDATE lunar_buffxnte_psu.users
+    SET avatar_objects = ARRAY[substr(avatar, length('https://cdn.psu.dev/') + 1)]
+    WHERE avatar LIKE 'https://cdn.psu.dev/profile_pictures/%' AND avatar_objects IS NULL;
@@ -0,0 +1,12 @@
+-- Every password reset request, kept for a day so requests can be limited per email and per IP.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.password_reset_requests (
+    id BIGSERIAL PRIMARY KEY,
+    email VARCHAR(255) NOT NULL,
+    ip_address VARCHAR(45) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS password_reset_requests_email_idx
+    ON lunar_buffxnte_psu.password_reset_requests (LOWER(email), created_at);
+CREATE INDEX IF NOT EXISTS password_reset_requests_ip_idx
+    ON lunar_buffxnte_psu.password_reset_requests (ip_address, created_at);
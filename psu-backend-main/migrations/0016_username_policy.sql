@@ -0,0 +1,29 @@
+-- Lowercased, lookalike-folded form of the username used for uniqueness checks. Keep this
+-- expression in step with `usernames::canonical`.
+ALTER TABLE lunar_buffxnte_psu.users ADD COLUMN IF NOT EXISTS username_canonical VARCHAR(255);
+ALTER TABLE lunar_buffxnte_psu.users ADD COLUMN IF NOT EXISTS username_changed_at TIMESTAMPTZ;
+
+UPDATE lunar_buffxnte_psu.users
+    SET username_canonical = translate(replace(replace(lower(username), 'rn', 'm'), 'vv', 'w'), '01i5_', 'olls')
+    WHERE username IS NOT NULL;
+
+-- Not unique, older accounts may already collide. New names are claimed under an advisory
+-- lock instead, see `usernames::claim`.
+CREATE INDEX IF NOT EXISTS users_username_canonical_idx ON lunar_buffxnte_psu.users (username_canonical);
+CREATE INDEX IF NOT EXISTS users_username_lower_idx ON lunar_buffxnte_psu.users (LOWER(username));
+
+-- Names given up by a rename. They redirect to the account's current name and can't be taken
+-- by anyone else until they expire.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.username_history (
+    id BIGSERIAL PRIMARY KEY,
+    user_id VARCHAR(40) NOT NULL,
+    username VARCHAR(255) NOT NULL,
+    username_canonical VARCHAR(255) NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS username_history_canonical_idx
+    ON lunar_buffxnte_psu.username_history (username_canonical, expires_at);
+CREATE INDEX IF NOT EXISTS username_history_lower_idx
+    ON lunar_buffxnte_psu.username_history (LOWER(username));
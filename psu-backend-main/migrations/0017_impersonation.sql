@@ -0,0 +1,5 @@
+-- Sessions opened by an admin on someone else's account. They carry the admin's id, are
+-- read-only unless write access was granted, and expire at a fixed time.
+ALTER TABLE lunar_buffxnte_psu.sessions ADD COLUMN IF NOT EXISTS impersonator_id VARCHAR(40);
+ALTER TABLE lunar_buffxnte_psu.sessions ADD COLUMN IF NOT EXISTS read_only BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE lunar_buffxnte_psu.sessions ADD COLUMN IF NOT EXISTS expires_at BIGINT;
@@ -0,0 +1,21 @@
+-- Devices (IP plus rough browser/OS) each user has signed in from, used to spot new sign-ins,
+-- and the single-use "this wasn't me" tokens sent in those alerts.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.login_devices (
+    user_id VARCHAR(40) NOT NULL,
+    ip_address VARCHAR(45) NOT NULL,
+    ua_family VARCHAR(64) NOT NULL,
+    first_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, ip_address, ua_family)
+);
+
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.login_alerts (
+    token VARCHAR(64) PRIMARY KEY,
+    user_id VARCHAR(40) NOT NULL,
+    session_id VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS login_alerts_created_at_idx ON lunar_buffxnte_psu.login_alerts (created_at);
+
+ALTER TABLE lunar_buffxnte_psu.users ADD COLUMN IF NOT EXISTS password_reset_required BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,13 @@
+-- Every saved revision of a script. The object at the script's id stays a copy of the newest
+-- version, the versions themselves live at object_key and are never modified.
+CREATE TABLE IF NOT EXISTS lunar_buffxnte_psu.script_versions (
+    script_id VARCHAR(40) NOT NULL,
+    version INTEGER NOT NULL,
+    author_id VARCHAR(40) NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    sha256 CHAR(64) NOT NULL,
+    restored_from INTEGER,
+    object_key VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (script_id, version)
+);
@@ -0,0 +1,14 @@
+-- Hash and size of the current source, served as the ETag and used for Range requests on
+-- downloads. Scripts with history get them from their newest version, the rest are filled in
+-- on their next save.
+ALTER TABLE lunar_buffxnte_psu.scripts ADD COLUMN IF NOT EXISTS sha256 CHAR(64);
+ALTER TABLE lunar_buffxnte_psu.scripts ADD COLUMN IF NOT EXISTS size_bytes BIGINT;
+
+UPDATE lunar_buffxnte_psu.scripts s
+SET sha256 = v.sha256, size_bytes = v.size_bytes
+FROM (
+    SELECT DISTINCT ON (script_id) script_id, sha256, size_bytes
+    FROM lunar_buffxnte_psu.script_versions
+    ORDER BY script_id, version DESC
+) v
+WHERE v.script_id = s.id AND s.sha256 IS NULL;
@@ -0,0 +1,7 @@
+-- What the last role sync found on Discord. Linked users who left the guild can't be given the
+-- role, so the sweep only looks at them once a day, and everyone else is visited oldest first
+-- so a batch that hits the rate limit doesn't keep retrying the same users.
+ALTER TABLE lunar_buffxnte_psu.users ADD COLUMN IF NOT EXISTS discord_in_guild BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE lunar_buffxnte_psu.users ADD COLUMN IF NOT EXISTS discord_synced_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS users_discord_synced_at_idx ON lunar_buffxnte_psu.users (discord_synced_at) WHERE discord_id IS NOT NULL;
@@ -1,28 +1,31 @@
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::{config, discord_sync};
 use crate::modules::user;
 use crate::MainPGDatabase;
 
-use bcrypt::{hash, verify};
+use bcrypt::verify;
 use chrono::Duration;
 use nanoid::nanoid;
 use postgres::rows::Rows;
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
 use user::row_to_user;
 
-// MultiPart bullshit
-use multipart::server::{
-    save::{DataReader, Entries, SaveResult::*, SavedData, SavedField, TempDir},
-    Multipart,
-};
-use std::collections::HashMap;
-use std::sync::Arc;
-
-use rusoto_core::{credential::ChainProvider, request::HttpClient, Region};
-use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3};
-use tokio::runtime::Runtime;
-
+pub mod account_deletion;
+pub mod api_keys;
+pub mod api_metering;
+pub mod avatars;
+pub mod data_export;
+pub mod discord_login;
+pub mod email_verification;
+pub mod impersonation;
+pub mod login_alerts;
+pub mod login_protection;
+pub mod passwords;
 pub mod permissions;
 pub mod roles;
+pub mod sessions;
+pub mod two_factor;
+pub mod usernames;
 
 pub fn has_premium(user_id: &String, conn: &MainPGDatabase) -> Option<String> {
     // Make sure transaction ID hasn't already been used
@@ -42,186 +45,12 @@ pub fn has_premium(user_id: &String, conn: &MainPGDatabase) -> Option<String> {
     }
 }
 
-pub fn finalise_password_reset(
-    token: &String,
-    password: &String,
-    conn: &MainPGDatabase,
-) -> Result<String, String> {
-    let rows_recieved: Rows = match conn.query(
-    r#"SELECT email, token, created_at, user_id FROM lunar_buffxnte_psu.password_resets WHERE created_at BETWEEN NOW() - INTERVAL '30 MINUTES' AND NOW() AND token = $1;"#,
-    &[&token],
-  ) {
-      Ok(data) => data,
-      Err(err) => {
-          println!("{:?}", err);
-          return Err(String::from("ERR_INTERNAL_ERR"));
-      }
-  };
-
-    if rows_recieved.len() <= 0 {
-        return Err(String::from("Invalid Token."));
-    };
-
-    // Everything is valid so get user ID and rewrite the password
-    let user_id: String = rows_recieved.get(0).get("user_id");
-
-    match conn.execute(
-        "UPDATE lunar_buffxnte_psu.users SET password=$1 WHERE id = $2;",
-        &[&hash(password, 12).unwrap(), &user_id],
-    ) {
-        Ok(_data) => (),
-        Err(err) => {
-            println!("SQL ERROR: {}", err);
-            return Err(String::from("Something went wrong resetting the passwsord"));
-        }
-    };
-
-    return Ok(String::from("Successfully Reset Password"));
-}
-
-pub fn process_multipart(
-    boundary: &str,
-    data: rocket::Data,
-) -> Result<HashMap<Arc<str>, Vec<SavedField>>, String> {
-    match Multipart::with_body(data.open(), boundary)
-        .save()
-        .with_dir("./temp/")
-    {
-        Full(entries) => return Ok(entries.fields),
-        Partial(partial, reason) => {
-            println!("Request partially processed: {:?}", reason);
-            if let Some(field) = partial.partial {
-                println!("Stopped on field: {:?}", field.source.headers);
-            }
-
-            Err(String::from(
-                "Error: Got partial multipart. Wanted Full. Possible multipart corruption",
-            ))
-        }
-        Error(_e) => return Err(String::from("Something went wrong parsing multipart")),
-    }
-}
-
-pub fn process_upload_aws(data: Vec<u8>, fileID: &String) -> Result<String, String> {
-    let mut chain = ChainProvider::new();
-    chain.set_timeout(std::time::Duration::from_millis(200));
-
-    let s3cli = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        chain,
-        Region::Custom {
-            name: "nyc-3".to_owned(),
-            endpoint: "https://psu.sfo3.digitaloceanspaces.com".to_owned(),
-        },
-    );
-
-    // BAD DUMB ASS CODE. RESULT OF NEEDING TO EXECUTE ASYNC CODE IN NON-ASYNC FUNCTIONS. DUE TO ROCKET NOT SUPPORTING ASYNC ROUTES.
-    let rt = match Runtime::new() {
-        Ok(runtime) => runtime,
-        Err(err) => {
-            println!("Tokio Runtime Error: {}", err);
-            return Err(String::from(
-                "Tokio Runtime Error. Please notify the administrator.",
-            ));
-        }
-    };
-
-    let result = rt.block_on(async {
-        let test = s3cli
-            .put_object(PutObjectRequest {
-                bucket: String::from("psu-public"),
-                key: format!("profile_pictures/{}.png", fileID),
-                body: Some(data.into()),
-                ..Default::default()
-            })
-            .await;
-
-        test
-    });
-
-    match result {
-        Ok(_data) => Ok(fileID.to_owned()),
-        Err(err) => {
-            println!("AWS ERROR: {}", err);
-            Err(String::from(
-                "A AWS Error occoured. Please notify the administrator.",
-            ))
-        }
-    }
-}
-
-pub fn process_avatar_upload(
-    boundary: &str,
-    data: rocket::Data,
-    conn: &MainPGDatabase,
-) -> Result<String, String> {
-    let multipart_data = match process_multipart(boundary, data) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("{}", err);
-            return Err(String::from("Something went wrong parsing multipart data."));
-        }
-    };
-
-    let token_field = match multipart_data.get("token") {
-        Some(data) => data,
-        None => return Err(String::from("No token field was recieved")),
-    };
-
-    let token = script_services::field_to_string(&token_field[0]).unwrap();
-
-    // Check user's auth.
-    let user_id = match is_authenticated(&token, conn) {
-        Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
-    let file_field = match multipart_data.get("file") {
-        Some(data) => data,
-        None => return Err(String::from("No file field was recieved")),
-    };
-
-    let file = match script_services::field_to_file(&file_field[0]) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("{}", err);
-            return Err(String::from("Something went wrong processing the file."));
-        }
-    };
-
-    let avatar_id = nanoid!(40);
-
-    match process_upload_aws(file, &avatar_id) {
-        Ok(_data) => (),
-        Err(err) => {
-            println!("AWS ERROR: {}", err);
-            return Err(String::from("AWS ERROR! Please contact the administrator."));
-        }
-    };
-
-    match conn.execute(
-        "UPDATE lunar_buffxnte_psu.users SET avatar=$1 WHERE id = $2;",
-        &[
-            &format!("https://cdn.psu.dev/profile_pictures/{}.png", &avatar_id),
-            &user_id,
-        ],
-    ) {
-        Ok(_data) => return Ok("OK".to_string()),
-        Err(err) => {
-            println!("SQL ERROR! {}", err);
-            return Err(
-                "Something went wrong while uploading this image. Please try again later."
-                    .to_string(),
-            );
-        }
-    };
-}
-
 pub fn update_profile(
     user_id: &String,
     email: &String,
     first_name: &String,
     last_name: &String,
+    context: &AuditContext,
     conn: &MainPGDatabase,
 ) -> Result<String, String> {
     // Run veriifcation
@@ -233,11 +62,47 @@ pub fn update_profile(
         return Err("Invalid Last or First Name!".to_string());
     }
 
+    let user = get_user(user_id, conn)?;
+    let email_changed = user.email.as_ref() != Some(email);
+
+    // A new address has to be verified again.
     match conn.execute(
-        "UPDATE lunar_buffxnte_psu.users SET email=$1,first_name=$2, last_name=$3 WHERE id = $4;",
+        "UPDATE lunar_buffxnte_psu.users SET email=$1,first_name=$2, last_name=$3, email_verified_at = CASE WHEN email = $1 THEN email_verified_at ELSE NULL END WHERE id = $4;",
         &[&email, &first_name, &last_name, &user_id],
     ) {
-        Ok(_data) => return Ok("Successfully updated profile!".to_string()),
+        Ok(_data) => {
+            audit_log::record(
+                Some(user_id),
+                None,
+                "profile.update",
+                serde_json::json!({"first_name": first_name, "last_name": last_name}),
+                context,
+                conn,
+            );
+
+            if email_changed {
+                audit_log::record(
+                    Some(user_id),
+                    None,
+                    "email.change",
+                    serde_json::json!({"from": user.email, "to": email}),
+                    context,
+                    conn,
+                );
+
+                match email_verification::send_verification(
+                    user_id,
+                    email,
+                    &user.username.unwrap_or_default(),
+                    conn,
+                ) {
+                    Ok(_data) => (),
+                    Err(err) => println!("Failed to send verification email: {}", err),
+                };
+            }
+
+            return Ok("Successfully updated profile!".to_string());
+        }
         Err(err) => {
             println!("SQL ERROR! {}", err);
             return Err(
@@ -248,64 +113,6 @@ pub fn update_profile(
     }
 }
 
-pub fn send_reset_email(email_address: &String, conn: &MainPGDatabase) -> Result<String, String> {
-    // Search for user...
-    let rows_recieved: Rows = match conn.query(
-        r#"SELECT * FROM lunar_buffxnte_psu.users WHERE email = $1 ORDER BY id ASC LIMIT 1"#,
-        &[&email_address],
-    ) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("{:?}", err);
-            return Err(String::from("ERR_INTERNAL_ERR"));
-        }
-    };
-
-    if rows_recieved.len() <= 0 {
-        return Ok(String::from("User doesn't exist")); // We return true because we don't need to validate to the user if the request succeeded. For security reasons
-    };
-
-    let user = row_to_user(&rows_recieved.get(0));
-
-    // if valid user then generate a NANOID
-    let token = nanoid::nanoid!();
-
-    match conn.execute(
-        "INSERT INTO lunar_buffxnte_psu.password_resets(
-      email, token, created_at, user_id)
-      VALUES ($1, $2, $3, $4);",
-        &[&email_address, &token, &chrono::Utc::now(), &user.id],
-    ) {
-        Ok(_data) => (),
-        Err(err) => {
-            println!("SQL ERROR: {}", err);
-            return Err(String::from(
-                "Something went wrong creating the password reset token",
-            ));
-        }
-    };
-
-    // Send the email
-    let data = ureq::post("https://api.mailgun.net/v3/email.psu.dev/messages")
-        .set(
-            "Authorization",
-            &format!(
-                "Basic {}",
-                base64::encode(format!("api:{}", std::env::var("MAILGUN_KEY").unwrap()))
-            ),
-        )
-        .send_form(&[
-            ("from", "PSU <donotreply@email.psu.dev>"),
-            ("to", &format!("test user <{}>", email_address)),
-            ("subject", "PSU Password Reset"),
-            ("template", "password-reset-template"),
-            ("v:username", &user.username.unwrap()),
-            ("v:password_reset_token", &token),
-        ]);
-
-    Ok("SUCCESS".to_string())
-}
-
 pub fn add_premium(
     user_id: &String,
     order_id: &String,
@@ -360,7 +167,10 @@ pub fn add_premium(
             &date_expires,
         ],
     ) {
-        Ok(_data) => return Ok(String::from("SUCCESSFULLY ACTIVATED PREMIUM")),
+        Ok(_data) => {
+            discord_sync::queue_sync(user_id);
+            return Ok(String::from("SUCCESSFULLY ACTIVATED PREMIUM"));
+        }
         Err(err) => {
             println!("SQL ERROR: {}", err);
             return Err(String::from("Something went wrong activating premium"));
@@ -373,7 +183,10 @@ pub fn remove_premium(user_id: &String, conn: &MainPGDatabase) -> Result<String,
         "DELETE FROM lunar_buffxnte_psu.purchases WHERE user_id = $1;",
         &[&user_id],
     ) {
-        Ok(_data) => return Ok(String::from("SUCCESSFULLY REMOVED PREMIUM")),
+        Ok(_data) => {
+            discord_sync::queue_sync(user_id);
+            return Ok(String::from("SUCCESSFULLY REMOVED PREMIUM"));
+        }
         Err(err) => {
             println!("SQL ERROR: {}", err);
             return Err(String::from("Something went wrong removing premium"));
@@ -381,9 +194,16 @@ pub fn remove_premium(user_id: &String, conn: &MainPGDatabase) -> Result<String,
     };
 }
 
-pub fn is_authenticated(token: &String, conn: &MainPGDatabase) -> Result<String, String> {
+pub struct SessionInfo {
+    pub user_id: String,
+    // Set on sessions an admin opened through impersonation.
+    pub impersonator_id: Option<String>,
+    pub read_only: bool,
+}
+
+pub fn authenticate_session(token: &String, conn: &MainPGDatabase) -> Result<SessionInfo, String> {
     // Get current token ID
-    let rows_recieved: Rows = match conn.query("SELECT * from lunar_buffxnte_psu.sessions WHERE id = $1", &[&token]) {
+    let rows_recieved: Rows = match conn.query("SELECT * from lunar_buffxnte_psu.sessions WHERE id = $1", &[&sessions::session_key(token)]) {
         Ok(data) => data,
         Err(err) => {
             println!("Error: {:?}", err);
@@ -397,20 +217,45 @@ pub fn is_authenticated(token: &String, conn: &MainPGDatabase) -> Result<String,
 
     let single_row_recieved = rows_recieved.get(0);
 
+    let created_at: Option<i64> = single_row_recieved.get("created_at");
+    let last_activity: i64 = single_row_recieved.get("last_activity");
+    let expires_at: Option<i64> = single_row_recieved.get("expires_at");
+
+    if sessions::is_expired(created_at, last_activity, sessions::now_secs())
+        || expires_at.map_or(false, |expires_at| expires_at <= sessions::now_secs())
+    {
+        let _ = sessions::logout(token, conn);
+        return Err(String::from("ERR_SESSION_EXPIRED"));
+    }
+
     let user_id: Option<String> = single_row_recieved.get("user_id");
 
     match user_id {
-        Some(id) => return Ok(id),
+        Some(id) => {
+            sessions::touch_session(token, last_activity, conn);
+            return Ok(SessionInfo {
+                user_id: id,
+                impersonator_id: single_row_recieved.get("impersonator_id"),
+                read_only: single_row_recieved.get("read_only"),
+            });
+        }
         None => return Err(String::from("ERR_INVALID_TOKEN")),
     }
 }
 
-pub fn get_user(token: &String, conn: &MainPGDatabase) -> Result<user::User, String> {
-    let user_id = match is_authenticated(token, &conn) {
-        Ok(data) => data,
-        Err(err) => return Err(err),
-    };
+// Only for ordinary sessions. Impersonation sessions have to come through the Authorization
+// header, where the guards can enforce their restrictions.
+pub fn is_authenticated(token: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let session = authenticate_session(token, conn)?;
 
+    if session.impersonator_id.is_some() {
+        return Err(String::from("ERR_INVALID_TOKEN"));
+    }
+
+    Ok(session.user_id)
+}
+
+pub fn get_user(user_id: &String, conn: &MainPGDatabase) -> Result<user::User, String> {
     let rows_recieved: Rows = match conn.query(
         r#"SELECT * FROM lunar_buffxnte_psu.users WHERE id = $1 ORDER BY id ASC LIMIT 1"#,
         &[&user_id],
@@ -431,87 +276,17 @@ pub fn get_user(token: &String, conn: &MainPGDatabase) -> Result<user::User, Str
     Ok(user::row_to_user(&user_row))
 }
 
-pub fn regenerate_api_key(user_id: &String, conn: MainPGDatabase) -> Result<String, String> {
-    let new_key = nanoid!(50);
-
-    // Remove previous ones.
-
-    match conn.execute(
-        "DELETE FROM lunar_buffxnte_psu.api_keys WHERE uid = $1;",
-        &[&user_id],
-    ) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("SQL ERROR: {}", err);
-            return Err(String::from("Something went wrong creating the token"));
-        }
-    };
-
-    match conn.execute(
-    "INSERT INTO lunar_buffxnte_psu.api_keys(
-    api_key, uid, todays_requests, allowed_requests, total_requests, last_request, created_at, disabled)
-      VALUES ($1, $2, $3, $4, $5, $6, $7, $8);", &[
-        &new_key,
-        &user_id,
-        &(0 as i64),
-        &(100 as i64),
-        &(0 as i64),
-        &chrono::Utc::now().to_string(),
-        &chrono::Utc::now().to_string(),
-        &(0 as i16)
-      ]) {
-        Ok(data) => data,
-        Err(err) => {
-          println!("SQL ERROR: {}", err);
-          return Err(String::from("Something went wrong creating the token"));
-        }
-      };
-
-    Ok(new_key)
+// Kept for the dashboard's regenerate button, which only knows about a single key.
+pub fn regenerate_api_key(user_id: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    api_keys::rotate_default_key(user_id, conn)
 }
 
-pub fn get_api_key(token: &String, conn: MainPGDatabase) -> Result<String, String> {
-    let user_id = match is_authenticated(token, &conn) {
-        Ok(string) => string,
-        Err(_err) => {
-            return Err(String::from("ERR_INVALID_AUTH"));
-        }
-    };
-
-    let rows_recieved: Rows = match conn.query(
-        r#"SELECT * FROM lunar_buffxnte_psu.api_keys WHERE uid = $1 LIMIT 1"#,
-        &[&user_id],
-    ) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("{:?}", err);
-            return Err(String::from("ERR_INTERNAL_ERR"));
-        }
-    };
-
-    let api_key: String;
-
-    if rows_recieved.len() <= 0 {
-        api_key = match regenerate_api_key(&user_id, conn) {
-            Ok(data) => data,
-            Err(err) => {
-                println!("ERROR: {}", err);
-                return Err(String::from(
-                    "Something went wrong regenerating the api key",
-                ));
-            }
-        };
-    } else {
-        api_key = rows_recieved.get(0).get("api_key")
-    }
-
-    return Ok(api_key);
+pub fn get_api_key(user_id: &String, conn: &MainPGDatabase) -> Result<Vec<api_keys::ApiKey>, String> {
+    api_keys::list_keys(user_id, conn)
 }
 
 use regex::Regex;
 
-use super::script_services;
-
 fn check_email(text: &str) -> bool {
     lazy_static! {
         static ref RE: Regex = Regex::new(r#"(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\.)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])"#).unwrap();
@@ -542,59 +317,63 @@ pub fn register_user(
         return Err("Email address already exists.".to_string());
     }
 
-    // Perform password validations
-    let score_estimate = zxcvbn::zxcvbn(&password, &[]).unwrap();
-
-    if score_estimate.score() <= 2 {
-        match score_estimate.feedback() {
-            Some(data) => match data.warning() {
-                Some(warning) => return Err(format!("Password is too weak, Warning: {}", warning)),
-                None => {
-                    return Err(format!(
-                        "Password is too weak, Suggestion: {}",
-                        data.suggestions()[0]
-                    ))
-                }
-            },
-            None => return Err("Password is too weak".to_string()),
-        }
-    }
+    usernames::check_new_username(username, None, &conn)?;
+
+    passwords::check_strength(password, &[username.as_str(), email.as_str()])?;
 
-    let hashed_password = hash(password, 12).unwrap();
+    let hashed_password = passwords::hash_password(password)?;
     let user_id = nanoid!(40);
-    let result = match conn.execute(
+
+    let transaction = match conn.transaction() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("Something went wrong creating the user"));
+        }
+    };
+
+    usernames::claim(username, &user_id, &transaction)?;
+
+    match transaction.execute(
     r#"INSERT INTO lunar_buffxnte_psu.users(
-      id, email, username, password, role_id, last_login, status, remember_token, created_at, updated_at, avatar)
-      VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11);"#,
+      id, email, username, password, role_id, last_login, status, remember_token, created_at, updated_at, avatar, username_canonical)
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12);"#,
     &[
         &user_id,
         &email,
         &username,
         &hashed_password,
-        &(2 as i64),
+        &roles::default_role_id(),
         &chrono::Utc::now(),
         &"Active",
         &nanoid!(30),
         &chrono::Utc::now(),
         &chrono::Utc::now(),
-        &"https://cdn.psu.dev/profile.jpg"
+        &"https://cdn.psu.dev/profile.jpg",
+        &usernames::canonical(username)
     ],
 ) {
-    Ok(data) => data,
+    Ok(_data) => (),
     Err(err) => {
         println!("{}", err);
         return Err(String::from("Something went wrong creating the user"));
     }
 };
 
-    match regenerate_api_key(&user_id, conn) {
+    match transaction.commit() {
         Ok(_data) => (),
         Err(err) => {
-            println!("ERROR: {}", err);
+            println!("SQL ERROR: {}", err);
             return Err(String::from("Something went wrong creating the user"));
         }
     };
 
+    // Registration still succeeds if Mailgun is down, the user can ask for a resend.
+    match email_verification::send_verification(&user_id, email, username, &conn) {
+        Ok(_data) => (),
+        Err(err) => println!("Failed to send verification email: {}", err),
+    };
+
     return Ok(String::from("Successfully Created User"));
 }
 
@@ -619,97 +398,133 @@ pub struct DiscordUser {
 }
 
 pub fn unlink_discord(user_id: &String, conn: MainPGDatabase) -> Result<String, String> {
-    match conn.execute("UPDATE lunar_buffxnte_psu.users SET discord_id=NULL, discord_username=NULL, discord_avatar=NULL WHERE id = $1;", 
+    let user = get_user(user_id, &conn)?;
+
+    match conn.execute("UPDATE lunar_buffxnte_psu.users SET discord_id=NULL, discord_username=NULL, discord_avatar=NULL, discord_premium_role=false WHERE id = $1;", 
   &[&user_id])
 {
-  Ok(_data) => Ok("SUCCESS".to_string()),
+  Ok(_data) => (),
   Err(err) => {
     println!("SQL ERROR: {}", err);
     return Err("Something went wrong unlinking this discord account. Try again later.".to_string())
   }
+};
+
+    if let Some(discord_id) = user.discord_id {
+        discord_sync::release_user(&discord_id);
+    }
+
+    Ok("SUCCESS".to_string())
 }
+
+// Discord only accepts the redirect URI the user was originally sent out with, so these
+// have to match what the dashboard uses when it builds the authorize links.
+pub fn discord_link_redirect_uri() -> String {
+    config::env_or("DISCORD_REDIRECT_URI", String::from("https://psu.dev/linkDiscord"))
 }
 
-pub fn link_discord(
-    user_id: &String,
-    token: &String,
-    conn: MainPGDatabase,
-) -> Result<DiscordUser, String> {
-    let data = ureq::post("https://discord.com/api/oauth2/token").send_form(&[
+pub fn discord_login_redirect_uri() -> String {
+    config::env_or("DISCORD_LOGIN_REDIRECT_URI", String::from("https://psu.dev/loginDiscord"))
+}
+
+pub fn discord_avatar_url(discord_user: &DiscordUser) -> String {
+    match &discord_user.avatar {
+        Some(data) => format!(
+            "https://cdn.discordapp.com/avatars/{}/{}.png",
+            &discord_user.id, data
+        ),
+        None => "https://cdn.discordapp.com/embed/avatars/0.png".to_string(),
+    }
+}
+
+// Exchanges an OAuth code and fetches the account it belongs to.
+fn discord_oauth(
+    code: &String,
+    redirect_uri: &String,
+    scope: &str,
+) -> Result<(DiscordTokenResponse, DiscordUser), String> {
+    let data = ureq::post(&format!("{}/oauth2/token", discord_sync::api_base())).send_form(&[
         ("client_id", &std::env::var("DISCORD_ID").unwrap()),
         ("client_secret", &std::env::var("DISCORD_SECRET").unwrap()),
         ("grant_type", "authorization_code"),
-        ("code", &token),
-        (
-            "redirect_uri",
-            "https://psu.dev/linkDiscord",
-        ),
-        ("scope", "identify email guilds.join"),
+        ("code", &code),
+        ("redirect_uri", &redirect_uri),
+        ("scope", scope),
     ]);
 
-    // We got a response with the token. Now handle other things...
     let token_response = match data.into_json_deserialize::<DiscordTokenResponse>() {
         Ok(data) => data,
         Err(err) => {
             println!("DISCORD ERROR: {}", err);
-            return Err(
-                "Something went wrong linking this discord account. Try again later.".to_string(),
-            );
+            return Err(String::from("ERR_DISCORD_OAUTH"));
         }
     };
 
-    let current_user_response = ureq::get("https://discord.com/api/users/@me")
+    let current_user_response = ureq::get(&format!("{}/users/@me", discord_sync::api_base()))
         .set(
             "Authorization",
             &format!("Bearer {}", &token_response.access_token),
         )
         .call();
 
-    let mut current_user = match current_user_response.into_json_deserialize::<DiscordUser>() {
-        Ok(data) => data,
+    match current_user_response.into_json_deserialize::<DiscordUser>() {
+        Ok(data) => Ok((token_response, data)),
         Err(err) => {
             println!("DISCORD ERROR: {}", err);
-            return Err(
-                "Something went wrong linking this discord account. Try again later.".to_string(),
-            );
+            Err(String::from("ERR_DISCORD_OAUTH"))
         }
-    };
+    }
+}
 
-    // Check if user has premium
-    let hasPremium = has_premium(user_id, &conn);
+pub fn discord_identity(code: &String, redirect_uri: &String) -> Result<DiscordUser, String> {
+    Ok(discord_oauth(code, redirect_uri, "identify email")?.1)
+}
 
-    let mut roles: Vec<String> = Default::default();
+pub fn link_discord(
+    user_id: &String,
+    token: &String,
+    conn: MainPGDatabase,
+) -> Result<DiscordUser, String> {
+    let (token_response, current_user) =
+        match discord_oauth(token, &discord_link_redirect_uri(), "identify email guilds.join") {
+            Ok(data) => data,
+            Err(_err) => {
+                return Err(
+                    "Something went wrong linking this discord account. Try again later."
+                        .to_string(),
+                )
+            }
+        };
 
-    let computed_avatar = match &current_user.avatar {
-        Some(data) => format!(
-            "https://cdn.discordapp.com/avatars/{}/{}.png",
-            &current_user.id, data
-        ),
-        None => "https://cdn.discordapp.com/embed/avatars/0.png".to_string(),
+    // Discord logins look accounts up by discord_id, so it can only belong to one of them.
+    let linked_elsewhere: Rows = match conn.query(
+        "SELECT id FROM lunar_buffxnte_psu.users WHERE discord_id = $1 AND id <> $2;",
+        &[&current_user.id, &user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
     };
 
-    roles.push("781621726123393056".to_string());
+    if linked_elsewhere.len() > 0 {
+        return Err(
+            "This discord account is already linked to another PSU account.".to_string(),
+        );
+    }
 
-    match hasPremium {
-        Some(_data) => {
-            roles.push("781621719869685780".to_string());
-        }
-        None => (),
-    };
+    let computed_avatar = discord_avatar_url(&current_user);
 
-    // Now add the user to the discord server. No need to handle as we simply don't care if they actually join.
-    let add_user_response = ureq::put(&format!(
-        "https://discord.com/api/guilds/781613878407725077/members/{}",
-        &current_user.id
-    ))
-    .set(
-        "Authorization",
-        &format!("Bot {}", std::env::var("DISCORD_BOTTOKEN").unwrap()),
-    )
-    .send_json(serde_json::json!({
-      "access_token": &token_response.access_token,
-      "roles": roles
-    }));
+    // No need to fail the link if this doesn't work, the role sync sweep catches up later.
+    match discord_sync::join_guild(
+        &current_user.id,
+        &token_response.access_token,
+        has_premium(user_id, &conn).is_some(),
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("DISCORD ERROR: failed to add {} to the guild: {}", &current_user.id, err),
+    };
 
     match conn.execute("UPDATE lunar_buffxnte_psu.users SET discord_id=$1, discord_username=$2, discord_avatar=$3 WHERE id = $4;", 
     &[
@@ -725,31 +540,55 @@ pub fn link_discord(
     }
   };
 
+    discord_sync::queue_sync(user_id);
+
     return Ok(current_user);
 }
 
+#[derive(Default)]
+pub struct SessionOptions {
+    pub impersonator_id: Option<String>,
+    pub read_only: bool,
+    // Hard lifetime in seconds, on top of the usual idle and absolute timeouts.
+    pub lifetime_secs: Option<i64>,
+}
+
 pub fn create_session(
     user_id: String,
     ip: String,
     user_agent: String,
     conn: MainPGDatabase,
+) -> Result<String, String> {
+    create_session_with(user_id, ip, user_agent, &SessionOptions::default(), &conn)
+}
+
+pub fn create_session_with(
+    user_id: String,
+    ip: String,
+    user_agent: String,
+    options: &SessionOptions,
+    conn: &MainPGDatabase,
 ) -> Result<String, String> {
     let id = nanoid!(30);
+    let now = sessions::now_secs();
+    let expires_at = options.lifetime_secs.map(|lifetime| now + lifetime);
 
     let _result = match conn.execute(
         r#"INSERT INTO lunar_buffxnte_psu.sessions(
-    id, user_id, ip_address, user_agent, payload, last_activity)
-    VALUES ($1, $2, $3, $4, $5, $6);"#,
+    id, user_id, ip_address, user_agent, payload, last_activity, created_at, public_id, impersonator_id, read_only, expires_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11);"#,
         &[
-            &id,
+            &sessions::session_key(&id),
             &user_id,
             &ip,
             &user_agent,
-            &id,
-            &(SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64),
+            &"",
+            &now,
+            &now,
+            &nanoid!(16),
+            &options.impersonator_id,
+            &options.read_only,
+            &expires_at,
         ],
     ) {
         Ok(data) => data,
@@ -759,20 +598,38 @@ pub fn create_session(
         }
     };
 
+    // Support sessions come from staff devices, the owner doesn't need to hear about those.
+    if options.impersonator_id.is_none() {
+        login_alerts::check_new_session(&user_id, &ip, &user_agent, &id, conn);
+    }
+
     Ok(id)
 }
 
+pub enum LoginOutcome {
+    Session(String),
+    // Password was correct but the account has 2FA, holds the challenge ID to exchange.
+    TwoFactorRequired(String),
+}
+
 pub fn login(
     username: &String,
     password: &String,
     conn: MainPGDatabase,
     ip_addr: String,
     user_agent: String,
-) -> Result<String, String> {
-    let rows_recieved: Rows = match conn.query(
-        r#"SELECT * FROM lunar_buffxnte_psu.users WHERE username = $1 OR email = $1 ORDER BY id ASC LIMIT 1"#,
-        &[&username],
-    ) {
+) -> Result<LoginOutcome, String> {
+    let context = AuditContext::new(&ip_addr, &user_agent);
+
+    // Usernames can't contain '@', so anything with one is an email. Older accounts may still
+    // have usernames that only differ by case, an exact match wins over those.
+    let query = if username.contains('@') {
+        r#"SELECT * FROM lunar_buffxnte_psu.users WHERE email = $1 ORDER BY id ASC LIMIT 1"#
+    } else {
+        r#"SELECT * FROM lunar_buffxnte_psu.users WHERE LOWER(username) = LOWER($1) ORDER BY (username = $1) DESC, id ASC LIMIT 1"#
+    };
+
+    let rows_recieved: Rows = match conn.query(query, &[&username]) {
         Ok(data) => data,
         Err(err) => {
             println!("{:?}", err);
@@ -780,21 +637,77 @@ pub fn login(
         }
     };
 
-    if rows_recieved.len() <= 0 {
+    if rows_recieved.is_empty() {
+        login_protection::check_attempt(None, username, &ip_addr, &conn)?;
+        login_protection::verify_dummy_password(password);
+        login_protection::record_failure(None, username, &ip_addr, None, &conn);
+        audit_log::record(
+            None,
+            None,
+            "login.failure",
+            serde_json::json!({"username": username, "reason": "unknown_account"}),
+            &context,
+            &conn,
+        );
         return Err(String::from("ERR_INVALID_CRED"));
     }
 
-    let user = rows_recieved.get(0);
+    let user = row_to_user(&rows_recieved.get(0));
 
-    let password_hash: String = user.get("password");
+    login_protection::check_attempt(Some(&user.id), username, &ip_addr, &conn)?;
 
-    if !verify(&password, &password_hash).unwrap() {
+    let password_ok = match &user.password {
+        Some(password_hash) => verify(&password, password_hash).unwrap_or(false),
+        None => {
+            login_protection::verify_dummy_password(password);
+            false
+        }
+    };
+
+    if !password_ok {
+        let owner = user
+            .email
+            .clone()
+            .map(|email| (email, user.username.clone().unwrap_or_default()));
+
+        login_protection::record_failure(Some(&user.id), username, &ip_addr, owner, &conn);
+        audit_log::record(
+            Some(&user.id),
+            None,
+            "login.failure",
+            serde_json::json!({"reason": "invalid_password"}),
+            &context,
+            &conn,
+        );
         return Err(String::from("ERR_INVALID_CRED"));
     }
 
+    // Set when the owner reports a sign-in they didn't make, the old password is no good now.
+    login_alerts::require_no_reset(&user.id, "password", &context, &conn)?;
+
+    // The failure count is only cleared once the code passes too, otherwise every correct
+    // password would buy a fresh round of code guesses.
+    if two_factor::is_enabled(&user) {
+        return match two_factor::create_challenge(&user.id, &conn) {
+            Ok(challenge) => Ok(LoginOutcome::TwoFactorRequired(challenge)),
+            Err(err) => Err(err),
+        };
+    }
+
+    login_protection::record_success(&user.id, &conn);
+
+    audit_log::record(
+        Some(&user.id),
+        None,
+        "login.success",
+        serde_json::json!({"method": "password"}),
+        &context,
+        &conn,
+    );
+
     // Generate us a session.
-    match create_session(user.get("ID"), ip_addr, user_agent, conn) {
-        Ok(token) => return Ok(token),
+    match create_session(user.id, ip_addr, user_agent, conn) {
+        Ok(token) => return Ok(LoginOutcome::Session(token)),
         Err(err) => return Err(err),
     }
 }
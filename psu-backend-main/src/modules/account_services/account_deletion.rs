@@ -0,0 +1,235 @@
+use crate::modules::account_services::{self, avatars, two_factor};
+use crate::modules::{config, discord_sync, mailer, scheduler, script_services};
+use crate::MainPGDatabase;
+
+use bcrypt::verify;
+use chrono::{DateTime, Duration, Utc};
+use postgres::rows::Rows;
+use postgres::Connection;
+
+// How long a deletion request can still be cancelled. Default 14 days.
+pub fn grace_period_days() -> i64 {
+    config::env_or("ACCOUNT_DELETION_GRACE_DAYS", 14)
+}
+
+pub fn pending_deletion(user_id: &String, conn: &Connection) -> Result<Option<DateTime<Utc>>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT scheduled_for FROM lunar_buffxnte_psu.account_deletions WHERE user_id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(rows_recieved.get(0).get("scheduled_for")))
+}
+
+// Schedules the account for deletion once the grace period is over. Needs the password and,
+// when 2FA is on, a TOTP or recovery code.
+pub fn request_deletion(
+    user_id: &String,
+    password: &String,
+    code: Option<&String>,
+    conn: &MainPGDatabase,
+) -> Result<DateTime<Utc>, String> {
+    let user = account_services::get_user(user_id, conn)?;
+
+    let password_hash = user.password.clone().unwrap_or_default();
+
+    if !verify(password, &password_hash).unwrap_or(false) {
+        return Err(String::from("ERR_INVALID_CRED"));
+    }
+
+    if two_factor::is_enabled(&user) {
+        let code = match code {
+            Some(data) => data,
+            None => return Err(String::from("ERR_2FA_REQUIRED")),
+        };
+
+        if !two_factor::verify_second_factor(user_id, code, conn)? {
+            return Err(String::from("ERR_INVALID_2FA_CODE"));
+        }
+    }
+
+    let scheduled_for = Utc::now() + Duration::days(grace_period_days());
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.account_deletions(user_id, requested_at, scheduled_for) VALUES ($1, $2, $3) ON CONFLICT (user_id) DO NOTHING;",
+        &[&user_id, &Utc::now(), &scheduled_for],
+    ) {
+        Ok(0) => return Err(String::from("ERR_DELETION_ALREADY_SCHEDULED")),
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if let Some(email) = &user.email {
+        match mailer::send_template(
+            email,
+            "Your PSU account is scheduled for deletion",
+            "account-deletion-template",
+            &[
+                ("username", &user.username.clone().unwrap_or_default()),
+                ("scheduled_for", &scheduled_for.format("%Y-%m-%d %H:%M UTC").to_string()),
+            ],
+        ) {
+            Ok(_data) => (),
+            Err(err) => println!("Failed to send deletion email: {}", err),
+        };
+    }
+
+    Ok(scheduled_for)
+}
+
+pub fn cancel_deletion(user_id: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.account_deletions WHERE user_id = $1;",
+        &[&user_id],
+    ) {
+        Ok(0) => Err(String::from("ERR_NO_DELETION_SCHEDULED")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+// Removes the account for good. The database goes first and storage is cleaned up after the
+// commit, so a failed transaction leaves the account intact, scripts and avatar included,
+// for the next sweep to retry. A storage failure only leaves unreachable objects behind.
+pub fn finalise_deletion(user_id: &String, conn: &Connection) -> Result<(), String> {
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT id FROM lunar_buffxnte_psu.scripts WHERE "belongs_to" = $1"#,
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let script_ids: Vec<String> = rows_recieved.iter().map(|row| row.get("id")).collect();
+    let avatar_objects = avatars::current_objects(user_id, conn)?;
+
+    let transaction = match conn.transaction() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let statements = [
+        r#"DELETE FROM lunar_buffxnte_psu.public_scripts WHERE id IN (SELECT id FROM lunar_buffxnte_psu.scripts WHERE "belongs_to" = $1);"#,
+        r#"DELETE FROM lunar_buffxnte_psu.scripts WHERE "belongs_to" = $1;"#,
+        "DELETE FROM lunar_buffxnte_psu.sessions WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.api_keys WHERE uid = $1;",
+        "DELETE FROM lunar_buffxnte_psu.password_resets WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.email_verifications WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.two_factor_recovery_codes WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.two_factor_challenges WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.user_permissions WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.audit_log WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.username_history WHERE user_id = $1;",
+        "UPDATE lunar_buffxnte_psu.purchases SET user_id = NULL, anonymised_at = now() WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.account_deletions WHERE user_id = $1;",
+        "DELETE FROM lunar_buffxnte_psu.users WHERE id = $1;",
+    ];
+
+    let discord_id: Option<String> = match transaction.query(
+        "SELECT discord_id FROM lunar_buffxnte_psu.users WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data.iter().next().and_then(|row| row.get("discord_id")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let version_objects: Vec<String> = match transaction.query(
+        r#"DELETE FROM lunar_buffxnte_psu.script_versions
+        WHERE script_id IN (SELECT id FROM lunar_buffxnte_psu.scripts WHERE "belongs_to" = $1)
+        RETURNING object_key;"#,
+        &[&user_id],
+    ) {
+        Ok(data) => data.iter().map(|row| row.get("object_key")).collect(),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    for statement in statements.iter() {
+        match transaction.execute(statement, &[&user_id]) {
+            Ok(_data) => (),
+            Err(err) => {
+                println!("SQL ERROR: {}", err);
+                return Err(String::from("ERR_INTERNAL_ERR"));
+            }
+        };
+    }
+
+    match transaction.commit() {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    script_services::versions::delete_objects(&version_objects);
+    script_services::versions::delete_objects(&script_ids);
+    avatars::delete_objects(&avatar_objects);
+
+    if let Some(discord_id) = discord_id {
+        discord_sync::release_user(&discord_id);
+    }
+
+    Ok(())
+}
+
+pub const DELETION_JOB: scheduler::Job = scheduler::Job {
+    name: "ACCOUNT_DELETION",
+    counts: "accounts deleted after their grace period",
+    default_interval_secs: 60 * 60,
+    run: process_due_deletions,
+};
+
+// Finalises every deletion whose grace period has run out. Returns how many went through.
+pub fn process_due_deletions(conn: &Connection) -> Result<u64, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT user_id FROM lunar_buffxnte_psu.account_deletions WHERE scheduled_for <= now();",
+        &[],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let mut deleted = 0;
+
+    for row in &rows_recieved {
+        let user_id: String = row.get("user_id");
+
+        match finalise_deletion(&user_id, conn) {
+            Ok(_data) => deleted += 1,
+            Err(err) => println!("Failed to delete account {}: {}", user_id, err),
+        };
+    }
+
+    Ok(deleted)
+}
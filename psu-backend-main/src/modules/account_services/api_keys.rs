@@ -0,0 +1,257 @@
+use crate::modules::account_services::{api_metering, email_verification};
+use crate::modules::credentials;
+use crate::MainPGDatabase;
+
+use chrono::{DateTime, Utc};
+use nanoid::nanoid;
+use postgres::rows::{Row, Rows};
+use postgres::Connection;
+use serde::Serialize;
+
+// Each scope is only honoured on the routes listed for it in guards::API_KEY_ROUTES. The
+// obfuscator and license checks don't run from this backend, so no route here accepts
+// `obfuscate` or `licenses:check` yet and a key holding only those can't call anything.
+pub const SCOPE_OBFUSCATE: &str = "obfuscate";
+pub const SCOPE_SCRIPTS_READ: &str = "scripts:read";
+pub const SCOPE_SCRIPTS_WRITE: &str = "scripts:write";
+pub const SCOPE_LICENSES_CHECK: &str = "licenses:check";
+
+pub const ALL_SCOPES: [&str; 4] = [
+    SCOPE_OBFUSCATE,
+    SCOPE_SCRIPTS_READ,
+    SCOPE_SCRIPTS_WRITE,
+    SCOPE_LICENSES_CHECK,
+];
+
+const MAX_KEYS_PER_USER: i64 = 10;
+
+// The name the single key from before named keys existed lives under. The legacy
+// regenerate route rotates this one and leaves the rest alone.
+pub const DEFAULT_KEY_NAME: &str = "Default";
+
+// Key metadata. The secret is only ever returned once, when the key is created, and only its
+// hash is stored.
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub prefix: String,
+    pub created_at: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub disabled: bool,
+}
+
+// What a request authenticated with an API key is allowed to do.
+#[derive(Clone, Debug)]
+pub struct KeyAuth {
+    pub user_id: String,
+    pub key_id: String,
+    pub scopes: Vec<String>,
+}
+
+impl KeyAuth {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+}
+
+fn row_to_key(row: &Row) -> ApiKey {
+    let disabled: i16 = row.get("disabled");
+
+    ApiKey {
+        id: row.get("public_id"),
+        name: row.get("name"),
+        scopes: row.get("scopes"),
+        prefix: row.get("key_prefix"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        last_used_at: row.get("last_used_at"),
+        disabled: disabled != 0,
+    }
+}
+
+pub fn list_keys(user_id: &String, conn: &Connection) -> Result<Vec<ApiKey>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.api_keys WHERE uid = $1 ORDER BY created_at ASC;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    Ok(rows_recieved.iter().map(|row| row_to_key(&row)).collect())
+}
+
+fn validate_key(
+    name: &String,
+    scopes: &Vec<String>,
+    expires_at: Option<&DateTime<Utc>>,
+) -> Result<(), String> {
+    if name.trim().is_empty() || name.len() > 64 {
+        return Err(String::from("Key name must be between 1 and 64 characters"));
+    }
+
+    if scopes.is_empty() {
+        return Err(String::from("A key needs at least one scope"));
+    }
+
+    if let Some(scope) = scopes.iter().find(|scope| !ALL_SCOPES.contains(&scope.as_str())) {
+        return Err(format!("Unknown scope '{}'", scope));
+    }
+
+    if let Some(expires_at) = expires_at {
+        if *expires_at <= Utc::now() {
+            return Err(String::from("Expiry date must be in the future"));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the new key's metadata and its secret.
+pub fn create_key(
+    user_id: &String,
+    name: &String,
+    scopes: &Vec<String>,
+    expires_at: Option<&DateTime<Utc>>,
+    conn: &MainPGDatabase,
+) -> Result<(ApiKey, String), String> {
+    validate_key(name, scopes, expires_at)?;
+    email_verification::ensure_verified(user_id, conn)?;
+
+    let rows_recieved: Rows = match conn.query(
+        "SELECT COUNT(*) AS key_count FROM lunar_buffxnte_psu.api_keys WHERE uid = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let key_count: i64 = rows_recieved.get(0).get("key_count");
+
+    if key_count >= MAX_KEYS_PER_USER {
+        return Err(String::from("ERR_MAX_API_KEYS_EXCEEDED"));
+    }
+
+    let issued = credentials::issue_api_key();
+
+    let rows_recieved: Rows = match conn.query(
+        r#"INSERT INTO lunar_buffxnte_psu.api_keys(
+    key_prefix, key_hash, uid, todays_requests, allowed_requests, total_requests, last_request, created_at, disabled,
+    public_id, name, scopes, expires_at)
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) RETURNING *;"#,
+        &[
+            &issued.prefix,
+            &issued.hash,
+            &user_id,
+            &(0 as i64),
+            &api_metering::daily_quota(user_id, conn),
+            &(0 as i64),
+            &chrono::Utc::now().to_string(),
+            &chrono::Utc::now().to_string(),
+            &(0 as i16),
+            &nanoid!(16),
+            &name.trim(),
+            &scopes,
+            &expires_at,
+        ],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("Something went wrong creating the token"));
+        }
+    };
+
+    Ok((row_to_key(&rows_recieved.get(0)), issued.key))
+}
+
+pub fn revoke_key(user_id: &String, key_id: &String, conn: &Connection) -> Result<String, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.api_keys WHERE uid = $1 AND public_id = $2;",
+        &[&user_id, &key_id],
+    ) {
+        Ok(0) => Err(String::from("ERR_API_KEY_NOT_FOUND")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+// Replaces the Default key with a fresh one holding every scope. Other keys are untouched.
+pub fn rotate_default_key(user_id: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.api_keys WHERE uid = $1 AND name = $2;",
+        &[&user_id, &DEFAULT_KEY_NAME],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("Something went wrong creating the token"));
+        }
+    };
+
+    let scopes: Vec<String> = ALL_SCOPES.iter().map(|scope| scope.to_string()).collect();
+    let (_key, secret) = create_key(user_id, &DEFAULT_KEY_NAME.to_string(), &scopes, None, conn)?;
+
+    Ok(secret)
+}
+
+// Only the prefix is used to find the row, the secret is checked against the stored hash
+// in constant time.
+pub fn authenticate(api_key: &String, conn: &Connection) -> Result<KeyAuth, String> {
+    let (prefix, secret) = match credentials::split_api_key(api_key) {
+        Some(data) => data,
+        None => return Err(String::from("ERR_INVALID_API_KEY")),
+    };
+
+    let rows_recieved: Rows = match conn.query(
+        "SELECT uid, public_id, scopes, expires_at, key_hash FROM lunar_buffxnte_psu.api_keys WHERE key_prefix = $1 AND disabled = 0",
+        &[&prefix],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Error: {:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let row = match rows_recieved.iter().find(|row| {
+        let key_hash: String = row.get("key_hash");
+        credentials::verify_secret(secret, &key_hash)
+    }) {
+        Some(row) => row,
+        None => return Err(String::from("ERR_INVALID_API_KEY")),
+    };
+    let expires_at: Option<DateTime<Utc>> = row.get("expires_at");
+
+    if expires_at.map_or(false, |expires_at| expires_at <= Utc::now()) {
+        return Err(String::from("ERR_API_KEY_EXPIRED"));
+    }
+
+    let auth = KeyAuth {
+        user_id: row.get("uid"),
+        key_id: row.get("public_id"),
+        scopes: row.get("scopes"),
+    };
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.api_keys SET last_used_at = $1 WHERE public_id = $2;",
+        &[&Utc::now(), &auth.key_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("SQL ERROR: {}", err),
+    };
+
+    Ok(auth)
+}
@@ -0,0 +1,159 @@
+use crate::modules::account_services::{self, api_keys::KeyAuth};
+use crate::modules::{config, scheduler};
+use crate::MainPGDatabase;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use postgres::rows::Rows;
+use postgres::Connection;
+use serde::Serialize;
+
+// Result of metering one request, used for the X-RateLimit-* headers.
+#[derive(Clone, Debug)]
+pub struct QuotaStatus {
+    pub limit: i64,
+    pub remaining: i64,
+    pub reset: DateTime<Utc>,
+    pub exceeded: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    pub id: String,
+    pub name: String,
+    pub todays_requests: i64,
+    pub allowed_requests: i64,
+    pub total_requests: i64,
+    pub last_request: Option<String>,
+    pub disabled: bool,
+}
+
+// Requests per key per UTC day. Premium accounts get the larger quota.
+pub fn daily_quota(user_id: &String, conn: &MainPGDatabase) -> i64 {
+    match account_services::has_premium(user_id, conn) {
+        Some(_expires) => config::env_or("API_QUOTA_PREMIUM", 10_000),
+        None => config::env_or("API_QUOTA_FREE", 100),
+    }
+}
+
+fn today() -> NaiveDate {
+    Utc::now().naive_utc().date()
+}
+
+// Counters roll over at UTC midnight.
+pub fn next_reset() -> DateTime<Utc> {
+    DateTime::from_utc((today() + Duration::days(1)).and_hms(0, 0, 0), Utc)
+}
+
+// Counts the request against the key. The row is locked before the quota is read, so
+// concurrent requests can't both squeeze in under the limit. Whether this request fits comes
+// back as its own column, which keeps a key that was revoked since `authenticate` (no row at
+// all) apart from one that is simply out of requests for today.
+pub fn meter_request(key: &KeyAuth, conn: &MainPGDatabase) -> Result<QuotaStatus, String> {
+    let quota = daily_quota(&key.user_id, conn);
+    let today = today();
+
+    let rows_recieved: Rows = match conn.query(
+        r#"UPDATE lunar_buffxnte_psu.api_keys k SET
+            todays_requests = CASE
+                WHEN NOT checked.admitted THEN k.todays_requests
+                WHEN k.quota_date = $2 THEN k.todays_requests + 1
+                ELSE 1 END,
+            total_requests = k.total_requests + CASE WHEN checked.admitted THEN 1 ELSE 0 END,
+            allowed_requests = $3,
+            quota_date = $2,
+            last_request = CASE WHEN checked.admitted THEN $4 ELSE k.last_request END
+        FROM (
+            SELECT public_id, (quota_date IS DISTINCT FROM $2 OR todays_requests < $3) AS admitted
+            FROM lunar_buffxnte_psu.api_keys WHERE public_id = $1 AND disabled = 0 FOR UPDATE
+        ) checked
+        WHERE k.public_id = checked.public_id
+        RETURNING checked.admitted, k.todays_requests;"#,
+        &[&key.key_id, &today, &quota, &Utc::now().to_string()],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_INVALID_API_KEY"));
+    }
+
+    let admitted: bool = rows_recieved.get(0).get("admitted");
+
+    if !admitted {
+        return Ok(QuotaStatus {
+            limit: quota,
+            remaining: 0,
+            reset: next_reset(),
+            exceeded: true,
+        });
+    }
+
+    let used: i64 = rows_recieved.get(0).get("todays_requests");
+
+    Ok(QuotaStatus {
+        limit: quota,
+        remaining: (quota - used).max(0),
+        reset: next_reset(),
+        exceeded: false,
+    })
+}
+
+pub fn get_usage(user_id: &String, conn: &Connection) -> Result<Vec<KeyUsage>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.api_keys WHERE uid = $1 ORDER BY created_at ASC;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let today = today();
+
+    Ok(rows_recieved
+        .iter()
+        .map(|row| {
+            let quota_date: Option<NaiveDate> = row.get("quota_date");
+            let todays_requests: i64 = row.get("todays_requests");
+            let disabled: i16 = row.get("disabled");
+
+            KeyUsage {
+                id: row.get("public_id"),
+                name: row.get("name"),
+                // A count from an earlier day hasn't been reset yet but is already stale.
+                todays_requests: if quota_date == Some(today) { todays_requests } else { 0 },
+                allowed_requests: row.get("allowed_requests"),
+                total_requests: row.get("total_requests"),
+                last_request: row.get("last_request"),
+                disabled: disabled != 0,
+            }
+        })
+        .collect())
+}
+
+pub const RESET_JOB: scheduler::Job = scheduler::Job {
+    name: "API_COUNTER_RESET",
+    counts: "API key counters reset",
+    default_interval_secs: 60 * 60,
+    run: reset_daily_counters,
+};
+
+// Zeroes yesterday's counts so anything reading the columns directly sees the reset.
+pub fn reset_daily_counters(conn: &Connection) -> Result<u64, String> {
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.api_keys SET todays_requests = 0, quota_date = $1 WHERE quota_date < $1;",
+        &[&today()],
+    ) {
+        Ok(count) => Ok(count),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use crate::modules::object_store::{self, PutOptions};
+use crate::modules::{config, script_services};
+use crate::MainPGDatabase;
+
+use image::imageops::FilterType;
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+use multipart::server::save::SavedField;
+use nanoid::nanoid;
+use postgres::rows::Rows;
+use postgres::Connection;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::sync::Arc;
+
+// Largest first, the biggest one is what `users.avatar` points at.
+const AVATAR_SIZES: [u32; 4] = [512, 256, 128, 64];
+const MIN_DIMENSION: u32 = 32;
+
+#[derive(Debug, Serialize)]
+pub struct AvatarUrls {
+    pub avatar: String,
+    pub sizes: BTreeMap<String, String>,
+}
+
+// Anything else is refused before decoding, so we never hand odd formats to a decoder.
+fn accepted_format(format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP => true,
+        _ => false,
+    }
+}
+
+pub fn max_bytes() -> usize {
+    config::env_or("AVATAR_MAX_BYTES", 5 * 1024 * 1024)
+}
+
+fn max_dimension() -> u32 {
+    config::env_or("AVATAR_MAX_DIMENSION", 4096)
+}
+
+// (format, extension, content type). WebP output is lossy and much smaller, PNG is the default.
+fn output_format() -> (ImageOutputFormat, &'static str, &'static str) {
+    match config::env_or("AVATAR_FORMAT", String::from("png")).as_str() {
+        "webp" => (ImageOutputFormat::WebP, "webp", "image/webp"),
+        _ => (ImageOutputFormat::Png, "png", "image/png"),
+    }
+}
+
+fn decode(data: &Vec<u8>) -> Result<DynamicImage, String> {
+    if data.len() > max_bytes() {
+        return Err(String::from("ERR_AVATAR_TOO_LARGE"));
+    }
+
+    let reader = match ImageReader::new(Cursor::new(data)).with_guessed_format() {
+        Ok(data) => data,
+        Err(_err) => return Err(String::from("ERR_AVATAR_NOT_AN_IMAGE")),
+    };
+
+    match reader.format() {
+        Some(format) if accepted_format(format) => (),
+        _ => return Err(String::from("ERR_AVATAR_NOT_AN_IMAGE")),
+    };
+
+    // Read the header on its own first so a tiny file claiming huge dimensions is refused
+    // before anything gets allocated for it.
+    let (width, height) = match ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|header| header.into_dimensions().ok())
+    {
+        Some(data) => data,
+        None => return Err(String::from("ERR_AVATAR_NOT_AN_IMAGE")),
+    };
+
+    if width > max_dimension() || height > max_dimension() {
+        return Err(String::from("ERR_AVATAR_DIMENSIONS_TOO_LARGE"));
+    }
+
+    if width < MIN_DIMENSION || height < MIN_DIMENSION {
+        return Err(String::from("ERR_AVATAR_DIMENSIONS_TOO_SMALL"));
+    }
+
+    match reader.decode() {
+        Ok(data) => Ok(data),
+        Err(err) => {
+            println!("AVATAR ERROR: {}", err);
+            Err(String::from("ERR_AVATAR_NOT_AN_IMAGE"))
+        }
+    }
+}
+
+// Decoding and re-encoding from raw pixels is what strips EXIF, ICC and any other metadata
+// the upload carried.
+fn render_sizes(image: &DynamicImage) -> Result<Vec<(u32, Vec<u8>)>, String> {
+    let (width, height) = image.dimensions();
+    let side = width.min(height);
+    let square = image.crop_imm((width - side) / 2, (height - side) / 2, side, side);
+
+    let (format, _extension, _content_type) = output_format();
+    let mut rendered: Vec<(u32, Vec<u8>)> = Default::default();
+
+    for size in AVATAR_SIZES.iter() {
+        let resized = DynamicImage::ImageRgba8(
+            square.resize_exact(*size, *size, FilterType::Lanczos3).to_rgba8(),
+        );
+
+        let mut buffer: Vec<u8> = Default::default();
+
+        match resized.write_to(&mut Cursor::new(&mut buffer), format.clone()) {
+            Ok(_data) => (),
+            Err(err) => {
+                println!("AVATAR ERROR: {}", err);
+                return Err(String::from("Something went wrong processing the image."));
+            }
+        };
+
+        rendered.push((*size, buffer));
+    }
+
+    Ok(rendered)
+}
+
+fn put_objects(objects: Vec<(String, Vec<u8>)>, content_type: &str) -> Result<(), String> {
+    let options = PutOptions {
+        content_type: Some(content_type.to_string()),
+        cache_control: Some(String::from("public, max-age=31536000, immutable")),
+    };
+
+    for (key, data) in objects {
+        match object_store::public().put(&key, data, &options) {
+            Ok(_data) => (),
+            Err(err) => {
+                println!("STORAGE ERROR: {}", err);
+                return Err(String::from("AWS ERROR! Please contact the administrator."));
+            }
+        };
+    }
+
+    Ok(())
+}
+
+// Best effort. A leftover object only costs storage, so failures are logged and skipped.
+pub fn delete_objects(keys: &Vec<String>) {
+    for key in keys {
+        match object_store::public().delete(key) {
+            Ok(_data) => (),
+            Err(err) => println!("STORAGE ERROR: failed to delete {}: {}", key, err),
+        };
+    }
+}
+
+pub fn current_objects(user_id: &String, conn: &Connection) -> Result<Vec<String>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT avatar_objects FROM lunar_buffxnte_psu.users WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_USER_NOT_FOUND"));
+    }
+
+    let objects: Option<Vec<String>> = rows_recieved.get(0).get("avatar_objects");
+    Ok(objects.unwrap_or_default())
+}
+
+pub fn process_avatar_upload(
+    user_id: &String,
+    multipart_data: &HashMap<Arc<str>, Vec<SavedField>>,
+    conn: &MainPGDatabase,
+) -> Result<AvatarUrls, String> {
+    let file_field = match multipart_data.get("file") {
+        Some(data) => data,
+        None => return Err(String::from("No file field was recieved")),
+    };
+
+    let file = match script_services::field_to_file(&file_field[0]).read() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{}", err);
+            return Err(String::from("Something went wrong processing the file."));
+        }
+    };
+
+    let rendered = render_sizes(&decode(&file)?)?;
+
+    let previous_objects = current_objects(user_id, conn)?;
+
+    let (_format, extension, content_type) = output_format();
+    let avatar_id = nanoid!(40);
+
+    let objects: Vec<(String, Vec<u8>)> = rendered
+        .into_iter()
+        .map(|(size, data)| {
+            (
+                format!("profile_pictures/{}/{}.{}", avatar_id, size, extension),
+                data,
+            )
+        })
+        .collect();
+
+    let keys: Vec<String> = objects.iter().map(|(key, _data)| key.clone()).collect();
+
+    if let Err(err) = put_objects(objects, content_type) {
+        delete_objects(&keys);
+        return Err(err);
+    }
+
+    let mut urls = AvatarUrls {
+        avatar: object_store::public_url(&keys[0]),
+        sizes: Default::default(),
+    };
+
+    for (size, key) in AVATAR_SIZES.iter().zip(keys.iter()) {
+        urls.sizes.insert(size.to_string(), object_store::public_url(key));
+    }
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET avatar = $1, avatar_objects = $2, updated_at = $3 WHERE id = $4;",
+        &[&urls.avatar, &keys, &chrono::Utc::now(), &user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR! {}", err);
+            delete_objects(&keys);
+            return Err(
+                "Something went wrong while uploading this image. Please try again later."
+                    .to_string(),
+            );
+        }
+    };
+
+    delete_objects(&previous_objects);
+
+    Ok(urls)
+}
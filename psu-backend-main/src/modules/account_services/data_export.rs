@@ -0,0 +1,159 @@
+use crate::modules::account_services::{self, api_keys, sessions};
+use crate::modules::{audit_log, script_services};
+use crate::MainPGDatabase;
+
+use postgres::rows::Rows;
+use serde::Serialize;
+use std::io::{Cursor, Write};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+#[derive(Debug, Serialize)]
+pub struct PurchaseRecord {
+    pub txn_id: Option<String>,
+    pub method: Option<String>,
+    pub status: Option<i16>,
+    pub amount: Option<f64>,
+    pub active: Option<i16>,
+    pub chargebacked: Option<i16>,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScriptRecord {
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub public: bool,
+    // Path of the body inside the archive, None when it couldn't be fetched from storage.
+    pub file: Option<String>,
+}
+
+fn get_purchases(user_id: &String, conn: &MainPGDatabase) -> Result<Vec<PurchaseRecord>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.purchases WHERE user_id = $1 ORDER BY created_at ASC;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    Ok(rows_recieved
+        .iter()
+        .map(|row| PurchaseRecord {
+            txn_id: row.get("txn_id"),
+            method: row.get("method"),
+            status: row.get("status"),
+            amount: row.get("amount"),
+            active: row.get("active"),
+            chargebacked: row.get("chargebacked"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+        })
+        .collect())
+}
+
+fn add_file(archive: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, data: &[u8]) -> Result<(), String> {
+    match archive.start_file(name, FileOptions::default()) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("ZIP ERROR: {}", err);
+            return Err(String::from("Something went wrong building the export"));
+        }
+    };
+
+    match archive.write_all(data) {
+        Ok(_data) => Ok(()),
+        Err(err) => {
+            println!("ZIP ERROR: {}", err);
+            Err(String::from("Something went wrong building the export"))
+        }
+    }
+}
+
+fn add_json<T: Serialize>(
+    archive: &mut ZipWriter<Cursor<Vec<u8>>>,
+    name: &str,
+    value: &T,
+) -> Result<(), String> {
+    match serde_json::to_vec_pretty(value) {
+        Ok(data) => add_file(archive, name, &data),
+        Err(err) => {
+            println!("JSON ERROR: {}", err);
+            Err(String::from("Something went wrong building the export"))
+        }
+    }
+}
+
+// Builds a zip of everything we hold about the user. Script bodies go under scripts/ and a
+// script that can't be fetched is listed in scripts.json without a file rather than failing
+// the whole export.
+pub fn export_user_data(user_id: &String, conn: &MainPGDatabase) -> Result<Vec<u8>, String> {
+    let profile = account_services::get_user(user_id, conn)?.get_safe_user(conn);
+    let purchases = get_purchases(user_id, conn)?;
+    let active_sessions = sessions::list_sessions(user_id, None, conn)?;
+    let keys = api_keys::list_keys(user_id, conn)?;
+
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+
+    add_json(&mut archive, "profile.json", &profile)?;
+    add_json(&mut archive, "purchases.json", &purchases)?;
+    add_json(&mut archive, "sessions.json", &active_sessions)?;
+    add_json(&mut archive, "api_keys.json", &keys)?;
+
+    let mut activity: Vec<audit_log::AuditEvent> = Default::default();
+    let mut page = 1;
+
+    loop {
+        let (events, total) =
+            audit_log::list_events(user_id, page, audit_log::MAX_PAGE_SIZE, conn)?;
+        let done = events.is_empty() || activity.len() + events.len() >= total as usize;
+
+        activity.extend(events);
+        page += 1;
+
+        if done {
+            break;
+        }
+    }
+
+    add_json(&mut archive, "activity.json", &activity)?;
+
+    let mut scripts: Vec<ScriptRecord> = Default::default();
+
+    for script in script_services::get_private_scripts(user_id, conn)? {
+        let file = match script_services::get_script(user_id, &script.id, conn) {
+            Ok(body) => {
+                let path = format!("scripts/{}.lua", script.id);
+                add_file(&mut archive, &path, &body)?;
+                Some(path)
+            }
+            Err(err) => {
+                println!("Export couldn't fetch script {}: {}", script.id, err);
+                None
+            }
+        };
+
+        scripts.push(ScriptRecord {
+            id: script.id,
+            title: script.title,
+            description: script.description,
+            public: script.public,
+            file: file,
+        });
+    }
+
+    add_json(&mut archive, "scripts.json", &scripts)?;
+
+    match archive.finish() {
+        Ok(cursor) => Ok(cursor.into_inner()),
+        Err(err) => {
+            println!("ZIP ERROR: {}", err);
+            Err(String::from("Something went wrong building the export"))
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::modules::account_services::{
+    self, login_alerts, roles, two_factor, usernames, DiscordUser, LoginOutcome,
+};
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::user::{row_to_user, User};
+use crate::MainPGDatabase;
+
+use nanoid::nanoid;
+use postgres::rows::Rows;
+
+pub struct DiscordLogin {
+    pub outcome: LoginOutcome,
+    // True when this login created the PSU account.
+    pub registered: bool,
+}
+
+fn find_by_discord_id(discord_id: &String, conn: &MainPGDatabase) -> Result<Option<User>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.users WHERE discord_id = $1;",
+        &[&discord_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(row_to_user(&rows_recieved.get(0))))
+}
+
+// Discord names allow characters we don't, so keep the safe ones and add a numeric suffix
+// when the result is taken or otherwise not allowed.
+fn pick_username(discord_user: &DiscordUser, conn: &MainPGDatabase) -> Result<String, String> {
+    let filtered: String = discord_user
+        .username
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+
+    // Leaves room for the suffix within the length limit.
+    let mut base: String = filtered.trim_matches('_').chars().take(usernames::MAX_LENGTH - 5).collect();
+
+    if base.len() < usernames::MIN_LENGTH {
+        base = String::from("psu_user");
+    }
+
+    if usernames::check_new_username(&base, None, conn).is_ok() {
+        return Ok(base);
+    }
+
+    let digits = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+
+    for _attempt in 0..5 {
+        let candidate = format!("{}_{}", base, nanoid!(4, &digits));
+
+        if usernames::check_new_username(&candidate, None, conn).is_ok() {
+            return Ok(candidate);
+        }
+    }
+
+    Err(String::from("ERR_USERNAME_UNAVAILABLE"))
+}
+
+// Discord has already verified the address, so the account starts out verified and without
+// a password. The owner can set one later through a password reset.
+fn register_from_discord(discord_user: &DiscordUser, email: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let users_with_email: Rows = match conn.query(
+        "SELECT id FROM lunar_buffxnte_psu.users WHERE LOWER(email) = LOWER($1);",
+        &[&email],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    // Never attach Discord to an existing account here, the owner has to link it themselves
+    // after logging in normally.
+    if users_with_email.len() > 0 {
+        return Err(String::from("ERR_DISCORD_NOT_LINKED"));
+    }
+
+    let username = pick_username(discord_user, conn)?;
+    let user_id = nanoid!(40);
+    let now = chrono::Utc::now();
+
+    let transaction = match conn.transaction() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("Something went wrong creating the user"));
+        }
+    };
+
+    usernames::claim(&username, &user_id, &transaction)?;
+
+    match transaction.execute(
+        r#"INSERT INTO lunar_buffxnte_psu.users(
+      id, email, username, password, role_id, last_login, status, remember_token, created_at, updated_at, avatar,
+      email_verified_at, discord_id, discord_username, discord_avatar, username_canonical)
+      VALUES ($1, $2, $3, NULL, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15);"#,
+        &[
+            &user_id,
+            &email,
+            &username,
+            &roles::default_role_id(),
+            &now,
+            &"Active",
+            &nanoid!(30),
+            &now,
+            &now,
+            &"https://cdn.psu.dev/profile.jpg",
+            &now,
+            &discord_user.id,
+            &format!("{}#{}", discord_user.username, discord_user.discriminator),
+            &account_services::discord_avatar_url(discord_user),
+            &usernames::canonical(&username),
+        ],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("Something went wrong creating the user"));
+        }
+    };
+
+    match transaction.commit() {
+        Ok(_data) => Ok(user_id),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("Something went wrong creating the user"))
+        }
+    }
+}
+
+pub fn login(
+    code: &String,
+    conn: MainPGDatabase,
+    ip_addr: String,
+    user_agent: String,
+) -> Result<DiscordLogin, String> {
+    let context = AuditContext::new(&ip_addr, &user_agent);
+
+    let discord_user =
+        account_services::discord_identity(code, &account_services::discord_login_redirect_uri())?;
+
+    let (user_id, registered) = match find_by_discord_id(&discord_user.id, &conn)? {
+        Some(user) => {
+            login_alerts::require_no_reset(&user.id, "discord", &context, &conn)?;
+
+            if two_factor::is_enabled(&user) {
+                return Ok(DiscordLogin {
+                    outcome: LoginOutcome::TwoFactorRequired(two_factor::create_challenge(&user.id, &conn)?),
+                    registered: false,
+                });
+            }
+
+            (user.id, false)
+        }
+        None => {
+            let email = match (&discord_user.email, discord_user.verified) {
+                (Some(email), true) => email,
+                _ => return Err(String::from("ERR_DISCORD_EMAIL_NOT_VERIFIED")),
+            };
+
+            let user_id = register_from_discord(&discord_user, email, &conn)?;
+
+            audit_log::record(
+                Some(&user_id),
+                None,
+                "account.register",
+                serde_json::json!({"method": "discord", "discord_id": &discord_user.id}),
+                &context,
+                &conn,
+            );
+
+            (user_id, true)
+        }
+    };
+
+    audit_log::record(
+        Some(&user_id),
+        None,
+        "login.success",
+        serde_json::json!({"method": "discord"}),
+        &context,
+        &conn,
+    );
+
+    let token = account_services::create_session(user_id, ip_addr, user_agent, conn)?;
+
+    Ok(DiscordLogin {
+        outcome: LoginOutcome::Session(token),
+        registered,
+    })
+}
@@ -0,0 +1,124 @@
+use crate::modules::{account_services, config, mailer};
+use crate::MainPGDatabase;
+
+use nanoid::nanoid;
+use postgres::rows::Rows;
+
+const RESEND_COOLDOWN_SECS: i64 = 60;
+const MAX_SENDS_PER_DAY: i64 = 5;
+
+// Opt-in policy. When REQUIRE_VERIFIED_EMAIL is set, unverified accounts can't buy premium,
+// publish public scripts or create API keys.
+pub fn verification_required() -> bool {
+    config::env_or("REQUIRE_VERIFIED_EMAIL", false)
+}
+
+pub fn ensure_verified(user_id: &String, conn: &MainPGDatabase) -> Result<(), String> {
+    if !verification_required() {
+        return Ok(());
+    }
+
+    let user = account_services::get_user(user_id, conn)?;
+
+    match user.email_verified_at {
+        Some(_data) => Ok(()),
+        None => Err(String::from("ERR_EMAIL_NOT_VERIFIED")),
+    }
+}
+
+pub fn send_verification(
+    user_id: &String,
+    email: &String,
+    username: &String,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    let token = nanoid!(48);
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.email_verifications(token, user_id, email, created_at) VALUES ($1, $2, $3, $4);",
+        &[&token, &user_id, &email, &chrono::Utc::now()],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("Something went wrong creating the verification token"));
+        }
+    };
+
+    mailer::send_template(
+        email,
+        "Verify your PSU email address",
+        "email-verification-template",
+        &[("username", username), ("verification_token", &token)],
+    )
+}
+
+pub fn resend_verification(user_id: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let user = account_services::get_user(user_id, conn)?;
+
+    if user.email_verified_at.is_some() {
+        return Err(String::from("ERR_ALREADY_VERIFIED"));
+    }
+
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT COUNT(*) AS sent_today,
+        COALESCE(EXTRACT(EPOCH FROM now() - MAX(created_at)), 999999)::BIGINT AS seconds_since_last
+        FROM lunar_buffxnte_psu.email_verifications
+        WHERE user_id = $1 AND created_at > now() - INTERVAL '24 HOURS';"#,
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let sent_today: i64 = rows_recieved.get(0).get("sent_today");
+    let seconds_since_last: i64 = rows_recieved.get(0).get("seconds_since_last");
+
+    if seconds_since_last < RESEND_COOLDOWN_SECS || sent_today >= MAX_SENDS_PER_DAY {
+        return Err(String::from("ERR_RATE_LIMITED"));
+    }
+
+    let email = match user.email {
+        Some(data) => data,
+        None => return Err(String::from("ERR_NO_EMAIL")),
+    };
+
+    send_verification(user_id, &email, &user.username.unwrap_or_default(), conn)
+}
+
+pub fn verify_email(token: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let rows_recieved: Rows = match conn.query(
+        "DELETE FROM lunar_buffxnte_psu.email_verifications WHERE token = $1 AND created_at > now() - INTERVAL '24 HOURS' RETURNING user_id, email;",
+        &[&token],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("Invalid Token."));
+    }
+
+    let user_id: String = rows_recieved.get(0).get("user_id");
+    let email: String = rows_recieved.get(0).get("email");
+
+    // The email must still be the one on the account, otherwise an old link could verify
+    // an address the user has since changed away from.
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET email_verified_at = $1 WHERE id = $2 AND email = $3;",
+        &[&chrono::Utc::now(), &user_id, &email],
+    ) {
+        Ok(0) => Err(String::from("Invalid Token.")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::modules::account_services::{self, permissions, roles, SessionOptions};
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::config;
+use crate::MainPGDatabase;
+
+use serde::Serialize;
+
+// Both are checked as exact grants, so system.admin alone isn't enough to impersonate.
+pub const IMPERSONATE_PERMISSION: &str = "admin.users.impersonate";
+pub const IMPERSONATE_WRITE_PERMISSION: &str = "admin.users.impersonate.write";
+
+fn lifetime_secs() -> i64 {
+    config::env_or("IMPERSONATION_TTL_SECS", 15 * 60)
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImpersonationSession {
+    pub token: String,
+    pub user_id: String,
+    pub read_only: bool,
+    pub expires_at: i64,
+}
+
+pub fn start(
+    admin_id: &String,
+    target_id: &String,
+    reason: &String,
+    write_access: bool,
+    context: &AuditContext,
+    conn: &MainPGDatabase,
+) -> Result<ImpersonationSession, String> {
+    if admin_id == target_id {
+        return Err(String::from("You can't impersonate yourself"));
+    }
+
+    if reason.trim().len() < 5 {
+        return Err(String::from("A reason is required to access another account"));
+    }
+
+    if !permissions::has_perms(admin_id, &IMPERSONATE_PERMISSION.to_string(), conn, true)? {
+        return Err(String::from("PERMISSION_DENIED"));
+    }
+
+    if write_access
+        && !permissions::has_perms(admin_id, &IMPERSONATE_WRITE_PERMISSION.to_string(), conn, true)?
+    {
+        return Err(String::from("PERMISSION_DENIED"));
+    }
+
+    // Same rule as role management: staff can only look into accounts ranked below them.
+    let target_role = roles::get_user_role(target_id, conn)?;
+
+    if !roles::can_manage(admin_id, target_role.level, conn)? {
+        return Err(String::from("PERMISSION_DENIED"));
+    }
+
+    let lifetime = lifetime_secs();
+    let options = SessionOptions {
+        impersonator_id: Some(admin_id.clone()),
+        read_only: !write_access,
+        lifetime_secs: Some(lifetime),
+    };
+
+    let token = account_services::create_session_with(
+        target_id.clone(),
+        context.ip_address.clone().unwrap_or_default(),
+        context.user_agent.clone().unwrap_or_default(),
+        &options,
+        conn,
+    )?;
+
+    audit_log::record(
+        Some(target_id),
+        Some(admin_id),
+        "impersonation.start",
+        serde_json::json!({"reason": reason.trim(), "read_only": !write_access}),
+        context,
+        conn,
+    );
+
+    Ok(ImpersonationSession {
+        token,
+        user_id: target_id.clone(),
+        read_only: !write_access,
+        expires_at: account_services::sessions::now_secs() + lifetime,
+    })
+}
@@ -0,0 +1,380 @@
+use crate::modules::account_services::{self, passwords, sessions};
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::{config, credentials, mailer, scheduler};
+use crate::MainPGDatabase;
+
+use lazy_static::lazy_static;
+use maxminddb::{geoip2, Reader};
+use nanoid::nanoid;
+use postgres::rows::Rows;
+use postgres::Connection;
+use std::net::IpAddr;
+
+// "This wasn't me" links stop working after this long.
+const REPORT_LINK_DAYS: i64 = 7;
+
+lazy_static! {
+    // Optional GeoLite2/GeoIP2 City database. Without it alerts just leave the location out.
+    static ref GEOIP: Option<Reader<Vec<u8>>> = match std::env::var("GEOIP_DATABASE") {
+        Ok(path) => match Reader::open_readfile(&path) {
+            Ok(reader) => Some(reader),
+            Err(err) => {
+                println!("CONFIG: Failed to open GEOIP_DATABASE {}: {}", path, err);
+                None
+            }
+        },
+        Err(_err) => None,
+    };
+}
+
+fn alerts_enabled() -> bool {
+    config::env_or("LOGIN_ALERTS_ENABLED", true)
+}
+
+// How far back a device has to have been seen to count as known.
+fn lookback_days() -> i64 {
+    config::env_or("LOGIN_ALERT_LOOKBACK_DAYS", 90)
+}
+
+// Rough "browser on OS" grouping, so a browser update doesn't count as a new device.
+pub fn user_agent_family(user_agent: &str) -> String {
+    let browser = if user_agent.contains("Edg/") {
+        "Edge"
+    } else if user_agent.contains("OPR/") || user_agent.contains("Opera") {
+        "Opera"
+    } else if user_agent.contains("Firefox/") || user_agent.contains("FxiOS") {
+        "Firefox"
+    } else if user_agent.contains("Chrome/") || user_agent.contains("CriOS") {
+        "Chrome"
+    } else if user_agent.contains("Safari/") {
+        "Safari"
+    } else {
+        "Unknown browser"
+    };
+
+    let os = if user_agent.contains("Windows") {
+        "Windows"
+    } else if user_agent.contains("Android") {
+        "Android"
+    } else if user_agent.contains("iPhone") || user_agent.contains("iPad") {
+        "iOS"
+    } else if user_agent.contains("Macintosh") || user_agent.contains("Mac OS X") {
+        "macOS"
+    } else if user_agent.contains("Linux") {
+        "Linux"
+    } else {
+        "an unknown OS"
+    };
+
+    format!("{} on {}", browser, os)
+}
+
+pub fn locate(ip: &str) -> Option<String> {
+    let reader = GEOIP.as_ref()?;
+    let address: IpAddr = ip.parse().ok()?;
+    let city: geoip2::City = reader.lookup(address).ok()?;
+
+    let city_name = city
+        .city
+        .and_then(|city| city.names)
+        .and_then(|names| names.get("en").map(|name| name.to_string()));
+    let country_name = city
+        .country
+        .and_then(|country| country.names)
+        .and_then(|names| names.get("en").map(|name| name.to_string()));
+
+    match (city_name, country_name) {
+        (Some(city), Some(country)) => Some(format!("{}, {}", city, country)),
+        (None, Some(country)) => Some(country),
+        (Some(city), None) => Some(city),
+        (None, None) => None,
+    }
+}
+
+// login_devices only started filling in with this feature, so an account that was already
+// signed in somewhere would look brand new and never get an alert. The first time such an
+// account logs in, its other open sessions are taken as the devices it already knows.
+fn seed_from_sessions(user_id: &String, session_token: &String, conn: &MainPGDatabase) {
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT ip_address, user_agent FROM lunar_buffxnte_psu.sessions
+        WHERE user_id = $1 AND id != $2 AND impersonator_id IS NULL
+        AND NOT EXISTS (SELECT 1 FROM lunar_buffxnte_psu.login_devices WHERE user_id = $1);"#,
+        &[&user_id, &sessions::session_key(session_token)],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return;
+        }
+    };
+
+    for row in rows_recieved.iter() {
+        let ip: Option<String> = row.get("ip_address");
+        let user_agent: Option<String> = row.get("user_agent");
+
+        let ip = match ip {
+            Some(ip) => ip,
+            None => continue,
+        };
+
+        match conn.execute(
+            r#"INSERT INTO lunar_buffxnte_psu.login_devices(user_id, ip_address, ua_family, first_seen_at, last_seen_at)
+            VALUES ($1, $2, $3, now(), now())
+            ON CONFLICT (user_id, ip_address, ua_family) DO NOTHING;"#,
+            &[&user_id, &ip, &user_agent_family(&user_agent.unwrap_or_default())],
+        ) {
+            Ok(_data) => (),
+            Err(err) => println!("SQL ERROR: {}", err),
+        };
+    }
+}
+
+// Called for every new session. Never fails the login, problems are only logged.
+pub fn check_new_session(
+    user_id: &String,
+    ip: &String,
+    user_agent: &String,
+    session_token: &String,
+    conn: &MainPGDatabase,
+) {
+    if !alerts_enabled() {
+        return;
+    }
+
+    let family = user_agent_family(user_agent);
+
+    seed_from_sessions(user_id, session_token, conn);
+
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT COUNT(*) AS known,
+        COUNT(*) FILTER (WHERE ip_address = $2) AS ip_seen,
+        COUNT(*) FILTER (WHERE ua_family = $3) AS family_seen
+        FROM lunar_buffxnte_psu.login_devices
+        WHERE user_id = $1 AND last_seen_at > now() - ($4 || ' days')::INTERVAL;"#,
+        &[&user_id, &ip, &family, &lookback_days().to_string()],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return;
+        }
+    };
+
+    let known: i64 = rows_recieved.get(0).get("known");
+    let ip_seen: i64 = rows_recieved.get(0).get("ip_seen");
+    let family_seen: i64 = rows_recieved.get(0).get("family_seen");
+
+    match conn.execute(
+        r#"INSERT INTO lunar_buffxnte_psu.login_devices(user_id, ip_address, ua_family, first_seen_at, last_seen_at)
+        VALUES ($1, $2, $3, now(), now())
+        ON CONFLICT (user_id, ip_address, ua_family) DO UPDATE SET last_seen_at = now();"#,
+        &[&user_id, &ip, &family],
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("SQL ERROR: {}", err),
+    };
+
+    // Nothing to compare against on an account's first login, with no other sessions either.
+    if known == 0 || (ip_seen > 0 && family_seen > 0) {
+        return;
+    }
+
+    match send_alert(user_id, ip, &family, session_token, conn) {
+        Ok(_data) => (),
+        Err(err) => println!("Failed to send new sign-in email: {}", err),
+    };
+}
+
+fn send_alert(
+    user_id: &String,
+    ip: &String,
+    family: &String,
+    session_token: &String,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    let user = account_services::get_user(user_id, conn)?;
+
+    let email = match user.email {
+        Some(data) => data,
+        None => return Err(String::from("ERR_NO_EMAIL")),
+    };
+
+    let report_token = nanoid!(48);
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.login_alerts(token, user_id, session_id, created_at) VALUES ($1, $2, $3, $4);",
+        &[
+            &credentials::hash_secret(&report_token),
+            &user_id,
+            &sessions::session_key(session_token),
+            &chrono::Utc::now(),
+        ],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    mailer::send_template(
+        &email,
+        "New sign-in to your PSU account",
+        "new-sign-in-template",
+        &[
+            ("username", &user.username.unwrap_or_default()),
+            ("ip_address", ip),
+            ("device", family),
+            ("location", &locate(ip).unwrap_or_else(|| String::from("Unknown location"))),
+            ("signed_in_at", &chrono::Utc::now().format("%Y-%m-%d %H:%M UTC").to_string()),
+            ("not_me_token", &report_token),
+        ],
+    )
+}
+
+// The "this wasn't me" link. Every session on the account is signed out, not only the
+// reported one, since whoever got in may have opened more. The password then has to be reset
+// before it can be used again.
+pub fn report_not_me(token: &String, context: &AuditContext, conn: &MainPGDatabase) -> Result<String, String> {
+    let rows_recieved: Rows = match conn.query(
+        &format!(
+            "DELETE FROM lunar_buffxnte_psu.login_alerts WHERE token = $1 AND created_at > now() - INTERVAL '{} DAYS' RETURNING user_id, session_id;",
+            REPORT_LINK_DAYS
+        ),
+        &[&credentials::hash_secret(token)],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("Invalid Token."));
+    }
+
+    let user_id: String = rows_recieved.get(0).get("user_id");
+    let session_id: String = rows_recieved.get(0).get("session_id");
+
+    // Dropped on its own first so the audit log shows whether it was still open.
+    let reported_session_active = match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.sessions WHERE id = $1 AND user_id = $2;",
+        &[&session_id, &user_id],
+    ) {
+        Ok(count) => count > 0,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET password_reset_required = true, updated_at = now() WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let revoked = sessions::revoke_other_sessions(&user_id, None, conn)?;
+
+    audit_log::record(
+        Some(&user_id),
+        None,
+        "login.reported",
+        serde_json::json!({"sessions_revoked": revoked, "reported_session_active": reported_session_active}),
+        context,
+        conn,
+    );
+
+    // Saves the user a trip to the reset form. If it can't be sent they can still ask for one.
+    let user = account_services::get_user(&user_id, conn)?;
+
+    if let Some(email) = user.email {
+        match passwords::send_reset_email(&email, &context.ip_address.clone().unwrap_or_default(), conn) {
+            Ok(_data) => (),
+            Err(err) => println!("Failed to send reset email after a reported sign-in: {}", err),
+        };
+    }
+
+    Ok(String::from("SUCCESS"))
+}
+
+// Set once the owner reports a sign-in they didn't make. Every way of signing in checks this
+// before handing out a session, not only the password form, or whoever got in could simply
+// come back through Discord or a 2FA challenge they already started.
+pub fn require_no_reset(
+    user_id: &String,
+    method: &str,
+    context: &AuditContext,
+    conn: &MainPGDatabase,
+) -> Result<(), String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT password_reset_required FROM lunar_buffxnte_psu.users WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_INVALID_CRED"));
+    }
+
+    let reset_required: bool = rows_recieved.get(0).get("password_reset_required");
+
+    if reset_required {
+        audit_log::record(
+            Some(user_id),
+            None,
+            "login.failure",
+            serde_json::json!({"method": method, "reason": "password_reset_required"}),
+            context,
+            conn,
+        );
+        return Err(String::from("ERR_PASSWORD_RESET_REQUIRED"));
+    }
+
+    Ok(())
+}
+
+pub const PURGE_JOB: scheduler::Job = scheduler::Job {
+    name: "LOGIN_ALERT_PURGE",
+    counts: "stale sign-in records deleted",
+    default_interval_secs: 60 * 60,
+    run: purge_stale,
+};
+
+pub fn purge_stale(conn: &Connection) -> Result<u64, String> {
+    let alerts = match conn.execute(
+        &format!(
+            "DELETE FROM lunar_buffxnte_psu.login_alerts WHERE created_at < now() - INTERVAL '{} DAYS';",
+            REPORT_LINK_DAYS
+        ),
+        &[],
+    ) {
+        Ok(count) => count,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.login_devices WHERE last_seen_at < now() - ($1 || ' days')::INTERVAL;",
+        &[&lookback_days().to_string()],
+    ) {
+        Ok(count) => Ok(alerts + count),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use crate::modules::account_services::passwords;
+use crate::modules::{config, mailer, scheduler};
+use crate::MainPGDatabase;
+
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use nanoid::nanoid;
+use postgres::rows::Rows;
+use postgres::Connection;
+
+struct Limits {
+    delay_after: i32,
+    lockout_after: i32,
+    lockout_secs: i64,
+    window_secs: i64,
+}
+
+// Accounts start getting delayed after 3 failures and are locked for 15 minutes after 10.
+fn account_limits() -> Limits {
+    Limits {
+        delay_after: config::env_or("LOGIN_DELAY_AFTER", 3),
+        lockout_after: config::env_or("LOGIN_LOCKOUT_AFTER", 10),
+        lockout_secs: config::env_or("LOGIN_LOCKOUT_SECS", 15 * 60),
+        window_secs: config::env_or("LOGIN_FAILURE_WINDOW_SECS", 60 * 60),
+    }
+}
+
+// A single address gets more room since schools and VPNs put many users behind one IP.
+fn ip_limits() -> Limits {
+    Limits {
+        delay_after: config::env_or("LOGIN_IP_DELAY_AFTER", 10),
+        lockout_after: config::env_or("LOGIN_IP_LOCKOUT_AFTER", 50),
+        lockout_secs: config::env_or("LOGIN_LOCKOUT_SECS", 15 * 60),
+        window_secs: config::env_or("LOGIN_FAILURE_WINDOW_SECS", 60 * 60),
+    }
+}
+
+const MAX_DELAY_SECS: i64 = 60;
+
+// Known accounts are counted by id, so switching between the username and the email doesn't
+// start a fresh count. Identifiers that don't match an account are counted as typed.
+fn account_key(user_id: Option<&String>, identifier: &String) -> String {
+    match user_id {
+        Some(user_id) => format!("user:{}", user_id),
+        None => format!("account:{}", identifier.trim().to_lowercase()),
+    }
+}
+
+fn ip_key(ip: &String) -> String {
+    format!("ip:{}", ip)
+}
+
+// 1s, 2s, 4s... after the delay threshold, capped at a minute.
+fn required_delay(failures: i32, limits: &Limits) -> i64 {
+    if failures < limits.delay_after {
+        return 0;
+    }
+
+    let exponent = (failures - limits.delay_after).min(6) as u32;
+    (2i64.pow(exponent)).min(MAX_DELAY_SECS)
+}
+
+fn check_key(key: &String, limits: &Limits, conn: &MainPGDatabase) -> Result<(), String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT failures, last_failure_at, locked_until FROM lunar_buffxnte_psu.login_failures WHERE key = $1;",
+        &[&key],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let failures: i32 = rows_recieved.get(0).get("failures");
+    let last_failure_at: DateTime<Utc> = rows_recieved.get(0).get("last_failure_at");
+    let locked_until: Option<DateTime<Utc>> = rows_recieved.get(0).get("locked_until");
+
+    if let Some(locked_until) = locked_until {
+        if locked_until > now {
+            return Err(String::from("ERR_TOO_MANY_ATTEMPTS"));
+        }
+    }
+
+    if now - last_failure_at > Duration::seconds(limits.window_secs) {
+        return Ok(());
+    }
+
+    if now - last_failure_at < Duration::seconds(required_delay(failures, limits)) {
+        return Err(String::from("ERR_TOO_MANY_ATTEMPTS"));
+    }
+
+    Ok(())
+}
+
+// Called before the password or code is even looked at. The same error comes back whether
+// or not the account exists.
+pub fn check_attempt(
+    user_id: Option<&String>,
+    identifier: &String,
+    ip: &String,
+    conn: &MainPGDatabase,
+) -> Result<(), String> {
+    check_key(&ip_key(ip), &ip_limits(), conn)?;
+    check_key(&account_key(user_id, identifier), &account_limits(), conn)
+}
+
+// Unknown accounts and accounts without a password still pay for a bcrypt verify, so response
+// times don't give away which usernames and emails exist.
+pub fn verify_dummy_password(password: &String) {
+    lazy_static! {
+        static ref DUMMY_HASH: String = passwords::hash_password(&nanoid!()).unwrap_or_default();
+    }
+
+    let _ = bcrypt::verify(password, &DUMMY_HASH);
+}
+
+// Returns true when this failure is the one that locked the key.
+fn record_key_failure(key: &String, limits: &Limits, conn: &MainPGDatabase) -> Result<bool, String> {
+    let rows_recieved: Rows = match conn.query(
+        r#"INSERT INTO lunar_buffxnte_psu.login_failures AS lf (key, failures, last_failure_at)
+        VALUES ($1, 1, now())
+        ON CONFLICT (key) DO UPDATE SET
+            failures = CASE WHEN lf.last_failure_at < now() - ($2 || ' seconds')::INTERVAL THEN 1 ELSE lf.failures + 1 END,
+            last_failure_at = now()
+        RETURNING failures;"#,
+        &[&key, &limits.window_secs.to_string()],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let failures: i32 = rows_recieved.get(0).get("failures");
+
+    if failures < limits.lockout_after {
+        return Ok(false);
+    }
+
+    // The count starts over with the lock, so guessing after it expires earns another lock
+    // instead of carrying on at the capped delay.
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.login_failures SET locked_until = $1, failures = 0 WHERE key = $2;",
+        &[&(Utc::now() + Duration::seconds(limits.lockout_secs)), &key],
+    ) {
+        Ok(_data) => Ok(true),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+// `account_email` is only known when the identifier matched a real account. It's used to
+// warn the owner, never to change what the caller sees.
+pub fn record_failure(
+    user_id: Option<&String>,
+    identifier: &String,
+    ip: &String,
+    account_email: Option<(String, String)>,
+    conn: &MainPGDatabase,
+) {
+    match record_key_failure(&ip_key(ip), &ip_limits(), conn) {
+        Ok(_data) => (),
+        Err(err) => println!("Failed to record login failure: {}", err),
+    };
+
+    let locked = match record_key_failure(&account_key(user_id, identifier), &account_limits(), conn) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Failed to record login failure: {}", err);
+            false
+        }
+    };
+
+    if let (true, Some((email, display_name))) = (locked, account_email) {
+        let minutes = (account_limits().lockout_secs / 60).to_string();
+
+        match mailer::send_template(
+            &email,
+            "PSU account temporarily locked",
+            "account-locked-template",
+            &[("username", &display_name), ("ip_address", ip), ("lockout_minutes", &minutes)],
+        ) {
+            Ok(_data) => (),
+            Err(err) => println!("Failed to send lockout email: {}", err),
+        };
+    }
+}
+
+// Only once every factor has passed, a correct password alone doesn't reset the count.
+pub fn record_success(user_id: &String, conn: &MainPGDatabase) {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.login_failures WHERE key = $1;",
+        &[&account_key(Some(user_id), user_id)],
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("SQL ERROR: {}", err),
+    };
+}
+
+// Clears the account's counter and any still kept under its username or email.
+pub fn unlock_account(user_id: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT username, email FROM lunar_buffxnte_psu.users WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_USER_NOT_FOUND"));
+    }
+
+    let username: Option<String> = rows_recieved.get(0).get("username");
+    let email: Option<String> = rows_recieved.get(0).get("email");
+
+    let keys: Vec<String> = vec![username, email]
+        .into_iter()
+        .flatten()
+        .map(|identifier| account_key(None, &identifier))
+        .chain(std::iter::once(account_key(Some(user_id), user_id)))
+        .collect();
+
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.login_failures WHERE key = ANY($1);",
+        &[&keys],
+    ) {
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+pub const PURGE_JOB: scheduler::Job = scheduler::Job {
+    name: "LOGIN_FAILURE_PURGE",
+    counts: "stale login failures deleted",
+    default_interval_secs: 60 * 60,
+    run: purge_stale_failures,
+};
+
+pub fn purge_stale_failures(conn: &Connection) -> Result<u64, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.login_failures WHERE last_failure_at < now() - INTERVAL '1 DAY' AND (locked_until IS NULL OR locked_until < now());",
+        &[],
+    ) {
+        Ok(count) => Ok(count),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::modules::account_services::{self, login_protection, sessions};
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::{config, credentials, mailer, scheduler};
+use crate::MainPGDatabase;
+
+use bcrypt::{hash, verify};
+use postgres::rows::Rows;
+
+const MIN_LENGTH: usize = 8;
+// bcrypt silently ignores everything past 72 bytes.
+const MAX_LENGTH: usize = 72;
+
+const RESET_WINDOW: &str = "1 HOUR";
+
+fn reset_limit_per_email() -> i64 {
+    config::env_or("RESET_LIMIT_PER_EMAIL", 3)
+}
+
+fn reset_limit_per_ip() -> i64 {
+    config::env_or("RESET_LIMIT_PER_IP", 10)
+}
+
+// The one set of rules for every place a password gets chosen. `user_inputs` are things like
+// the username and email, which zxcvbn treats as easy to guess.
+pub fn check_strength(password: &String, user_inputs: &[&str]) -> Result<(), String> {
+    if password.len() < MIN_LENGTH {
+        return Err(format!("Password needs to be at least {} characters", MIN_LENGTH));
+    }
+
+    if password.len() > MAX_LENGTH {
+        return Err(format!("Password can't be longer than {} characters", MAX_LENGTH));
+    }
+
+    let score_estimate = match zxcvbn::zxcvbn(password, user_inputs) {
+        Ok(data) => data,
+        Err(_err) => return Err("Password is too weak".to_string()),
+    };
+
+    if score_estimate.score() <= 2 {
+        match score_estimate.feedback() {
+            Some(data) => match data.warning() {
+                Some(warning) => return Err(format!("Password is too weak, Warning: {}", warning)),
+                None => match data.suggestions().first() {
+                    Some(suggestion) => {
+                        return Err(format!("Password is too weak, Suggestion: {}", suggestion))
+                    }
+                    None => return Err("Password is too weak".to_string()),
+                },
+            },
+            None => return Err("Password is too weak".to_string()),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn hash_password(password: &String) -> Result<String, String> {
+    match hash(password, 12) {
+        Ok(data) => Ok(data),
+        Err(err) => {
+            println!("BCRYPT ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+fn set_password(user_id: &String, password: &String, conn: &MainPGDatabase) -> Result<(), String> {
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET password = $1, password_reset_required = false, updated_at = $2 WHERE id = $3;",
+        &[&hash_password(password)?, &chrono::Utc::now(), &user_id],
+    ) {
+        Ok(_data) => Ok(()),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("Something went wrong changing the password"))
+        }
+    }
+}
+
+// Every session except `keep_session` is signed out, so anyone who had the old password
+// loses access straight away.
+pub fn change_password(
+    user_id: &String,
+    current_password: &String,
+    new_password: &String,
+    keep_session: Option<&String>,
+    context: &AuditContext,
+    conn: &MainPGDatabase,
+) -> Result<u64, String> {
+    let user = account_services::get_user(user_id, conn)?;
+
+    let password_hash = match &user.password {
+        Some(data) => data,
+        // Accounts made through Discord have no password until they set one with a reset.
+        None => return Err(String::from("ERR_NO_PASSWORD_SET")),
+    };
+
+    if !verify(current_password, password_hash).unwrap_or(false) {
+        return Err(String::from("ERR_INVALID_CRED"));
+    }
+
+    if current_password == new_password {
+        return Err(String::from("The new password must be different from the current one"));
+    }
+
+    check_strength(
+        new_password,
+        &[
+            user.username.as_deref().unwrap_or_default(),
+            user.email.as_deref().unwrap_or_default(),
+        ],
+    )?;
+
+    set_password(user_id, new_password, conn)?;
+
+    let revoked = sessions::revoke_other_sessions(user_id, keep_session, conn)?;
+
+    audit_log::record(
+        Some(user_id),
+        None,
+        "password.change",
+        serde_json::json!({"sessions_revoked": revoked}),
+        context,
+        conn,
+    );
+
+    Ok(revoked)
+}
+
+// Every request is logged, whether or not the email belongs to an account, so the limits
+// can't be used to find out which addresses are registered.
+fn check_reset_limits(email: &String, ip: &String, conn: &MainPGDatabase) -> Result<(), String> {
+    let rows_recieved: Rows = match conn.query(
+        &format!(
+            r#"SELECT
+            COUNT(*) FILTER (WHERE LOWER(email) = LOWER($1)) AS email_requests,
+            COUNT(*) FILTER (WHERE ip_address = $2) AS ip_requests
+            FROM lunar_buffxnte_psu.password_reset_requests
+            WHERE created_at > now() - INTERVAL '{}';"#,
+            RESET_WINDOW
+        ),
+        &[&email, &ip],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let email_requests: i64 = rows_recieved.get(0).get("email_requests");
+    let ip_requests: i64 = rows_recieved.get(0).get("ip_requests");
+
+    if email_requests >= reset_limit_per_email() || ip_requests >= reset_limit_per_ip() {
+        return Err(String::from("ERR_RATE_LIMITED"));
+    }
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.password_reset_requests(email, ip_address, created_at) VALUES ($1, $2, $3);",
+        &[&email, &ip, &chrono::Utc::now()],
+    ) {
+        Ok(_data) => Ok(()),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+pub fn send_reset_email(email_address: &String, ip: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    check_reset_limits(email_address, ip, conn)?;
+
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT id, username FROM lunar_buffxnte_psu.users WHERE email = $1 ORDER BY id ASC LIMIT 1"#,
+        &[&email_address],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    // Unknown addresses get the same answer so the endpoint can't be used to find accounts.
+    if rows_recieved.is_empty() {
+        return Ok(String::from("SUCCESS"));
+    };
+
+    let user_id: String = rows_recieved.get(0).get("id");
+    let username: Option<String> = rows_recieved.get(0).get("username");
+
+    let token = nanoid::nanoid!();
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.password_resets(email, token, created_at, user_id) VALUES ($1, $2, $3, $4);",
+        &[&email_address, &credentials::hash_secret(&token), &chrono::Utc::now(), &user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from(
+                "Something went wrong creating the password reset token",
+            ));
+        }
+    };
+
+    match mailer::send_template(
+        email_address,
+        "PSU Password Reset",
+        "password-reset-template",
+        &[
+            ("username", &username.unwrap_or_default()),
+            ("password_reset_token", &token),
+        ],
+    ) {
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            // A token nobody received is just something else that could leak.
+            match conn.execute(
+                "DELETE FROM lunar_buffxnte_psu.password_resets WHERE token = $1;",
+                &[&credentials::hash_secret(&token)],
+            ) {
+                Ok(_data) => (),
+                Err(err) => println!("SQL ERROR: {}", err),
+            };
+
+            println!("Failed to send password reset email: {}", err);
+            Err(String::from("Something went wrong sending the reset email. Try again later."))
+        }
+    }
+}
+
+fn reset_token_owner(token: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT user_id FROM lunar_buffxnte_psu.password_resets WHERE created_at BETWEEN NOW() - INTERVAL '30 MINUTES' AND NOW() AND token = $1;"#,
+        &[&credentials::hash_secret(token)],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("Invalid Token."));
+    };
+
+    Ok(rows_recieved.get(0).get("user_id"))
+}
+
+// The token is only consumed once the new password passes the strength rules, so a weak
+// choice doesn't cost the user their link. Consuming it is a single DELETE, so two requests
+// racing with the same token can't both get through.
+pub fn finalise_password_reset(
+    token: &String,
+    password: &String,
+    context: &AuditContext,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    let user_id = reset_token_owner(token, conn)?;
+    let user = account_services::get_user(&user_id, conn)?;
+
+    check_strength(
+        password,
+        &[
+            user.username.as_deref().unwrap_or_default(),
+            user.email.as_deref().unwrap_or_default(),
+        ],
+    )?;
+
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.password_resets WHERE token = $1 AND user_id = $2;",
+        &[&credentials::hash_secret(token), &user_id],
+    ) {
+        Ok(0) => return Err(String::from("Invalid Token.")),
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    set_password(&user_id, password, conn)?;
+
+    // Any other links that were sent out are dead now too.
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.password_resets WHERE user_id = $1;",
+        &[&user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("SQL ERROR: {}", err),
+    };
+
+    let revoked = sessions::revoke_other_sessions(&user_id, None, conn)?;
+
+    // Whoever reset the password owns the inbox, so a lockout shouldn't keep them out.
+    match login_protection::unlock_account(&user_id, conn) {
+        Ok(_data) => (),
+        Err(err) => println!("Failed to clear login failures after reset: {}", err),
+    };
+
+    audit_log::record(
+        Some(&user_id),
+        None,
+        "password.reset",
+        serde_json::json!({"sessions_revoked": revoked}),
+        context,
+        conn,
+    );
+
+    Ok(String::from("Successfully Reset Password"))
+}
+
+pub const PURGE_JOB: scheduler::Job = scheduler::Job {
+    name: "RESET_REQUEST_PURGE",
+    counts: "old password reset requests deleted",
+    default_interval_secs: 60 * 60,
+    run: purge_reset_requests,
+};
+
+pub fn purge_reset_requests(conn: &postgres::Connection) -> Result<u64, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.password_reset_requests WHERE created_at < now() - INTERVAL '1 DAY';",
+        &[],
+    ) {
+        Ok(count) => Ok(count),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::modules::account_services::roles;
+use crate::MainPGDatabase;
+
+use lazy_static::lazy_static;
+use postgres::rows::Rows;
+use regex::Regex;
+
+// Holders of this node can do anything unless the check asks for an exact grant. It ranks
+// below every other match so an explicit deny still applies to admins.
+pub const ADMIN_PERMISSION: &str = "system.admin";
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Source {
+    Role,
+    User,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    node: String,
+    allow: bool,
+    source: Source,
+}
+
+// A user's resolved permissions: their role's nodes plus any per-user grants and denies.
+#[derive(Clone, Debug)]
+pub struct PermissionSet {
+    entries: Vec<Entry>,
+}
+
+pub fn is_valid_node(node: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r#"^-?(\*|[a-z0-9_]+(\.[a-z0-9_]+)*(\.\*)?)$"#).unwrap();
+    }
+    RE.is_match(node) && node.len() <= 128
+}
+
+// How specifically `pattern` matches `node`, or None when it doesn't match at all.
+// Exact matches beat wildcards, and longer wildcard prefixes beat shorter ones.
+fn match_rank(pattern: &str, node: &str) -> Option<usize> {
+    if pattern == node {
+        return Some(usize::MAX);
+    }
+
+    if pattern == "*" {
+        return Some(1);
+    }
+
+    if pattern.ends_with(".*") {
+        let prefix = &pattern[..pattern.len() - 2];
+
+        if node == prefix || node.starts_with(&format!("{}.", prefix)) {
+            return Some(prefix.split('.').count() + 1);
+        }
+    }
+
+    None
+}
+
+impl PermissionSet {
+    fn new(role_perms: Vec<String>, user_perms: Vec<String>) -> Self {
+        let mut entries: Vec<Entry> = Default::default();
+
+        for (nodes, source) in vec![(role_perms, Source::Role), (user_perms, Source::User)] {
+            for node in nodes {
+                let (node, allow) = match node.strip_prefix('-') {
+                    Some(denied) => (denied.to_string(), false),
+                    None => (node, true),
+                };
+
+                entries.push(Entry {
+                    node,
+                    allow,
+                    source,
+                });
+            }
+        }
+
+        PermissionSet { entries }
+    }
+
+    // The most specific matching entry wins. Ties go to per-user overrides over the role,
+    // then to denies over grants.
+    pub fn has(&self, permission: &str, exact: bool) -> bool {
+        let mut best: Option<(usize, Source, bool)> = None;
+
+        for entry in &self.entries {
+            let rank = if entry.node == ADMIN_PERMISSION && entry.node != permission {
+                if exact {
+                    continue;
+                }
+                0
+            } else {
+                match match_rank(&entry.node, permission) {
+                    Some(rank) if !exact || rank == usize::MAX => rank,
+                    _ => continue,
+                }
+            };
+
+            // Denies sort above grants at the same rank and source.
+            let candidate = (rank, entry.source, !entry.allow);
+
+            if best.map_or(true, |best| candidate > best) {
+                best = Some(candidate);
+            }
+        }
+
+        match best {
+            Some((_rank, _source, deny)) => !deny,
+            None => false,
+        }
+    }
+
+    // Flattened view for the dashboard, which checks nodes with a plain `includes`.
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.allow && self.has(&entry.node, false))
+            .map(|entry| entry.node.clone())
+            .collect();
+
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+}
+
+// Only the per-user overrides, denies included with their leading '-'.
+pub fn get_user_overrides(user_id: &String, conn: &MainPGDatabase) -> Result<Vec<String>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT permission FROM lunar_buffxnte_psu.user_permissions WHERE user_id = $1 ORDER BY permission ASC;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    Ok(rows_recieved.iter().map(|row| row.get("permission")).collect())
+}
+
+pub fn resolve(user_id: &String, conn: &MainPGDatabase) -> Result<PermissionSet, String> {
+    let role = roles::get_user_role(user_id, conn)?;
+    let overrides = get_user_overrides(user_id, conn)?;
+
+    Ok(PermissionSet::new(role.permissions, overrides))
+}
+
+pub fn get_user_perms(user_id: &String, conn: &MainPGDatabase) -> Result<Vec<String>, String> {
+    Ok(resolve(user_id, conn)?.nodes())
+}
+
+// `exact` ignores wildcards and system.admin, for checks that must be granted explicitly.
+// Routes should prefer the request-cached `PermissionCache` guard over calling this directly.
+pub fn has_perms(
+    user_id: &String,
+    permission: &String,
+    conn: &MainPGDatabase,
+    exact: bool,
+) -> Result<bool, String> {
+    Ok(resolve(user_id, conn)?.has(permission, exact))
+}
+
+pub fn add_perm(
+    user_id: &String,
+    permission: &String,
+    granted_by: &String,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    if !is_valid_node(permission) {
+        return Err(String::from("ERR_INVALID_PERMISSION"));
+    }
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.user_permissions(user_id, permission, granted_by, created_at) VALUES ($1, $2, $3, $4) ON CONFLICT (user_id, permission) DO NOTHING;",
+        &[&user_id, &permission, &granted_by, &chrono::Utc::now()],
+    ) {
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+pub fn remove_perm(user_id: &String, permission: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.user_permissions WHERE user_id = $1 AND permission = $2;",
+        &[&user_id, &permission],
+    ) {
+        Ok(0) => Err(String::from("ERR_PERMISSION_NOT_FOUND")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
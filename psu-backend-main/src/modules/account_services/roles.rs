@@ -1,25 +1,201 @@
+use crate::modules::config;
 use crate::MainPGDatabase;
 
+use postgres::rows::{Row, Rows};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
 pub struct Role {
-  id: String,
-  name: String,
-  desc: String,
-  permissions: Vec<String>,
-  level: i64
+    pub id: i64,
+    pub name: String,
+    pub desc: String,
+    pub permissions: Vec<String>,
+    pub level: i64,
 }
 
-pub fn get_roles(conn: &MainPGDatabase) {
+fn row_to_role(row: &Row) -> Role {
+    Role {
+        id: row.get("id"),
+        name: row.get("name"),
+        desc: row.get("description"),
+        permissions: row.get("permissions"),
+        level: row.get("level"),
+    }
+}
 
+// The role given to new accounts. Set DEFAULT_ROLE_ID to change it.
+pub fn default_role_id() -> i64 {
+    config::env_or("DEFAULT_ROLE_ID", 2)
 }
 
-pub fn set_role(conn: &MainPGDatabase) {
-  
+pub fn get_roles(conn: &MainPGDatabase) -> Result<Vec<Role>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.roles ORDER BY level DESC, id ASC;",
+        &[],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    Ok(rows_recieved.iter().map(|row| row_to_role(&row)).collect())
 }
 
-pub fn modify_role(conn: &MainPGDatabase) {
-  
+pub fn get_role(role_id: &i64, conn: &MainPGDatabase) -> Result<Role, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.roles WHERE id = $1;",
+        &[&role_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_ROLE_NOT_FOUND"));
+    }
+
+    Ok(row_to_role(&rows_recieved.get(0)))
 }
 
-pub fn create_role(conn: &MainPGDatabase) {
+// Users without a role (or with a role that was deleted) fall back to the default role.
+pub fn get_user_role(user_id: &String, conn: &MainPGDatabase) -> Result<Role, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT role_id FROM lunar_buffxnte_psu.users WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_USER_NOT_FOUND"));
+    }
 
-}
\ No newline at end of file
+    let role_id: Option<i64> = rows_recieved.get(0).get("role_id");
+
+    match get_role(&role_id.unwrap_or(default_role_id()), conn) {
+        Err(err) if err == "ERR_ROLE_NOT_FOUND" => get_role(&default_role_id(), conn),
+        result => result,
+    }
+}
+
+// Staff may only manage roles strictly below their own level, so a moderator can't
+// promote themselves or edit the admin role.
+pub fn can_manage(actor_id: &String, role_level: i64, conn: &MainPGDatabase) -> Result<bool, String> {
+    let actor_role = get_user_role(actor_id, conn)?;
+
+    Ok(actor_role.level > role_level)
+}
+
+fn validate_role(name: &String, level: &i64) -> Result<(), String> {
+    if name.trim().len() < 2 || name.len() > 64 {
+        return Err(String::from("Role name must be between 2 and 64 characters"));
+    }
+
+    if *level < 0 {
+        return Err(String::from("Role level can't be negative"));
+    }
+
+    Ok(())
+}
+
+pub fn create_role(
+    name: &String,
+    desc: &String,
+    permissions: &Vec<String>,
+    level: &i64,
+    conn: &MainPGDatabase,
+) -> Result<i64, String> {
+    validate_role(name, level)?;
+
+    let rows_recieved: Rows = match conn.query(
+        "INSERT INTO lunar_buffxnte_psu.roles(name, description, permissions, level) VALUES ($1, $2, $3, $4) RETURNING id;",
+        &[&name.trim(), &desc, &permissions, &level],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("Something went wrong creating the role. Does the name already exist?"));
+        }
+    };
+
+    Ok(rows_recieved.get(0).get("id"))
+}
+
+pub fn modify_role(
+    role_id: &i64,
+    name: &String,
+    desc: &String,
+    permissions: &Vec<String>,
+    level: &i64,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    validate_role(name, level)?;
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.roles SET name = $1, description = $2, permissions = $3, level = $4 WHERE id = $5;",
+        &[&name.trim(), &desc, &permissions, &level, &role_id],
+    ) {
+        Ok(0) => Err(String::from("ERR_ROLE_NOT_FOUND")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("Something went wrong modifying the role"))
+        }
+    }
+}
+
+pub fn delete_role(role_id: &i64, conn: &MainPGDatabase) -> Result<String, String> {
+    if *role_id == default_role_id() {
+        return Err(String::from("The default role can't be deleted"));
+    }
+
+    // Move anyone still holding the role back to the default so nobody is left without one.
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET role_id = $1 WHERE role_id = $2;",
+        &[&default_role_id(), &role_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.roles WHERE id = $1;",
+        &[&role_id],
+    ) {
+        Ok(0) => Err(String::from("ERR_ROLE_NOT_FOUND")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+pub fn set_role(user_id: &String, role_id: &i64, conn: &MainPGDatabase) -> Result<String, String> {
+    // Make sure the role exists before pointing a user at it.
+    get_role(role_id, conn)?;
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET role_id = $1, updated_at = $2 WHERE id = $3;",
+        &[&role_id, &chrono::Utc::now(), &user_id],
+    ) {
+        Ok(0) => Err(String::from("ERR_USER_NOT_FOUND")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::modules::{config, credentials, scheduler};
+
+use postgres::rows::Rows;
+use postgres::Connection;
+use serde::Serialize;
+use std::time::SystemTime;
+
+#[derive(Debug, Serialize)]
+pub struct ActiveSession {
+    pub id: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: Option<i64>,
+    pub last_activity: i64,
+    pub current: bool,
+    // Opened by PSU support through impersonation. Users can revoke it like any other session.
+    pub support_access: bool,
+    pub read_only: bool,
+    pub expires_at: Option<i64>,
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// Sessions die this long after being created, no matter how active they are. Default 30 days.
+pub fn absolute_timeout() -> i64 {
+    config::env_or("SESSION_ABSOLUTE_TIMEOUT_SECS", 60 * 60 * 24 * 30)
+}
+
+// Sessions die after this long without a request. Default 7 days.
+pub fn idle_timeout() -> i64 {
+    config::env_or("SESSION_IDLE_TIMEOUT_SECS", 60 * 60 * 24 * 7)
+}
+
+// `last_activity` is only rewritten when it is at least this stale, so a burst of requests
+// from the dashboard doesn't turn into a burst of UPDATEs.
+const ACTIVITY_REFRESH_SECS: i64 = 60;
+
+// Sessions are stored under the hash of their token, never the token itself.
+pub fn session_key(token: &str) -> String {
+    credentials::hash_secret(token)
+}
+
+pub fn is_expired(created_at: Option<i64>, last_activity: i64, now: i64) -> bool {
+    if now - last_activity > idle_timeout() {
+        return true;
+    }
+
+    match created_at {
+        Some(created_at) => now - created_at > absolute_timeout(),
+        None => false,
+    }
+}
+
+pub fn touch_session(token: &String, last_activity: i64, conn: &Connection) {
+    let now = now_secs();
+
+    if now - last_activity < ACTIVITY_REFRESH_SECS {
+        return;
+    }
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.sessions SET last_activity = $1 WHERE id = $2;",
+        &[&now, &session_key(token)],
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("SQL ERROR: {}", err),
+    };
+}
+
+pub fn logout(token: &String, conn: &Connection) -> Result<String, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.sessions WHERE id = $1;",
+        &[&session_key(token)],
+    ) {
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+pub fn list_sessions(
+    user_id: &String,
+    current_token: Option<&String>,
+    conn: &Connection,
+) -> Result<Vec<ActiveSession>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.sessions WHERE user_id = $1 ORDER BY last_activity DESC",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let now = now_secs();
+    let current_key = current_token.map(|token| session_key(token));
+    let mut sessions: Vec<ActiveSession> = Default::default();
+
+    for row in &rows_recieved {
+        let key: String = row.get("id");
+        let created_at: Option<i64> = row.get("created_at");
+        let last_activity: i64 = row.get("last_activity");
+        let expires_at: Option<i64> = row.get("expires_at");
+        let impersonator_id: Option<String> = row.get("impersonator_id");
+
+        if is_expired(created_at, last_activity, now) || expires_at.map_or(false, |expires_at| expires_at <= now) {
+            continue;
+        }
+
+        sessions.push(ActiveSession {
+            id: row.get("public_id"),
+            ip_address: row.get("ip_address"),
+            user_agent: row.get("user_agent"),
+            created_at: created_at,
+            last_activity: last_activity,
+            current: current_key.as_ref() == Some(&key),
+            support_access: impersonator_id.is_some(),
+            read_only: row.get("read_only"),
+            expires_at: expires_at,
+        })
+    }
+
+    Ok(sessions)
+}
+
+pub fn revoke_session(
+    user_id: &String,
+    public_id: &String,
+    conn: &Connection,
+) -> Result<String, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.sessions WHERE user_id = $1 AND public_id = $2;",
+        &[&user_id, &public_id],
+    ) {
+        Ok(0) => Err(String::from("ERR_SESSION_NOT_FOUND")),
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+// Kills every session for the user except `keep`. Passing None signs the user out everywhere.
+pub fn revoke_other_sessions(
+    user_id: &String,
+    keep: Option<&String>,
+    conn: &Connection,
+) -> Result<u64, String> {
+    let result = match keep {
+        Some(token) => conn.execute(
+            "DELETE FROM lunar_buffxnte_psu.sessions WHERE user_id = $1 AND id <> $2;",
+            &[&user_id, &session_key(token)],
+        ),
+        None => conn.execute(
+            "DELETE FROM lunar_buffxnte_psu.sessions WHERE user_id = $1;",
+            &[&user_id],
+        ),
+    };
+
+    match result {
+        Ok(count) => Ok(count),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+pub const PURGE_JOB: scheduler::Job = scheduler::Job {
+    name: "SESSION_PURGE",
+    counts: "expired sessions deleted",
+    default_interval_secs: 60 * 60,
+    run: purge_expired_sessions,
+};
+
+pub fn purge_expired_sessions(conn: &Connection) -> Result<u64, String> {
+    let now = now_secs();
+
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.sessions WHERE last_activity < $1 OR created_at < $2 OR expires_at <= $3;",
+        &[&(now - idle_timeout()), &(now - absolute_timeout()), &now],
+    ) {
+        Ok(count) => Ok(count),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::modules::account_services::{self, login_alerts, login_protection, sessions};
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::scheduler;
+use crate::MainPGDatabase;
+
+use bcrypt::{hash, verify};
+use hmac::{Hmac, Mac, NewMac};
+use nanoid::nanoid;
+use postgres::rows::Rows;
+use postgres::Connection;
+use rand::RngCore;
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// How many steps either side of now we accept, to cover clock drift on the user's phone.
+const TOTP_SKEW: i64 = 1;
+const CHALLENGE_LIFETIME_SECS: i64 = 5 * 60;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn is_enabled(user: &crate::modules::user::User) -> bool {
+    user.two_factor_options.as_deref() == Some("totp")
+}
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+pub fn otpauth_uri(secret: &str, account_name: &str) -> String {
+    format!(
+        "otpauth://totp/PSU:{}?secret={}&issuer=PSU&algorithm=SHA1&digits={}&period={}",
+        account_name.replace(' ', "%20"),
+        secret,
+        TOTP_DIGITS,
+        TOTP_STEP_SECS
+    )
+}
+
+// RFC 4226 HOTP, which RFC 6238 TOTP feeds with the current time step.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = HmacSha1::new_varkey(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = ((digest[offset] as u32 & 0x7f) << 24)
+        | ((digest[offset + 1] as u32) << 16)
+        | ((digest[offset + 2] as u32) << 8)
+        | (digest[offset + 3] as u32);
+
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+// Returns the time step the code matched so callers can refuse to accept it twice.
+pub fn verify_totp(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    let code = code.trim().replace(' ', "");
+
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+
+    let code: u32 = code.parse().ok()?;
+    let current_step = now / TOTP_STEP_SECS;
+
+    for step in (current_step - TOTP_SKEW)..=(current_step + TOTP_SKEW) {
+        if step >= 0 && hotp(&key, step as u64) == code {
+            return Some(step);
+        }
+    }
+
+    None
+}
+
+// Accepts either a TOTP code or an unused recovery code for a user with 2FA enabled.
+pub fn verify_second_factor(
+    user_id: &String,
+    code: &String,
+    conn: &MainPGDatabase,
+) -> Result<bool, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT two_factor_secret, two_factor_last_step FROM lunar_buffxnte_psu.users WHERE id = $1 AND two_factor_options = 'totp';",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_2FA_NOT_ENABLED"));
+    }
+
+    let secret: Option<String> = rows_recieved.get(0).get("two_factor_secret");
+    let last_step: Option<i64> = rows_recieved.get(0).get("two_factor_last_step");
+
+    if let Some(secret) = secret {
+        if let Some(step) = verify_totp(&secret, code, sessions::now_secs()) {
+            if Some(step) <= last_step {
+                return Ok(false);
+            }
+
+            match conn.execute(
+                "UPDATE lunar_buffxnte_psu.users SET two_factor_last_step = $1 WHERE id = $2;",
+                &[&step, &user_id],
+            ) {
+                Ok(_data) => return Ok(true),
+                Err(err) => {
+                    println!("SQL ERROR: {}", err);
+                    return Err(String::from("ERR_INTERNAL_ERR"));
+                }
+            };
+        }
+    }
+
+    use_recovery_code(user_id, code, conn)
+}
+
+fn use_recovery_code(user_id: &String, code: &String, conn: &MainPGDatabase) -> Result<bool, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT id, code_hash FROM lunar_buffxnte_psu.two_factor_recovery_codes WHERE user_id = $1 AND used_at IS NULL;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let normalised = code.trim().to_lowercase();
+
+    for row in &rows_recieved {
+        let code_hash: String = row.get("code_hash");
+
+        if verify(&normalised, &code_hash).unwrap_or(false) {
+            let id: i32 = row.get("id");
+
+            match conn.execute(
+                "UPDATE lunar_buffxnte_psu.two_factor_recovery_codes SET used_at = now() WHERE id = $1 AND used_at IS NULL;",
+                &[&id],
+            ) {
+                Ok(1) => return Ok(true),
+                Ok(_data) => return Ok(false),
+                Err(err) => {
+                    println!("SQL ERROR: {}", err);
+                    return Err(String::from("ERR_INTERNAL_ERR"));
+                }
+            };
+        }
+    }
+
+    Ok(false)
+}
+
+// Replaces any existing recovery codes and returns the new ones in plaintext. This is the
+// only time they are ever visible.
+pub fn generate_recovery_codes(user_id: &String, conn: &MainPGDatabase) -> Result<Vec<String>, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.two_factor_recovery_codes WHERE user_id = $1;",
+        &[&user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let alphabet: [char; 32] = [
+        'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't', 'u',
+        'v', 'w', 'x', 'y', 'z', '2', '3', '4', '5', '6', '7', '8', '9', '0',
+    ];
+
+    let mut codes: Vec<String> = Default::default();
+
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = format!("{}-{}", nanoid!(5, &alphabet), nanoid!(5, &alphabet));
+
+        let code_hash = match hash(&code, 10) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("BCRYPT ERROR: {}", err);
+                return Err(String::from("ERR_INTERNAL_ERR"));
+            }
+        };
+
+        match conn.execute(
+            "INSERT INTO lunar_buffxnte_psu.two_factor_recovery_codes(user_id, code_hash) VALUES ($1, $2);",
+            &[&user_id, &code_hash],
+        ) {
+            Ok(_data) => (),
+            Err(err) => {
+                println!("SQL ERROR: {}", err);
+                return Err(String::from("ERR_INTERNAL_ERR"));
+            }
+        };
+
+        codes.push(code);
+    }
+
+    Ok(codes)
+}
+
+// Step one of enrolment. Stores a pending secret and returns it with its otpauth URI so
+// the dashboard can render a QR code. 2FA isn't active until `confirm_enrolment`.
+pub fn begin_enrolment(user_id: &String, conn: &MainPGDatabase) -> Result<(String, String), String> {
+    let user = account_services::get_user(user_id, conn)?;
+
+    if is_enabled(&user) {
+        return Err(String::from("ERR_2FA_ALREADY_ENABLED"));
+    }
+
+    let secret = generate_secret();
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET two_factor_secret = $1, two_factor_last_step = NULL WHERE id = $2;",
+        &[&secret, &user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let account_name = user.username.or(user.email).unwrap_or(user.id);
+    let uri = otpauth_uri(&secret, &account_name);
+
+    Ok((secret, uri))
+}
+
+pub fn confirm_enrolment(
+    user_id: &String,
+    code: &String,
+    conn: &MainPGDatabase,
+) -> Result<Vec<String>, String> {
+    let user = account_services::get_user(user_id, conn)?;
+
+    if is_enabled(&user) {
+        return Err(String::from("ERR_2FA_ALREADY_ENABLED"));
+    }
+
+    let secret = match user.two_factor_secret {
+        Some(data) => data,
+        None => return Err(String::from("ERR_2FA_NOT_ENROLLING")),
+    };
+
+    let step = match verify_totp(&secret, code, sessions::now_secs()) {
+        Some(data) => data,
+        None => return Err(String::from("ERR_INVALID_2FA_CODE")),
+    };
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET two_factor_options = 'totp', two_factor_last_step = $1 WHERE id = $2;",
+        &[&step, &user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    generate_recovery_codes(user_id, conn)
+}
+
+pub fn disable(
+    user_id: &String,
+    password: &String,
+    code: &String,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    let user = account_services::get_user(user_id, conn)?;
+
+    if !is_enabled(&user) {
+        return Err(String::from("ERR_2FA_NOT_ENABLED"));
+    }
+
+    let password_hash = user.password.unwrap_or_default();
+
+    if !verify(password, &password_hash).unwrap_or(false) {
+        return Err(String::from("ERR_INVALID_CRED"));
+    }
+
+    if !verify_second_factor(user_id, code, conn)? {
+        return Err(String::from("ERR_INVALID_2FA_CODE"));
+    }
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.users SET two_factor_options = NULL, two_factor_secret = NULL, two_factor_last_step = NULL WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.two_factor_recovery_codes WHERE user_id = $1;",
+        &[&user_id],
+    ) {
+        Ok(_data) => Ok(String::from("SUCCESS")),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+pub fn create_challenge(user_id: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let id = nanoid!(40);
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.two_factor_challenges(id, user_id, created_at) VALUES ($1, $2, $3);",
+        &[&id, &user_id, &sessions::now_secs()],
+    ) {
+        Ok(_data) => Ok(id),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("Something went wrong creating the session"))
+        }
+    }
+}
+
+// Exchanges a login challenge plus a valid code for a real session. The challenge is
+// consumed even when the code is wrong, so each password check buys exactly one guess.
+pub fn complete_challenge(
+    challenge: &String,
+    code: &String,
+    ip: String,
+    user_agent: String,
+    conn: MainPGDatabase,
+) -> Result<String, String> {
+    let rows_recieved: Rows = match conn.query(
+        "DELETE FROM lunar_buffxnte_psu.two_factor_challenges WHERE id = $1 AND created_at > $2 RETURNING user_id;",
+        &[&challenge, &(sessions::now_secs() - CHALLENGE_LIFETIME_SECS)],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_INVALID_CHALLENGE"));
+    }
+
+    let user_id: String = rows_recieved.get(0).get("user_id");
+    let context = AuditContext::new(&ip, &user_agent);
+
+    // Wrong codes count towards the same lockout as wrong passwords, which also covers
+    // challenges handed out before the account was locked.
+    login_protection::check_attempt(Some(&user_id), &user_id, &ip, &conn)?;
+
+    if !verify_second_factor(&user_id, code, &conn)? {
+        let user = account_services::get_user(&user_id, &conn)?;
+        let owner = user
+            .email
+            .clone()
+            .map(|email| (email, user.username.clone().unwrap_or_default()));
+
+        login_protection::record_failure(Some(&user_id), &user_id, &ip, owner, &conn);
+        audit_log::record(
+            Some(&user_id),
+            None,
+            "login.failure",
+            serde_json::json!({"reason": "invalid_2fa_code"}),
+            &context,
+            &conn,
+        );
+        return Err(String::from("ERR_INVALID_2FA_CODE"));
+    }
+
+    // The challenge may have been started before the owner reported the sign-in.
+    login_alerts::require_no_reset(&user_id, "password+2fa", &context, &conn)?;
+
+    login_protection::record_success(&user_id, &conn);
+
+    audit_log::record(
+        Some(&user_id),
+        None,
+        "login.success",
+        serde_json::json!({"method": "password+2fa"}),
+        &context,
+        &conn,
+    );
+
+    account_services::create_session(user_id, ip, user_agent, conn)
+}
+
+pub const PURGE_JOB: scheduler::Job = scheduler::Job {
+    name: "TWO_FACTOR_PURGE",
+    counts: "expired 2FA challenges deleted",
+    default_interval_secs: 60 * 60,
+    run: purge_expired_challenges,
+};
+
+pub fn purge_expired_challenges(conn: &Connection) -> Result<u64, String> {
+    match conn.execute(
+        "DELETE FROM lunar_buffxnte_psu.two_factor_challenges WHERE created_at < $1;",
+        &[&(sessions::now_secs() - CHALLENGE_LIFETIME_SECS)],
+    ) {
+        Ok(count) => Ok(count),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
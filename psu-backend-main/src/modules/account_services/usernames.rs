@@ -0,0 +1,320 @@
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::config;
+use crate::MainPGDatabase;
+
+use lazy_static::lazy_static;
+use postgres::rows::Rows;
+use postgres::transaction::Transaction;
+use regex::Regex;
+use serde::Serialize;
+
+pub const MIN_LENGTH: usize = 3;
+pub const MAX_LENGTH: usize = 20;
+
+// Compared by canonical form, so lookalikes such as "adm1n" or "Supp0rt" are covered too.
+const RESERVED: [&str; 24] = [
+    "admin",
+    "administrator",
+    "api",
+    "billing",
+    "discord",
+    "help",
+    "info",
+    "login",
+    "logout",
+    "me",
+    "mod",
+    "moderator",
+    "null",
+    "owner",
+    "psu",
+    "register",
+    "root",
+    "security",
+    "settings",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+    "user",
+];
+
+#[derive(Debug, Serialize)]
+pub struct PublicProfile {
+    pub username: String,
+    pub avatar: Option<String>,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+pub enum Lookup {
+    Found(PublicProfile),
+    // The name was given up recently, holds the name the account uses now.
+    Renamed(String),
+    NotFound,
+}
+
+// How long someone has to wait between renames.
+fn rename_cooldown_days() -> i64 {
+    config::env_or("USERNAME_CHANGE_COOLDOWN_DAYS", 30)
+}
+
+// How long an old name keeps redirecting, during which nobody else can take it.
+fn history_days() -> i64 {
+    config::env_or("USERNAME_HISTORY_DAYS", 90)
+}
+
+// Lowercases and folds characters that are easy to mistake for one another, so names that
+// only differ by those count as the same name. Only ASCII gets this far, which already
+// rules out homoglyphs from other scripts.
+pub fn canonical(username: &str) -> String {
+    username
+        .to_lowercase()
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '5' => 's',
+            _ => c,
+        })
+        .filter(|c| *c != '_')
+        .collect()
+}
+
+pub fn validate(username: &String) -> Result<(), String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r#"^[A-Za-z0-9](?:[A-Za-z0-9_]*[A-Za-z0-9])?$"#).unwrap();
+    }
+
+    if username.len() < MIN_LENGTH || username.len() > MAX_LENGTH {
+        return Err(format!(
+            "Username must be between {} and {} characters",
+            MIN_LENGTH, MAX_LENGTH
+        ));
+    }
+
+    // No '@' also means a username can never be mistaken for an email at login.
+    if !RE.is_match(username) {
+        return Err(String::from(
+            "Username can only contain letters, numbers and underscores, and can't start or end with an underscore",
+        ));
+    }
+
+    let canonical_name = canonical(username);
+
+    if RESERVED.iter().any(|reserved| canonical(reserved) == canonical_name) {
+        return Err(String::from("That username is reserved"));
+    }
+
+    Ok(())
+}
+
+// Anyone other than $2 holding the canonical name $1, either now or as a recent rename.
+const TAKEN_QUERY: &str = r#"SELECT 1 FROM lunar_buffxnte_psu.users WHERE username_canonical = $1 AND id <> $2
+        UNION ALL
+        SELECT 1 FROM lunar_buffxnte_psu.username_history WHERE username_canonical = $1 AND user_id <> $2 AND expires_at > now()
+        LIMIT 1;"#;
+
+// `user_id` lets an account keep or go back to a name it already holds.
+pub fn is_available(username: &String, user_id: Option<&String>, conn: &MainPGDatabase) -> Result<bool, String> {
+    let owner = user_id.cloned().unwrap_or_default();
+
+    let rows_recieved: Rows = match conn.query(TAKEN_QUERY, &[&canonical(username), &owner]) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    Ok(rows_recieved.is_empty())
+}
+
+pub fn check_new_username(username: &String, user_id: Option<&String>, conn: &MainPGDatabase) -> Result<(), String> {
+    validate(username)?;
+
+    if !is_available(username, user_id, conn)? {
+        return Err(String::from("Username is already taken."));
+    }
+
+    Ok(())
+}
+
+// Old accounts may already share a canonical name, so there is no unique index to fall back
+// on. Instead whoever saves a name takes a lock on its canonical form for the rest of their
+// transaction and checks again, so two signups or renames racing for one name take turns.
+pub fn claim(username: &String, user_id: &String, transaction: &Transaction) -> Result<(), String> {
+    match transaction.execute(
+        "SELECT pg_advisory_xact_lock(hashtext($1));",
+        &[&canonical(username)],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let rows_recieved: Rows = match transaction.query(TAKEN_QUERY, &[&canonical(username), &user_id]) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.len() > 0 {
+        return Err(String::from("Username is already taken."));
+    }
+
+    Ok(())
+}
+
+pub fn rename(
+    user_id: &String,
+    new_username: &String,
+    context: &AuditContext,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT username, username_changed_at FROM lunar_buffxnte_psu.users WHERE id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_USER_NOT_FOUND"));
+    }
+
+    let old_username: Option<String> = rows_recieved.get(0).get("username");
+    let changed_at: Option<chrono::DateTime<chrono::Utc>> = rows_recieved.get(0).get("username_changed_at");
+
+    if old_username.as_ref() == Some(new_username) {
+        return Err(String::from("That is already your username"));
+    }
+
+    if let Some(changed_at) = changed_at {
+        if chrono::Utc::now() - changed_at < chrono::Duration::days(rename_cooldown_days()) {
+            return Err(String::from("ERR_USERNAME_CHANGE_COOLDOWN"));
+        }
+    }
+
+    check_new_username(new_username, Some(user_id), conn)?;
+
+    let transaction = match conn.transaction() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    claim(new_username, user_id, &transaction)?;
+
+    // Taking back one of your own old names shouldn't leave it redirecting to itself.
+    match transaction.execute(
+        "DELETE FROM lunar_buffxnte_psu.username_history WHERE user_id = $1 AND username_canonical = $2;",
+        &[&user_id, &canonical(new_username)],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if let Some(old_username) = &old_username {
+        match transaction.execute(
+            r#"INSERT INTO lunar_buffxnte_psu.username_history(user_id, username, username_canonical, changed_at, expires_at)
+            VALUES ($1, $2, $3, now(), now() + ($4 || ' days')::INTERVAL);"#,
+            &[&user_id, &old_username, &canonical(old_username), &history_days().to_string()],
+        ) {
+            Ok(_data) => (),
+            Err(err) => {
+                println!("SQL ERROR: {}", err);
+                return Err(String::from("ERR_INTERNAL_ERR"));
+            }
+        };
+    }
+
+    match transaction.execute(
+        "UPDATE lunar_buffxnte_psu.users SET username = $1, username_canonical = $2, username_changed_at = now(), updated_at = now() WHERE id = $3;",
+        &[&new_username, &canonical(new_username), &user_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    match transaction.commit() {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    audit_log::record(
+        Some(user_id),
+        None,
+        "username.change",
+        serde_json::json!({"from": old_username, "to": new_username}),
+        context,
+        conn,
+    );
+
+    Ok(String::from("SUCCESS"))
+}
+
+pub fn lookup(username: &String, conn: &MainPGDatabase) -> Result<Lookup, String> {
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT username, avatar, created_at FROM lunar_buffxnte_psu.users
+        WHERE LOWER(username) = LOWER($1)
+        ORDER BY (username = $1) DESC, id ASC LIMIT 1;"#,
+        &[&username],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.len() > 0 {
+        let row = rows_recieved.get(0);
+
+        return Ok(Lookup::Found(PublicProfile {
+            username: row.get("username"),
+            avatar: row.get("avatar"),
+            created_at: row.get("created_at"),
+        }));
+    }
+
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT u.username FROM lunar_buffxnte_psu.username_history h
+        JOIN lunar_buffxnte_psu.users u ON u.id = h.user_id
+        WHERE LOWER(h.username) = LOWER($1) AND h.expires_at > now()
+        ORDER BY h.changed_at DESC LIMIT 1;"#,
+        &[&username],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.len() > 0 {
+        return Ok(Lookup::Renamed(rows_recieved.get(0).get("username")));
+    }
+
+    Ok(Lookup::NotFound)
+}
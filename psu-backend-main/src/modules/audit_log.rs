@@ -0,0 +1,123 @@
+use postgres::rows::Rows;
+use postgres::Connection;
+use serde::Serialize;
+use serde_json::Value;
+
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+// Where a request came from. Routes get one from the request guard in routes::guards.
+#[derive(Clone, Debug, Default)]
+pub struct AuditContext {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    // Set when the request came through an admin's impersonation session.
+    pub impersonator_id: Option<String>,
+}
+
+impl AuditContext {
+    pub fn new(ip_address: &String, user_agent: &String) -> Self {
+        AuditContext {
+            ip_address: Some(ip_address.clone()),
+            user_agent: Some(user_agent.clone()),
+            impersonator_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub event: String,
+    pub actor_id: Option<String>,
+    pub details: Value,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Never fails the caller. Losing an audit entry is logged but shouldn't undo the action.
+// Under impersonation the admin becomes the actor (unless one was given) and is also kept in
+// the details, so the entry names both the admin and the user.
+pub fn record(
+    user_id: Option<&String>,
+    actor_id: Option<&String>,
+    event: &str,
+    details: Value,
+    context: &AuditContext,
+    conn: &Connection,
+) {
+    let mut details = details;
+    let actor_id = actor_id.or(context.impersonator_id.as_ref());
+
+    if let (Some(impersonator_id), Value::Object(fields)) = (&context.impersonator_id, &mut details) {
+        fields.insert(String::from("impersonator_id"), Value::String(impersonator_id.clone()));
+    }
+
+    match conn.execute(
+        "INSERT INTO lunar_buffxnte_psu.audit_log(user_id, actor_id, event, details, ip_address, user_agent, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7);",
+        &[
+            &user_id,
+            &actor_id,
+            &event,
+            &details.to_string(),
+            &context.ip_address,
+            &context.user_agent,
+            &chrono::Utc::now(),
+        ],
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("SQL ERROR: Failed to record audit event {}: {}", event, err),
+    };
+}
+
+// Newest first. `page` starts at 1. Returns the page plus the total number of entries.
+pub fn list_events(
+    user_id: &String,
+    page: i64,
+    per_page: i64,
+    conn: &Connection,
+) -> Result<(Vec<AuditEvent>, i64), String> {
+    let per_page = per_page.max(1).min(MAX_PAGE_SIZE);
+    let offset = page.max(1).saturating_sub(1).saturating_mul(per_page);
+
+    let rows_recieved: Rows = match conn.query(
+        "SELECT * FROM lunar_buffxnte_psu.audit_log WHERE user_id = $1 ORDER BY created_at DESC, id DESC LIMIT $2 OFFSET $3;",
+        &[&user_id, &per_page, &offset],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let events = rows_recieved
+        .iter()
+        .map(|row| {
+            let details: String = row.get("details");
+
+            AuditEvent {
+                id: row.get("id"),
+                event: row.get("event"),
+                actor_id: row.get("actor_id"),
+                details: serde_json::from_str(&details).unwrap_or(Value::Null),
+                ip_address: row.get("ip_address"),
+                user_agent: row.get("user_agent"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .collect();
+
+    let count_rows: Rows = match conn.query(
+        "SELECT COUNT(*) AS total FROM lunar_buffxnte_psu.audit_log WHERE user_id = $1;",
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    Ok((events, count_rows.get(0).get("total")))
+}
@@ -0,0 +1,149 @@
+use colored::*;
+use lazy_static::lazy_static;
+
+pub trait CaptchaVerifier: Send + Sync {
+    // Ok(false) means the user failed the challenge, Err means we couldn't find out.
+    fn verify(&self, response: &str, remote_ip: Option<&str>) -> Result<bool, String>;
+}
+
+// reCAPTCHA, hCaptcha and Turnstile all speak the same siteverify protocol.
+fn site_verify(
+    url: &str,
+    secret: &str,
+    response: &str,
+    remote_ip: Option<&str>,
+) -> Result<bool, String> {
+    let mut form: Vec<(&str, &str)> = vec![("secret", secret), ("response", response)];
+
+    if let Some(ip) = remote_ip {
+        form.push(("remoteip", ip));
+    }
+
+    let data = ureq::post(url)
+        .timeout_connect(5_000)
+        .timeout_read(5_000)
+        .send_form(&form);
+
+    if let Some(err) = data.synthetic_error() {
+        println!("[{}] Failed to reach {}: {}", "CAPTCHA".red(), url, err);
+        return Err(String::from("ERR_CAPTCHA_UNAVAILABLE"));
+    }
+
+    let json = match data.into_json() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("[{}] Invalid response from {}: {}", "CAPTCHA".red(), url, err);
+            return Err(String::from("ERR_CAPTCHA_UNAVAILABLE"));
+        }
+    };
+
+    match json.get("success").and_then(|success| success.as_bool()) {
+        Some(success) => Ok(success),
+        None => {
+            println!("[{}] Unexpected response from {}: {}", "CAPTCHA".red(), url, json);
+            Err(String::from("ERR_CAPTCHA_UNAVAILABLE"))
+        }
+    }
+}
+
+pub struct ReCaptcha {
+    pub secret: String,
+}
+
+impl CaptchaVerifier for ReCaptcha {
+    fn verify(&self, response: &str, remote_ip: Option<&str>) -> Result<bool, String> {
+        site_verify(
+            "https://www.google.com/recaptcha/api/siteverify",
+            &self.secret,
+            response,
+            remote_ip,
+        )
+    }
+}
+
+pub struct HCaptcha {
+    pub secret: String,
+}
+
+impl CaptchaVerifier for HCaptcha {
+    fn verify(&self, response: &str, remote_ip: Option<&str>) -> Result<bool, String> {
+        site_verify("https://hcaptcha.com/siteverify", &self.secret, response, remote_ip)
+    }
+}
+
+pub struct Turnstile {
+    pub secret: String,
+}
+
+impl CaptchaVerifier for Turnstile {
+    fn verify(&self, response: &str, remote_ip: Option<&str>) -> Result<bool, String> {
+        site_verify(
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            &self.secret,
+            response,
+            remote_ip,
+        )
+    }
+}
+
+// For local development and integration tests only.
+pub struct AlwaysPass;
+
+impl CaptchaVerifier for AlwaysPass {
+    fn verify(&self, _response: &str, _remote_ip: Option<&str>) -> Result<bool, String> {
+        Ok(true)
+    }
+}
+
+pub struct AlwaysFail;
+
+impl CaptchaVerifier for AlwaysFail {
+    fn verify(&self, _response: &str, _remote_ip: Option<&str>) -> Result<bool, String> {
+        Ok(false)
+    }
+}
+
+// Stands in when the provider is misconfigured so requests fail cleanly instead of panicking.
+pub struct Unconfigured(pub String);
+
+impl CaptchaVerifier for Unconfigured {
+    fn verify(&self, _response: &str, _remote_ip: Option<&str>) -> Result<bool, String> {
+        println!("[{}] {}", "CAPTCHA".red(), self.0);
+        Err(String::from("ERR_CAPTCHA_NOT_CONFIGURED"))
+    }
+}
+
+// CAPTCHA_PROVIDER picks the implementation: recaptcha (default), hcaptcha, turnstile,
+// always_pass or always_fail. The real providers read their secret from CAPTCHA_KEY.
+pub fn from_config() -> Box<dyn CaptchaVerifier> {
+    let provider = std::env::var("CAPTCHA_PROVIDER").unwrap_or(String::from("recaptcha"));
+
+    match provider.as_str() {
+        "always_pass" => {
+            println!("[{}] Captcha checks are DISABLED (always_pass)", "CAPTCHA".yellow());
+            return Box::new(AlwaysPass);
+        }
+        "always_fail" => return Box::new(AlwaysFail),
+        _ => (),
+    };
+
+    let secret = match std::env::var("CAPTCHA_KEY") {
+        Ok(data) => data,
+        Err(_err) => return Box::new(Unconfigured(String::from("CAPTCHA_KEY is not set"))),
+    };
+
+    match provider.as_str() {
+        "recaptcha" => Box::new(ReCaptcha { secret }),
+        "hcaptcha" => Box::new(HCaptcha { secret }),
+        "turnstile" => Box::new(Turnstile { secret }),
+        other => Box::new(Unconfigured(format!("Unknown CAPTCHA_PROVIDER '{}'", other))),
+    }
+}
+
+lazy_static! {
+    static ref VERIFIER: Box<dyn CaptchaVerifier> = from_config();
+}
+
+pub fn verify(response: &str, remote_ip: Option<&str>) -> Result<bool, String> {
+    VERIFIER.verify(response, remote_ip)
+}
@@ -0,0 +1,16 @@
+use std::str::FromStr;
+
+// Reads an optional setting from the environment, falling back to `default` when it is
+// missing or can't be parsed. Required settings should keep using `std::env::var(..).unwrap()`.
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => match value.parse::<T>() {
+            Ok(parsed) => parsed,
+            Err(_err) => {
+                println!("CONFIG: {} has an invalid value, using the default", key);
+                default
+            }
+        },
+        Err(_err) => default,
+    }
+}
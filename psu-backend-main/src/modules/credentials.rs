@@ -0,0 +1,70 @@
+use nanoid::nanoid;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+// No '_' or '-' so the parts of a `psu_<prefix>_<secret>` key can be split unambiguously.
+const KEY_ALPHABET: [char; 62] = [
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i',
+    'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', 'A', 'B',
+    'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U',
+    'V', 'W', 'X', 'Y', 'Z',
+];
+
+pub const KEY_PREFIX_LEN: usize = 12;
+const KEY_SECRET_LEN: usize = 40;
+
+// Hex encoded SHA-256. Every credential we hand out has enough entropy that a plain hash is
+// enough, the point is only that a copy of the database can't be used to log in.
+pub fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && bool::from(a.as_bytes().ct_eq(b.as_bytes()))
+}
+
+pub fn verify_secret(secret: &str, stored_hash: &str) -> bool {
+    constant_time_eq(&hash_secret(secret), stored_hash)
+}
+
+pub struct IssuedKey {
+    // The full `psu_<prefix>_<secret>` string. Shown to the user once and never stored.
+    pub key: String,
+    pub prefix: String,
+    pub hash: String,
+}
+
+pub fn issue_api_key() -> IssuedKey {
+    let prefix = nanoid!(KEY_PREFIX_LEN, &KEY_ALPHABET);
+    let secret = nanoid!(KEY_SECRET_LEN, &KEY_ALPHABET);
+
+    IssuedKey {
+        key: format!("psu_{}_{}", prefix, secret),
+        hash: hash_secret(&secret),
+        prefix: prefix,
+    }
+}
+
+// Splits a presented key into the lookup prefix and the part that gets hashed. Keys from
+// before the `psu_` format were migrated with their first KEY_PREFIX_LEN characters as the
+// prefix and the whole key hashed.
+pub fn split_api_key(key: &str) -> Option<(&str, &str)> {
+    if let Some(rest) = key.strip_prefix("psu_") {
+        let mut parts = rest.splitn(2, '_');
+
+        if let (Some(prefix), Some(secret)) = (parts.next(), parts.next()) {
+            if prefix.len() == KEY_PREFIX_LEN && !secret.is_empty() {
+                return Some((prefix, secret));
+            }
+        }
+    }
+
+    if key.len() > KEY_PREFIX_LEN && key.is_char_boundary(KEY_PREFIX_LEN) {
+        return Some((&key[..KEY_PREFIX_LEN], key));
+    }
+
+    None
+}
@@ -0,0 +1,618 @@
+use crate::modules::config;
+
+use lazy_static::lazy_static;
+use postgres::rows::Rows;
+use postgres::{Connection, TlsMode};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use colored::*;
+
+lazy_static! {
+    // Users whose premium state just changed, synced by the role sync thread as soon as it can.
+    static ref QUEUE: (Mutex<Sender<String>>, Mutex<Receiver<String>>) = {
+        let (sender, receiver) = mpsc::channel();
+        (Mutex::new(sender), Mutex::new(receiver))
+    };
+}
+
+// Retries on a 429 are only worth it for short waits, anything longer is left to the next sweep.
+const MAX_ATTEMPTS: u32 = 3;
+const MAX_RETRY_WAIT_SECS: f64 = 30.0;
+const SWEEP_BATCH_SIZE: i64 = 500;
+// Users who left the guild can't take the role, so the sweep only checks on them this often.
+const NOT_IN_GUILD_RECHECK_HOURS: i64 = 24;
+
+// Point DISCORD_API_BASE at a local fake to exercise the sync and OAuth without touching Discord.
+pub fn api_base() -> String {
+    config::env_or("DISCORD_API_BASE", String::from("https://discord.com/api"))
+}
+
+pub fn guild_id() -> String {
+    config::env_or("DISCORD_GUILD_ID", String::from("781613878407725077"))
+}
+
+// Given to everyone with a linked account.
+pub fn member_role_id() -> String {
+    config::env_or("DISCORD_MEMBER_ROLE_ID", String::from("781621726123393056"))
+}
+
+pub fn premium_role_id() -> String {
+    config::env_or("DISCORD_PREMIUM_ROLE_ID", String::from("781621719869685780"))
+}
+
+// Discord sends the wait both as a header and in the body, in seconds with a fraction.
+fn retry_after(response: ureq::Response) -> f64 {
+    if let Some(secs) = response
+        .header("Retry-After")
+        .and_then(|value| value.parse::<f64>().ok())
+    {
+        return secs;
+    }
+
+    match response.into_json() {
+        Ok(body) => body["retry_after"].as_f64().unwrap_or(1.0),
+        Err(_err) => 1.0,
+    }
+}
+
+fn send(method: &str, path: &String, body: Option<serde_json::Value>) -> Result<ureq::Response, String> {
+    for _attempt in 0..MAX_ATTEMPTS {
+        let mut request = ureq::request(method, &format!("{}{}", api_base(), path));
+        request
+            .set("Authorization", &format!("Bot {}", std::env::var("DISCORD_BOTTOKEN").unwrap()))
+            .set("X-Audit-Log-Reason", "PSU premium sync")
+            .timeout_connect(5_000)
+            .timeout_read(10_000);
+
+        let response = match &body {
+            Some(data) => request.send_json(data.clone()),
+            None => request.call(),
+        };
+
+        if let Some(err) = response.synthetic_error() {
+            println!("DISCORD ERROR: {}", err);
+            return Err(String::from("ERR_DISCORD_UNAVAILABLE"));
+        }
+
+        if response.status() != 429 {
+            // The bucket is empty, so the next call would only get a 429. Waiting here keeps
+            // sweeps from hammering the API.
+            if response.header("X-RateLimit-Remaining") == Some("0") {
+                let reset_after = response
+                    .header("X-RateLimit-Reset-After")
+                    .and_then(|value| value.parse::<f64>().ok())
+                    .unwrap_or(0.0)
+                    .max(0.0)
+                    .min(MAX_RETRY_WAIT_SECS);
+
+                std::thread::sleep(Duration::from_secs_f64(reset_after));
+            }
+
+            return Ok(response);
+        }
+
+        let wait = retry_after(response);
+
+        if wait > MAX_RETRY_WAIT_SECS {
+            return Err(String::from("ERR_DISCORD_RATE_LIMITED"));
+        }
+
+        std::thread::sleep(Duration::from_secs_f64(wait.max(0.0)));
+    }
+
+    Err(String::from("ERR_DISCORD_RATE_LIMITED"))
+}
+
+// Returns false when the user isn't in the guild, in which case there is nothing to change.
+// PUT and DELETE are both no-ops when the member already has (or lacks) the role.
+pub fn set_role(discord_id: &String, role_id: &String, present: bool) -> Result<bool, String> {
+    let path = format!("/guilds/{}/members/{}/roles/{}", guild_id(), discord_id, role_id);
+    let method = if present { "PUT" } else { "DELETE" };
+
+    let response = send(method, &path, None)?;
+
+    match response.status() {
+        200..=299 => Ok(true),
+        404 => Ok(false),
+        status => {
+            println!("DISCORD ERROR: {} {} returned {}", method, path, status);
+            Err(String::from("ERR_DISCORD_REQUEST_FAILED"))
+        }
+    }
+}
+
+// Adds a freshly linked user to the guild. Discord ignores `roles` for people who are
+// already members, so they get the member role separately.
+pub fn join_guild(discord_id: &String, access_token: &String, premium: bool) -> Result<(), String> {
+    let mut roles = vec![member_role_id()];
+
+    if premium {
+        roles.push(premium_role_id());
+    }
+
+    let response = send(
+        "PUT",
+        &format!("/guilds/{}/members/{}", guild_id(), discord_id),
+        Some(serde_json::json!({
+          "access_token": access_token,
+          "roles": roles
+        })),
+    )?;
+
+    match response.status() {
+        201 => Ok(()),
+        204 => set_role(discord_id, &member_role_id(), true).map(|_joined| ()),
+        status => {
+            println!("DISCORD ERROR: guild join returned {}", status);
+            Err(String::from("ERR_DISCORD_REQUEST_FAILED"))
+        }
+    }
+}
+
+// Brings the premium role in line with the user's purchases and records what Discord now
+// holds, so the sweep only revisits users whose state has drifted.
+pub fn sync_user(user_id: &String, conn: &Connection) -> Result<(), String> {
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT discord_id, EXISTS (
+            SELECT 1 FROM lunar_buffxnte_psu.purchases p WHERE p.user_id = u.id AND p.expires_at > now()::date
+        ) AS premium
+        FROM lunar_buffxnte_psu.users u WHERE u.id = $1;"#,
+        &[&user_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.is_empty() {
+        return Err(String::from("ERR_USER_NOT_FOUND"));
+    }
+
+    let discord_id: Option<String> = rows_recieved.get(0).get("discord_id");
+    let premium: bool = rows_recieved.get(0).get("premium");
+
+    let discord_id = match discord_id {
+        Some(data) => data,
+        None => return Ok(()),
+    };
+
+    let in_guild = set_role(&discord_id, &premium_role_id(), premium)?;
+
+    // Members who left the guild are left flagged as not holding the role, so they pick it
+    // up on a later sweep if they come back while still premium.
+    match conn.execute(
+        r#"UPDATE lunar_buffxnte_psu.users SET discord_premium_role = $1, discord_in_guild = $2, discord_synced_at = now()
+        WHERE id = $3 AND discord_id = $4;"#,
+        &[&(premium && in_guild), &in_guild, &user_id, &discord_id],
+    ) {
+        Ok(_data) => Ok(()),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            Err(String::from("ERR_INTERNAL_ERR"))
+        }
+    }
+}
+
+// Used by the role sync thread, where a failure only means the sweep tries again later.
+fn sync_user_quietly(user_id: &String, conn: &Connection) {
+    match sync_user(user_id, conn) {
+        Ok(_data) => (),
+        Err(err) => println!("[{}] Role sync for {} failed: {}", "DISCORD".red(), user_id, err),
+    };
+}
+
+// Hands the user to the role sync thread instead of calling Discord from the request, so
+// purchases and webhooks never wait on (or get rate limited by) Discord. The purchase itself
+// already leaves the user mismatched, so the sweep still catches them if this is lost.
+pub fn queue_sync(user_id: &String) {
+    let sender = QUEUE.0.lock().expect("Discord sync queue mutex poisoned");
+
+    match sender.send(user_id.clone()) {
+        Ok(_data) => (),
+        Err(err) => println!("[{}] Failed to queue role sync for {}: {}", "DISCORD".red(), user_id, err),
+    };
+}
+
+// Takes back the roles PSU handed out when a Discord account is unlinked or deleted.
+pub fn release_user(discord_id: &String) {
+    for role_id in vec![premium_role_id(), member_role_id()] {
+        match set_role(discord_id, &role_id, false) {
+            Ok(_data) => (),
+            Err(err) => println!("[{}] Failed to remove role {}: {}", "DISCORD".red(), role_id, err),
+        };
+    }
+}
+
+// Revisits every linked user whose premium state no longer matches what Discord holds,
+// which covers expired purchases as well as grants whose sync failed at the time. Premium
+// users who left the guild stay mismatched until they come back, so they are only checked
+// once a day and go to the back of the queue, behind whoever was synced longest ago.
+pub fn reconcile(conn: &Connection) -> Result<u64, String> {
+    let rows_recieved: Rows = match conn.query(
+        &format!(
+            r#"SELECT id FROM (
+            SELECT u.id, u.discord_premium_role, u.discord_in_guild, u.discord_synced_at, EXISTS (
+                SELECT 1 FROM lunar_buffxnte_psu.purchases p WHERE p.user_id = u.id AND p.expires_at > now()::date
+            ) AS premium
+            FROM lunar_buffxnte_psu.users u
+            WHERE u.discord_id IS NOT NULL
+        ) linked
+        WHERE linked.premium <> linked.discord_premium_role
+        AND (linked.discord_in_guild OR linked.discord_synced_at IS NULL
+            OR linked.discord_synced_at < now() - INTERVAL '{} HOURS')
+        ORDER BY linked.discord_in_guild DESC, linked.discord_synced_at ASC NULLS FIRST
+        LIMIT $1;"#,
+            NOT_IN_GUILD_RECHECK_HOURS
+        ),
+        &[&SWEEP_BATCH_SIZE],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let mut synced = 0;
+
+    for row in &rows_recieved {
+        let user_id: String = row.get("id");
+
+        match sync_user(&user_id, conn) {
+            Ok(_data) => synced += 1,
+            // No point carrying on until the limit resets, the next sweep continues from here.
+            Err(err) if err == "ERR_DISCORD_RATE_LIMITED" || err == "ERR_DISCORD_UNAVAILABLE" => {
+                return Err(err)
+            }
+            Err(err) => println!("[{}] Role sync for {} failed: {}", "DISCORD".red(), user_id, err),
+        };
+    }
+
+    Ok(synced)
+}
+
+fn connect() -> Option<Connection> {
+    match Connection::connect(std::env::var("DATABASE_URL").unwrap(), TlsMode::None) {
+        Ok(conn) => Some(conn),
+        Err(err) => {
+            println!("[{}] Failed to connect for role sync: {}", "DISCORD".red(), err);
+            None
+        }
+    }
+}
+
+// Syncs queued users as they come in and runs the reconcile sweep in between.
+pub fn spawn_role_sync() {
+    let interval = Duration::from_secs(config::env_or::<u64>("DISCORD_SYNC_INTERVAL_SECS", 15 * 60));
+
+    std::thread::spawn(move || {
+        let queue = QUEUE.1.lock().expect("Discord sync queue mutex poisoned");
+        let mut next_sweep = Instant::now() + interval;
+
+        loop {
+            match queue.recv_timeout(next_sweep.saturating_duration_since(Instant::now())) {
+                Ok(user_id) => {
+                    if let Some(conn) = connect() {
+                        sync_user_quietly(&user_id, &conn);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    next_sweep = Instant::now() + interval;
+
+                    let conn = match connect() {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+
+                    match reconcile(&conn) {
+                        Ok(0) => (),
+                        Ok(count) => println!("[{}] Synced premium role for {} users", "DISCORD".blue(), count),
+                        Err(err) => println!("[{}] Role sync sweep stopped: {}", "DISCORD".red(), err),
+                    };
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::time::{Duration, Instant};
+
+    lazy_static! {
+        // DISCORD_API_BASE is process wide, so tests that point it at a fake take turns.
+        static ref FAKE_API: Mutex<()> = Mutex::new(());
+    }
+
+    struct FakeDiscord {
+        requests: Arc<Mutex<Vec<String>>>,
+        _turn: MutexGuard<'static, ()>,
+    }
+
+    impl FakeDiscord {
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn reply(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n", status, body.len());
+
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        format!("{}\r\n{}", response, body)
+    }
+
+    // Serves DISCORD_API_BASE for the rest of the test. `respond` gets the request number and
+    // "METHOD /path", and every request is recorded in that form.
+    fn fake_discord<F>(respond: F) -> FakeDiscord
+    where
+        F: Fn(usize, &str) -> String + Send + 'static,
+    {
+        let turn = FAKE_API.lock().unwrap_or_else(|err| err.into_inner());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        std::env::set_var("DISCORD_API_BASE", format!("http://{}/api", listener.local_addr().unwrap()));
+        std::env::set_var("DISCORD_BOTTOKEN", "test-token");
+
+        let seen = requests.clone();
+
+        std::thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_err) => return,
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+
+                    if line.trim().is_empty() {
+                        break;
+                    }
+
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(value) = lower.strip_prefix("content-length:") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let request: Vec<&str> = request_line.split_whitespace().take(2).collect();
+                let request = request.join(" ");
+
+                seen.lock().unwrap().push(request.clone());
+                stream.write_all(respond(index, &request).as_bytes()).unwrap();
+            }
+        });
+
+        FakeDiscord {
+            requests,
+            _turn: turn,
+        }
+    }
+
+    fn role_path(method: &str) -> String {
+        format!("{} /api/guilds/{}/members/1234/roles/5678", method, guild_id())
+    }
+
+    #[test]
+    fn set_role_puts_and_deletes() {
+        let fake = fake_discord(|_index, _request| reply("204 No Content", &[], ""));
+
+        assert_eq!(set_role(&String::from("1234"), &String::from("5678"), true), Ok(true));
+        assert_eq!(set_role(&String::from("1234"), &String::from("5678"), false), Ok(true));
+        assert_eq!(fake.requests(), vec![role_path("PUT"), role_path("DELETE")]);
+    }
+
+    #[test]
+    fn set_role_reports_members_outside_the_guild() {
+        let _fake = fake_discord(|_index, _request| {
+            reply("404 Not Found", &[], r#"{"message": "Unknown Member", "code": 10007}"#)
+        });
+
+        assert_eq!(set_role(&String::from("1234"), &String::from("5678"), true), Ok(false));
+    }
+
+    #[test]
+    fn set_role_fails_on_other_errors() {
+        let _fake = fake_discord(|_index, _request| {
+            reply("403 Forbidden", &[], r#"{"message": "Missing Permissions", "code": 50013}"#)
+        });
+
+        assert_eq!(
+            set_role(&String::from("1234"), &String::from("5678"), true),
+            Err(String::from("ERR_DISCORD_REQUEST_FAILED"))
+        );
+    }
+
+    #[test]
+    fn send_retries_after_a_429() {
+        let fake = fake_discord(|index, _request| match index {
+            0 => reply("429 Too Many Requests", &[("Retry-After", "0.2")], r#"{"retry_after": 0.2}"#),
+            _ => reply("204 No Content", &[], ""),
+        });
+
+        let started = Instant::now();
+
+        assert_eq!(set_role(&String::from("1234"), &String::from("5678"), true), Ok(true));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(fake.requests().len(), 2);
+    }
+
+    #[test]
+    fn send_reads_the_wait_from_the_body_without_a_header() {
+        let fake = fake_discord(|index, _request| match index {
+            0 => reply("429 Too Many Requests", &[], r#"{"retry_after": 0.2}"#),
+            _ => reply("204 No Content", &[], ""),
+        });
+
+        let started = Instant::now();
+
+        assert_eq!(set_role(&String::from("1234"), &String::from("5678"), true), Ok(true));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(fake.requests().len(), 2);
+    }
+
+    #[test]
+    fn send_leaves_long_waits_to_the_next_sweep() {
+        let fake = fake_discord(|_index, _request| {
+            reply("429 Too Many Requests", &[("Retry-After", "600")], r#"{"retry_after": 600}"#)
+        });
+
+        let started = Instant::now();
+
+        assert_eq!(
+            set_role(&String::from("1234"), &String::from("5678"), true),
+            Err(String::from("ERR_DISCORD_RATE_LIMITED"))
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(fake.requests().len(), 1);
+    }
+
+    #[test]
+    fn send_gives_up_after_max_attempts() {
+        let fake = fake_discord(|_index, _request| {
+            reply("429 Too Many Requests", &[("Retry-After", "0.01")], r#"{"retry_after": 0.01}"#)
+        });
+
+        assert_eq!(
+            set_role(&String::from("1234"), &String::from("5678"), true),
+            Err(String::from("ERR_DISCORD_RATE_LIMITED"))
+        );
+        assert_eq!(fake.requests().len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn send_waits_out_an_empty_bucket() {
+        let fake = fake_discord(|_index, _request| {
+            reply(
+                "204 No Content",
+                &[("X-RateLimit-Remaining", "0"), ("X-RateLimit-Reset-After", "0.3")],
+                "",
+            )
+        });
+
+        let started = Instant::now();
+
+        assert_eq!(set_role(&String::from("1234"), &String::from("5678"), true), Ok(true));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(fake.requests().len(), 1);
+    }
+
+    #[test]
+    fn send_reports_discord_being_down() {
+        let _fake = fake_discord(|_index, _request| reply("204 No Content", &[], ""));
+
+        // Nothing listens on a port that was just given back.
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        std::env::set_var("DISCORD_API_BASE", format!("http://{}/api", closed));
+
+        assert_eq!(
+            set_role(&String::from("1234"), &String::from("5678"), true),
+            Err(String::from("ERR_DISCORD_UNAVAILABLE"))
+        );
+    }
+
+    fn insert_linked_user(id: &str, discord_id: &str, premium: bool, conn: &Connection) {
+        conn.execute(
+            r#"INSERT INTO lunar_buffxnte_psu.users(
+          id, email, username, password, role_id, last_login, status, remember_token, created_at, updated_at, avatar,
+          email_verified_at, discord_id, discord_username, discord_avatar, discord_premium_role)
+          VALUES ($1, $2, $1, NULL, 1, now(), 'Active', $1, now(), now(), '', now(), $3, $3, '', false);"#,
+            &[&id, &format!("{}@example.com", id), &discord_id],
+        )
+        .unwrap();
+
+        if premium {
+            conn.execute(
+                r#"INSERT INTO lunar_buffxnte_psu.purchases(
+              txn_id, method, user_id, status, amount, active, chargebacked, created_at, expires_at)
+              VALUES ($1, 'test', $1, 1, 0, 1, 0, now(), now() + INTERVAL '1 DAY');"#,
+                &[&id],
+            )
+            .unwrap();
+        }
+    }
+
+    fn remove_user(id: &str, conn: &Connection) {
+        conn.execute("DELETE FROM lunar_buffxnte_psu.purchases WHERE user_id = $1;", &[&id])
+            .unwrap();
+        conn.execute("DELETE FROM lunar_buffxnte_psu.users WHERE id = $1;", &[&id])
+            .unwrap();
+    }
+
+    // Needs a migrated database with no other linked users out of sync:
+    // TEST_DATABASE_URL=postgres://... cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn reconcile_syncs_members_and_parks_users_who_left() {
+        let conn = Connection::connect(std::env::var("TEST_DATABASE_URL").unwrap(), TlsMode::None).unwrap();
+
+        let fake = fake_discord(|_index, request| {
+            if request.contains("/members/left-guild/") {
+                reply("404 Not Found", &[], r#"{"message": "Unknown Member", "code": 10007}"#)
+            } else {
+                reply("204 No Content", &[], "")
+            }
+        });
+
+        insert_linked_user("reconcile-member", "in-guild", true, &conn);
+        insert_linked_user("reconcile-left", "left-guild", true, &conn);
+
+        let first = reconcile(&conn);
+        let requests = fake.requests();
+
+        // Nobody is due again until the one who left has been out of the guild for a day.
+        let second = reconcile(&conn);
+        let second_requests = fake.requests().len() - requests.len();
+
+        let rows_recieved: Rows = conn
+            .query(
+                r#"SELECT id, discord_premium_role, discord_in_guild, discord_synced_at IS NOT NULL AS synced
+                FROM lunar_buffxnte_psu.users WHERE id IN ('reconcile-member', 'reconcile-left') ORDER BY id;"#,
+                &[],
+            )
+            .unwrap();
+        let state: Vec<(String, bool, bool, bool)> = rows_recieved
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+            .collect();
+
+        remove_user("reconcile-member", &conn);
+        remove_user("reconcile-left", &conn);
+
+        assert_eq!(first, Ok(2));
+        assert_eq!(requests.len(), 2);
+        assert_eq!(second, Ok(0));
+        assert_eq!(second_requests, 0);
+        assert_eq!(
+            state,
+            vec![
+                (String::from("reconcile-left"), false, false, true),
+                (String::from("reconcile-member"), true, true, true),
+            ]
+        );
+    }
+}
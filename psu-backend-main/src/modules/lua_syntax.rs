@@ -0,0 +1,250 @@
+use serde::Serialize;
+
+pub mod lexer;
+pub mod parser;
+
+// Where a script stops being valid Lua. Lines and columns count from 1, columns in characters
+// rather than bytes, and the end is just past the offending token, so editors can underline it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub line: u32,
+    pub column: u32,
+    pub end_line: u32,
+    pub end_column: u32,
+    pub message: String,
+}
+
+// Checks that `source` parses as Lua 5.1 with the Luau extensions the obfuscator accepts:
+// compound assignment, `continue`, type annotations, if-then-else expressions, interpolated
+// strings and binary or underscored number literals. Like Lua itself this stops at the first
+// error.
+pub fn check(source: &[u8]) -> Result<(), Diagnostic> {
+    parser::Parser::new(source).parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejects(source: &str) -> (u32, u32, u32, u32, String) {
+        let diagnostic = check(source.as_bytes()).expect_err("should not parse");
+
+        (
+            diagnostic.line,
+            diagnostic.column,
+            diagnostic.end_line,
+            diagnostic.end_column,
+            diagnostic.message,
+        )
+    }
+
+    fn rejected(
+        line: u32,
+        column: u32,
+        end_line: u32,
+        end_column: u32,
+        message: &str,
+    ) -> (u32, u32, u32, u32, String) {
+        (line, column, end_line, end_column, String::from(message))
+    }
+
+    #[test]
+    fn accepts_lua_51() {
+        let source = r##"#!/usr/bin/lua
+local s = [==[
+long ]] string ]==] .. "\65\x41\u{48}\z
+  tail"
+local t = {1, 2; 3, a = 1, ["b"] = 2,}
+function t.a.b:c(...) local a, b = ... return select("#", ...) end
+repeat local x = 1 until x == 1
+while true do break end
+for k, v in pairs(t) do print(k, v) end
+do local f = function(...) return ... end end
+local z = -2 ^ - -3 .. #t
+return
+"##;
+
+        assert!(check(source.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn accepts_luau_extensions() {
+        let source = r#"local function f(a: number, b: string?): (number, ...string)
+  return a
+end
+local x: {[string]: number} = {}
+type Point<T> = {x: T, y: T}
+export type Id = number | string
+local n = 0b1010_1010 + 1_000 + 0xFF
+x += 1; n //= 2; s ..= "x"
+local v = if n > 1 then "a" elseif n < 0 then "b" else "c"
+local msg = `hello {name} you are {age + 1}`
+local y = (x :: any) :: number
+for i = 1, 10 do if i % 2 == 0 then continue end end
+local continue = 1
+local type = 2
+type(x)
+"#;
+
+        assert!(check(source.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn accepts_an_empty_script() {
+        assert!(check(b"").is_ok());
+    }
+
+    #[test]
+    fn accepts_deep_but_reasonable_nesting() {
+        let source = format!("x = {}1{}", "(".repeat(150), ")".repeat(150));
+
+        assert!(check(source.as_bytes()).is_ok());
+    }
+
+    // Unary operators are read in a loop, a long run of them shouldn't count as nesting.
+    #[test]
+    fn accepts_long_unary_chains() {
+        let source = format!("x = {}1", "- ".repeat(100_000));
+
+        assert!(check(source.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn rejects_unclosed_blocks_at_the_end() {
+        assert_eq!(
+            rejects("if x then\n  print(1)\n"),
+            rejected(
+                3,
+                1,
+                3,
+                1,
+                "'end' expected (to close 'if' at line 1) near '<eof>'"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_unclosed_tables_at_the_next_token() {
+        assert_eq!(
+            rejects("local t = {1, 2\nprint(t)\n"),
+            rejected(
+                2,
+                1,
+                2,
+                6,
+                "'}' expected (to close '{' at line 1) near 'print'"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_missing_operands() {
+        assert_eq!(
+            rejects("x = 1 +\n"),
+            rejected(2, 1, 2, 1, "unexpected symbol near '<eof>'")
+        );
+    }
+
+    #[test]
+    fn rejects_assigning_to_a_call() {
+        assert_eq!(
+            rejects("f() = 1\n"),
+            rejected(1, 5, 1, 6, "syntax error near '='")
+        );
+    }
+
+    #[test]
+    fn rejects_ambiguous_calls() {
+        assert_eq!(
+            rejects("local a = f\n(g)()\n"),
+            rejected(
+                2,
+                1,
+                2,
+                2,
+                "ambiguous syntax (function call x new statement) near '('"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_break_and_continue_outside_loops() {
+        assert_eq!(
+            rejects("break\n"),
+            rejected(1, 1, 1, 6, "no loop to break near 'break'")
+        );
+        assert_eq!(
+            rejects("continue\n"),
+            rejected(1, 1, 1, 9, "no loop to continue near 'continue'")
+        );
+    }
+
+    #[test]
+    fn rejects_varargs_outside_vararg_functions() {
+        assert_eq!(
+            rejects("function f()\n  return ...\nend\n"),
+            rejected(
+                2,
+                10,
+                2,
+                13,
+                "cannot use '...' outside a vararg function near '...'"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_excessive_nesting() {
+        let source = format!("x = {}1{}", "(".repeat(250), ")".repeat(250));
+
+        assert_eq!(
+            rejects(&source),
+            rejected(1, 204, 1, 205, "chunk has too many syntax levels near '('")
+        );
+    }
+
+    #[test]
+    fn reports_lexer_errors() {
+        assert_eq!(
+            rejects("local s = \"abc\nprint(s)\n"),
+            rejected(1, 11, 1, 15, "unfinished string near '\"abc'")
+        );
+        assert_eq!(
+            rejects("--[[ never closed\nprint(1)\n"),
+            rejected(
+                1,
+                1,
+                3,
+                1,
+                "unfinished long comment near '--[[ never closed\nprint(1)\n'"
+            )
+        );
+        assert_eq!(
+            rejects("x = 0x\n"),
+            rejected(1, 5, 1, 7, "malformed number near '0x'")
+        );
+        assert_eq!(
+            rejects("x = 1 @ 2\n"),
+            rejected(1, 7, 1, 8, "unexpected symbol near '@'")
+        );
+        assert_eq!(
+            rejects("local x = `a{{b}}`\n"),
+            rejected(
+                1,
+                11,
+                1,
+                14,
+                "double braces are not permitted within interpolated strings, use '\\{' for a literal brace near '`a{'"
+            )
+        );
+    }
+
+    // Editors count characters, so a multi-byte character before the error is one column.
+    #[test]
+    fn counts_columns_in_characters() {
+        assert_eq!(
+            rejects("local s = \"caf\u{e9}\" @\n"),
+            rejected(1, 18, 1, 19, "unexpected symbol near '@'")
+        );
+    }
+}
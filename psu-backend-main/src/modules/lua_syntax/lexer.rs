@@ -0,0 +1,694 @@
+use crate::modules::lua_syntax::Diagnostic;
+
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Name,
+    Keyword,
+    Number,
+    String,
+    // `text` with no expressions in it.
+    InterpSimple,
+    // `text{, }text{ and }text` around the expressions of an interpolated string.
+    InterpBegin,
+    InterpMid,
+    InterpEnd,
+    Symbol,
+    Eof,
+    // Where lexing failed. Always the last token, the parser reports `Lexed::error` when it
+    // gets here.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+    pub end_line: u32,
+    pub end_column: u32,
+}
+
+pub struct Lexed {
+    pub tokens: Vec<Token>,
+    pub error: Option<Diagnostic>,
+}
+
+// Luau keeps Lua 5.1's reserved words. `continue`, `type` and `export` only mean something at
+// the start of a statement, so they stay plain names here.
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in", "local",
+    "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// Longest first, so `..=` isn't read as `..` followed by `=`.
+const SYMBOLS: &[&str] = &[
+    "...", "..=", "//=", "..", "::", "->", "//", "==", "~=", "<=", ">=", "+=", "-=", "*=", "/=",
+    "%=", "^=", "+", "-", "*", "/", "%", "^", "#", "<", ">", "=", "(", ")", "{", "}", "[", "]",
+    ";", ":", ",", ".", "&", "|", "?",
+];
+
+pub struct Lexer<'a> {
+    source: &'a [u8],
+    position: usize,
+    line: u32,
+    column: u32,
+    // One entry per open `{`, true when it opened an expression inside an interpolated string
+    // and the matching `}` carries on with the string.
+    braces: Vec<bool>,
+}
+
+pub fn text<'a>(source: &'a [u8], token: &Token) -> Cow<'a, str> {
+    String::from_utf8_lossy(&source[token.start..token.end])
+}
+
+pub fn tokenize(source: &[u8]) -> Lexed {
+    Lexer::new(source).run()
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a [u8]) -> Self {
+        Lexer {
+            source,
+            position: 0,
+            line: 1,
+            column: 1,
+            braces: Default::default(),
+        }
+    }
+
+    pub fn run(mut self) -> Lexed {
+        let mut tokens: Vec<Token> = Default::default();
+
+        if self.source.starts_with(b"\xEF\xBB\xBF") {
+            self.position = 3;
+        }
+
+        // A `#!` line at the top is skipped, same as the standalone interpreter does.
+        if self.peek(0) == Some(b'#') {
+            while !matches!(self.peek(0), None | Some(b'\n') | Some(b'\r')) {
+                self.bump();
+            }
+        }
+
+        loop {
+            let (start, line, column) = (self.position, self.line, self.column);
+
+            let result = match self.skip_trivia() {
+                Ok(_data) => {
+                    let (start, line, column) = (self.position, self.line, self.column);
+
+                    match self.token() {
+                        Ok(kind) => Ok(Token {
+                            kind,
+                            start,
+                            end: self.position,
+                            line,
+                            column,
+                            end_line: self.line,
+                            end_column: self.column,
+                        }),
+                        Err(message) => Err(self.diagnostic(message, start, line, column)),
+                    }
+                }
+                Err(message) => Err(self.diagnostic(message, start, line, column)),
+            };
+
+            match result {
+                Ok(token) => {
+                    tokens.push(token);
+
+                    if token.kind == TokenKind::Eof {
+                        return Lexed {
+                            tokens,
+                            error: None,
+                        };
+                    }
+                }
+                Err(error) => {
+                    tokens.push(Token {
+                        kind: TokenKind::Error,
+                        start: self.position,
+                        end: self.position,
+                        line: error.line,
+                        column: error.column,
+                        end_line: error.end_line,
+                        end_column: error.end_column,
+                    });
+
+                    return Lexed {
+                        tokens,
+                        error: Some(error),
+                    };
+                }
+            }
+        }
+    }
+
+    fn diagnostic(&self, message: String, start: usize, line: u32, column: u32) -> Diagnostic {
+        let near = String::from_utf8_lossy(&self.source[start..self.position]);
+        let near: String = near.chars().take(40).collect();
+
+        Diagnostic {
+            line,
+            column,
+            end_line: self.line,
+            end_column: self.column,
+            message: if near.is_empty() {
+                message
+            } else {
+                format!("{} near '{}'", message, near)
+            },
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.get(self.position + offset).copied()
+    }
+
+    fn bump(&mut self) {
+        let byte = self.source[self.position];
+        self.position += 1;
+
+        if byte == b'\n' || byte == b'\r' {
+            // \r\n and \n\r count as a single line break, same as Lua.
+            if let Some(next) = self.peek(0) {
+                if (next == b'\n' || next == b'\r') && next != byte {
+                    self.position += 1;
+                }
+            }
+
+            self.line += 1;
+            self.column = 1;
+        } else if byte & 0xC0 != 0x80 {
+            // Continuation bytes of a UTF-8 character don't start a new column.
+            self.column += 1;
+        }
+    }
+
+    fn skip_trivia(&mut self) -> Result<(), String> {
+        loop {
+            match self.peek(0) {
+                Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') | Some(0x0B) | Some(0x0C) => {
+                    self.bump()
+                }
+                Some(b'-') if self.peek(1) == Some(b'-') => {
+                    self.bump();
+                    self.bump();
+
+                    if let Some(level) = self.long_bracket_level() {
+                        self.long_bracket(level, "comment")?;
+                        continue;
+                    }
+
+                    while !matches!(self.peek(0), None | Some(b'\n') | Some(b'\r')) {
+                        self.bump();
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<TokenKind, String> {
+        let byte = match self.peek(0) {
+            Some(data) => data,
+            None => return Ok(TokenKind::Eof),
+        };
+
+        if byte.is_ascii_alphabetic() || byte == b'_' {
+            let start = self.position;
+
+            while matches!(self.peek(0), Some(next) if next.is_ascii_alphanumeric() || next == b'_')
+            {
+                self.bump();
+            }
+
+            let word = &self.source[start..self.position];
+
+            return Ok(
+                if KEYWORDS.iter().any(|keyword| keyword.as_bytes() == word) {
+                    TokenKind::Keyword
+                } else {
+                    TokenKind::Name
+                },
+            );
+        }
+
+        if byte.is_ascii_digit()
+            || (byte == b'.' && matches!(self.peek(1), Some(next) if next.is_ascii_digit()))
+        {
+            return self.number();
+        }
+
+        match byte {
+            b'"' | b'\'' => return self.short_string(byte),
+            b'`' => {
+                self.bump();
+                return self.interpolated_segment(true);
+            }
+            b'[' if matches!(self.peek(1), Some(b'[') | Some(b'=')) => {
+                return match self.long_bracket_level() {
+                    Some(level) => {
+                        self.long_bracket(level, "string")?;
+                        Ok(TokenKind::String)
+                    }
+                    None => {
+                        self.bump();
+                        Err(String::from("invalid long string delimiter"))
+                    }
+                };
+            }
+            b'{' => self.braces.push(false),
+            b'}' => {
+                if self.braces.pop() == Some(true) {
+                    self.bump();
+                    return self.interpolated_segment(false);
+                }
+            }
+            _ => (),
+        }
+
+        let rest = &self.source[self.position..];
+
+        match SYMBOLS
+            .iter()
+            .find(|symbol| rest.starts_with(symbol.as_bytes()))
+        {
+            Some(symbol) => {
+                for _ in 0..symbol.len() {
+                    self.bump();
+                }
+
+                Ok(TokenKind::Symbol)
+            }
+            None => {
+                self.bump();
+
+                // Swallow the rest of a multi-byte character so it shows up whole in the message.
+                while matches!(self.peek(0), Some(next) if next & 0xC0 == 0x80) {
+                    self.bump();
+                }
+
+                Err(String::from("unexpected symbol"))
+            }
+        }
+    }
+
+    // Reads anything that could belong to the number and then checks it, so `3x` is one bad
+    // number rather than a number followed by a name. Luau allows `0b` literals and `_` between
+    // digits.
+    fn number(&mut self) -> Result<TokenKind, String> {
+        let start = self.position;
+        let hex = matches!(self.peek(1), Some(b'x') | Some(b'X')) && self.peek(0) == Some(b'0');
+
+        loop {
+            match self.peek(0) {
+                Some(b'e') | Some(b'E') if !hex => {
+                    self.bump();
+
+                    if matches!(self.peek(0), Some(b'+') | Some(b'-')) {
+                        self.bump();
+                    }
+                }
+                Some(next) if next.is_ascii_alphanumeric() || next == b'_' || next == b'.' => {
+                    self.bump()
+                }
+                _ => break,
+            }
+        }
+
+        let literal: String = String::from_utf8_lossy(&self.source[start..self.position])
+            .chars()
+            .filter(|character| *character != '_')
+            .collect();
+
+        let valid = if literal.len() > 2 && (literal.starts_with("0x") || literal.starts_with("0X"))
+        {
+            literal[2..]
+                .chars()
+                .all(|character| character.is_ascii_hexdigit())
+        } else if literal.len() > 2 && (literal.starts_with("0b") || literal.starts_with("0B")) {
+            literal[2..]
+                .chars()
+                .all(|character| character == '0' || character == '1')
+        } else {
+            literal.parse::<f64>().is_ok()
+        };
+
+        if !valid {
+            return Err(String::from("malformed number"));
+        }
+
+        Ok(TokenKind::Number)
+    }
+
+    fn short_string(&mut self, quote: u8) -> Result<TokenKind, String> {
+        self.bump();
+
+        loop {
+            match self.peek(0) {
+                None | Some(b'\n') | Some(b'\r') => return Err(String::from("unfinished string")),
+                Some(b'\\') => {
+                    self.bump();
+                    self.escape()?;
+                }
+                Some(next) if next == quote => {
+                    self.bump();
+                    return Ok(TokenKind::String);
+                }
+                Some(_next) => self.bump(),
+            }
+        }
+    }
+
+    // Starts just after the opening backtick or the `}` closing an expression.
+    fn interpolated_segment(&mut self, first: bool) -> Result<TokenKind, String> {
+        loop {
+            match self.peek(0) {
+                None | Some(b'\n') | Some(b'\r') => {
+                    return Err(String::from("unfinished interpolated string"))
+                }
+                Some(b'\\') => {
+                    self.bump();
+                    self.escape()?;
+                }
+                Some(b'`') => {
+                    self.bump();
+                    return Ok(if first {
+                        TokenKind::InterpSimple
+                    } else {
+                        TokenKind::InterpEnd
+                    });
+                }
+                Some(b'{') => {
+                    self.bump();
+
+                    if self.peek(0) == Some(b'{') {
+                        return Err(String::from(
+                            "double braces are not permitted within interpolated strings, use '\\{' for a literal brace",
+                        ));
+                    }
+
+                    self.braces.push(true);
+                    return Ok(if first {
+                        TokenKind::InterpBegin
+                    } else {
+                        TokenKind::InterpMid
+                    });
+                }
+                Some(_next) => self.bump(),
+            }
+        }
+    }
+
+    // Everything Lua 5.1 accepts after a backslash, plus Luau's `\x`, `\z` and `\u{}`. Any other
+    // character just stands for itself, as it does in 5.1.
+    fn escape(&mut self) -> Result<(), String> {
+        match self.peek(0) {
+            None => Err(String::from("unfinished string")),
+            Some(b'x') => {
+                self.bump();
+
+                for _ in 0..2 {
+                    match self.peek(0) {
+                        Some(next) if next.is_ascii_hexdigit() => self.bump(),
+                        _ => return Err(String::from("hexadecimal digit expected")),
+                    }
+                }
+
+                Ok(())
+            }
+            Some(b'z') => {
+                self.bump();
+
+                while matches!(
+                    self.peek(0),
+                    Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') | Some(0x0B) | Some(0x0C)
+                ) {
+                    self.bump();
+                }
+
+                Ok(())
+            }
+            Some(b'u') => {
+                self.bump();
+
+                if self.peek(0) != Some(b'{') {
+                    return Err(String::from("missing '{' in \\u{xxxx}"));
+                }
+                self.bump();
+
+                let mut value: u32 = 0;
+                let mut digits = 0;
+
+                while let Some(next) = self.peek(0).filter(|next| next.is_ascii_hexdigit()) {
+                    value = value
+                        .saturating_mul(16)
+                        .saturating_add((next as char).to_digit(16).unwrap_or(0));
+                    digits += 1;
+                    self.bump();
+                }
+
+                if digits == 0 {
+                    return Err(String::from("hexadecimal digit expected"));
+                }
+
+                if self.peek(0) != Some(b'}') {
+                    return Err(String::from("missing '}' in \\u{xxxx}"));
+                }
+                self.bump();
+
+                if value > 0x10FFFF {
+                    return Err(String::from("UTF-8 value too large"));
+                }
+
+                Ok(())
+            }
+            Some(next) if next.is_ascii_digit() => {
+                let mut value: u32 = 0;
+
+                for _ in 0..3 {
+                    match self.peek(0) {
+                        Some(digit) if digit.is_ascii_digit() => {
+                            value = value * 10 + (digit - b'0') as u32;
+                            self.bump();
+                        }
+                        _ => break,
+                    }
+                }
+
+                if value > 255 {
+                    return Err(String::from("escape sequence too large"));
+                }
+
+                Ok(())
+            }
+            // Includes a backslash before a line break, which continues the string on the next line.
+            Some(_next) => {
+                self.bump();
+                Ok(())
+            }
+        }
+    }
+
+    // The number of `=` in a long bracket starting here, or None if this isn't one.
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek(0) != Some(b'[') {
+            return None;
+        }
+
+        let mut level = 0;
+
+        while self.peek(1 + level) == Some(b'=') {
+            level += 1;
+        }
+
+        if self.peek(1 + level) == Some(b'[') {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    fn long_bracket(&mut self, level: usize, what: &str) -> Result<(), String> {
+        for _ in 0..level + 2 {
+            self.bump();
+        }
+
+        loop {
+            match self.peek(0) {
+                None => return Err(format!("unfinished long {}", what)),
+                Some(b']') => {
+                    let closes = (1..=level).all(|offset| self.peek(offset) == Some(b'='))
+                        && self.peek(level + 1) == Some(b']');
+
+                    if closes {
+                        for _ in 0..level + 2 {
+                            self.bump();
+                        }
+
+                        return Ok(());
+                    }
+
+                    self.bump();
+                }
+                Some(_next) => self.bump(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Kind and text of every token up to, but not including, the end of the source.
+    fn tokens(source: &str) -> Vec<(TokenKind, String)> {
+        let lexed = tokenize(source.as_bytes());
+        assert!(lexed.error.is_none(), "unexpected error {:?}", lexed.error);
+
+        lexed
+            .tokens
+            .iter()
+            .filter(|token| token.kind != TokenKind::Eof)
+            .map(|token| (token.kind, text(source.as_bytes(), token).into_owned()))
+            .collect()
+    }
+
+    fn token(kind: TokenKind, text: &str) -> (TokenKind, String) {
+        (kind, String::from(text))
+    }
+
+    #[test]
+    fn prefers_the_longest_symbol() {
+        assert_eq!(
+            tokens("s ..= t..u...//=v"),
+            vec![
+                token(TokenKind::Name, "s"),
+                token(TokenKind::Symbol, "..="),
+                token(TokenKind::Name, "t"),
+                token(TokenKind::Symbol, ".."),
+                token(TokenKind::Name, "u"),
+                token(TokenKind::Symbol, "..."),
+                token(TokenKind::Symbol, "//="),
+                token(TokenKind::Name, "v"),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_contextual_keywords_as_names() {
+        assert_eq!(
+            tokens("local continue type export end"),
+            vec![
+                token(TokenKind::Keyword, "local"),
+                token(TokenKind::Name, "continue"),
+                token(TokenKind::Name, "type"),
+                token(TokenKind::Name, "export"),
+                token(TokenKind::Keyword, "end"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_numbers() {
+        assert_eq!(
+            tokens("3 3.0 3.1416 314.16e-2 0.31416E1 0xff 0x56 0b1010_1010 1_000_000 .5"),
+            vec![
+                token(TokenKind::Number, "3"),
+                token(TokenKind::Number, "3.0"),
+                token(TokenKind::Number, "3.1416"),
+                token(TokenKind::Number, "314.16e-2"),
+                token(TokenKind::Number, "0.31416E1"),
+                token(TokenKind::Number, "0xff"),
+                token(TokenKind::Number, "0x56"),
+                token(TokenKind::Number, "0b1010_1010"),
+                token(TokenKind::Number, "1_000_000"),
+                token(TokenKind::Number, ".5"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_reads_long_strings() {
+        assert_eq!(
+            tokens("--[==[ a ]] b ]==] x -- rest\n[[one\n]] [=[two]]]=]"),
+            vec![
+                token(TokenKind::Name, "x"),
+                token(TokenKind::String, "[[one\n]]"),
+                token(TokenKind::String, "[=[two]]]=]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_interpolated_strings_around_expressions() {
+        assert_eq!(
+            tokens("`plain` `a{b}c{ {d} }e`"),
+            vec![
+                token(TokenKind::InterpSimple, "`plain`"),
+                token(TokenKind::InterpBegin, "`a{"),
+                token(TokenKind::Name, "b"),
+                token(TokenKind::InterpMid, "}c{"),
+                token(TokenKind::Symbol, "{"),
+                token(TokenKind::Name, "d"),
+                token(TokenKind::Symbol, "}"),
+                token(TokenKind::InterpEnd, "}e`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_a_byte_order_mark_and_shebang() {
+        assert_eq!(
+            tokens("\u{feff}#!/usr/bin/env lua\nreturn"),
+            vec![token(TokenKind::Keyword, "return")]
+        );
+    }
+
+    // Columns count characters, and a CRLF is one line break, not two.
+    #[test]
+    fn tracks_lines_and_columns() {
+        let source = "a\r\n  bb\n\n\"\u{e9}\" [[x\ny]] c";
+        let lexed = tokenize(source.as_bytes());
+        assert!(lexed.error.is_none());
+
+        let positions: Vec<(u32, u32, u32, u32)> = lexed
+            .tokens
+            .iter()
+            .map(|token| (token.line, token.column, token.end_line, token.end_column))
+            .collect();
+
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1, 1, 2),
+                (2, 3, 2, 5),
+                (4, 1, 4, 4),
+                (4, 5, 5, 4),
+                (5, 5, 5, 6),
+                (5, 6, 5, 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_where_a_token_went_wrong() {
+        let lexed = tokenize(b"x = 'abc\n");
+        let error = lexed.error.expect("should fail");
+
+        assert_eq!(
+            (error.line, error.column, error.end_line, error.end_column),
+            (1, 5, 1, 9)
+        );
+        assert_eq!(error.message, "unfinished string near ''abc'");
+        assert_eq!(
+            lexed.tokens.last().map(|token| token.kind),
+            Some(TokenKind::Error)
+        );
+    }
+}
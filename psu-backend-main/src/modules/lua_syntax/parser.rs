@@ -0,0 +1,921 @@
+use crate::modules::lua_syntax::lexer::{self, Token, TokenKind};
+use crate::modules::lua_syntax::Diagnostic;
+
+use std::borrow::Cow;
+
+// Nesting allowed for blocks, expressions and types, the same limit Lua 5.1 has. It keeps a
+// hostile upload from running the request thread out of stack.
+const MAX_DEPTH: u32 = 200;
+
+const COMPOUND_OPERATORS: &[&str] = &["+=", "-=", "*=", "/=", "//=", "%=", "^=", "..="];
+
+const BINARY_OPERATORS: &[&str] = &[
+    "+", "-", "*", "/", "//", "%", "^", "..", "==", "~=", "<", "<=", ">", ">=", "and", "or",
+];
+
+const UNARY_OPERATORS: &[&str] = &["not", "-", "#"];
+
+// What an expression turned out to be, as far as statements care: only names and indexing can
+// be assigned to, and only calls can stand on their own.
+#[derive(Debug, Clone, Copy)]
+enum Expression {
+    // Index of the name's token, it may turn out to be `continue`, `type` or `export`.
+    Name(usize),
+    Index,
+    Call,
+    Other,
+}
+
+struct FunctionState {
+    vararg: bool,
+    loops: u32,
+}
+
+// Recursive descent over the whole chunk. Nothing is built, it only has to tell whether the
+// source parses and where it doesn't.
+pub struct Parser<'a> {
+    source: &'a [u8],
+    tokens: Vec<Token>,
+    lex_error: Option<Diagnostic>,
+    position: usize,
+    functions: Vec<FunctionState>,
+    depth: u32,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a [u8]) -> Self {
+        let lexed = lexer::tokenize(source);
+
+        Parser {
+            source,
+            tokens: lexed.tokens,
+            lex_error: lexed.error,
+            position: 0,
+            // The main chunk is a vararg function.
+            functions: vec![FunctionState {
+                vararg: true,
+                loops: 0,
+            }],
+            depth: 0,
+        }
+    }
+
+    pub fn parse(mut self) -> Result<(), Diagnostic> {
+        self.block()?;
+
+        if self.current().kind != TokenKind::Eof {
+            return Err(self.error("'<eof>' expected"));
+        }
+
+        Ok(())
+    }
+
+    fn current(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    // The token `offset` places ahead, or the last one if that runs off the end.
+    fn ahead(&self, offset: usize) -> &Token {
+        &self.tokens[(self.position + offset).min(self.tokens.len() - 1)]
+    }
+
+    fn text(&self, token: &Token) -> Cow<'a, str> {
+        lexer::text(self.source, token)
+    }
+
+    fn advance(&mut self) {
+        if self.position < self.tokens.len() - 1 {
+            self.position += 1;
+        }
+    }
+
+    // Whether the token `offset` places ahead is the given symbol or keyword.
+    fn is_at(&self, offset: usize, expected: &str) -> bool {
+        let token = self.ahead(offset);
+
+        matches!(token.kind, TokenKind::Symbol | TokenKind::Keyword) && self.text(token) == expected
+    }
+
+    fn is(&self, expected: &str) -> bool {
+        self.is_at(0, expected)
+    }
+
+    fn is_any(&self, expected: &[&str]) -> bool {
+        expected.iter().any(|expected| self.is(expected))
+    }
+
+    fn is_name_at(&self, offset: usize, expected: Option<&str>) -> bool {
+        let token = self.ahead(offset);
+
+        token.kind == TokenKind::Name
+            && expected.map_or(true, |expected| self.text(token) == expected)
+    }
+
+    fn accept(&mut self, expected: &str) -> bool {
+        if self.is(expected) {
+            self.advance();
+            return true;
+        }
+
+        false
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Diagnostic> {
+        if !self.accept(expected) {
+            return Err(self.error(&format!("'{}' expected", expected)));
+        }
+
+        Ok(())
+    }
+
+    // Like `expect`, but points back at the opening token when it's on an earlier line, which
+    // is far more useful for a missing `end`.
+    fn expect_closing(
+        &mut self,
+        expected: &str,
+        opened_by: &str,
+        line: u32,
+    ) -> Result<(), Diagnostic> {
+        if self.accept(expected) {
+            return Ok(());
+        }
+
+        if self.current().line == line {
+            return Err(self.error(&format!("'{}' expected", expected)));
+        }
+
+        Err(self.error(&format!(
+            "'{}' expected (to close '{}' at line {})",
+            expected, opened_by, line
+        )))
+    }
+
+    fn name(&mut self) -> Result<(), Diagnostic> {
+        if self.current().kind != TokenKind::Name {
+            return Err(self.error("<name> expected"));
+        }
+
+        self.advance();
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> Diagnostic {
+        self.error_at(self.position, message)
+    }
+
+    fn error_at(&self, index: usize, message: &str) -> Diagnostic {
+        let token = &self.tokens[index];
+
+        // Anything that runs into the broken token is really failing because of it.
+        if token.kind == TokenKind::Error {
+            if let Some(error) = &self.lex_error {
+                return error.clone();
+            }
+        }
+
+        let near: String = match token.kind {
+            TokenKind::Eof => String::from("<eof>"),
+            _ => self.text(token).chars().take(40).collect(),
+        };
+
+        Diagnostic {
+            line: token.line,
+            column: token.column,
+            end_line: token.end_line,
+            end_column: token.end_column,
+            message: format!("{} near '{}'", message, near),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), Diagnostic> {
+        self.depth += 1;
+
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("chunk has too many syntax levels"));
+        }
+
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn function(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn block_follow(&self) -> bool {
+        self.current().kind == TokenKind::Eof || self.is_any(&["else", "elseif", "end", "until"])
+    }
+
+    fn block(&mut self) -> Result<(), Diagnostic> {
+        self.enter()?;
+
+        while !self.block_follow() {
+            if self.accept(";") {
+                continue;
+            }
+
+            let last = self.statement()?;
+            self.accept(";");
+
+            // `return`, `break` and `continue` have to end their block.
+            if last {
+                break;
+            }
+        }
+
+        self.leave();
+        Ok(())
+    }
+
+    fn loop_block(&mut self) -> Result<(), Diagnostic> {
+        self.function().loops += 1;
+        self.block()?;
+        self.function().loops -= 1;
+
+        Ok(())
+    }
+
+    // Returns whether the statement has to be the last one in its block.
+    fn statement(&mut self) -> Result<bool, Diagnostic> {
+        let token = *self.current();
+        let line = token.line;
+
+        if token.kind != TokenKind::Keyword {
+            return self.expression_statement();
+        }
+
+        match self.text(&token).as_ref() {
+            "if" => {
+                self.advance();
+                self.expression()?;
+                self.expect("then")?;
+                self.block()?;
+
+                while self.accept("elseif") {
+                    self.expression()?;
+                    self.expect("then")?;
+                    self.block()?;
+                }
+
+                if self.accept("else") {
+                    self.block()?;
+                }
+
+                self.expect_closing("end", "if", line)?;
+            }
+            "while" => {
+                self.advance();
+                self.expression()?;
+                self.expect("do")?;
+                self.loop_block()?;
+                self.expect_closing("end", "while", line)?;
+            }
+            "do" => {
+                self.advance();
+                self.block()?;
+                self.expect_closing("end", "do", line)?;
+            }
+            "for" => {
+                self.advance();
+                self.name()?;
+                self.annotation()?;
+
+                if self.accept("=") {
+                    self.expression()?;
+                    self.expect(",")?;
+                    self.expression()?;
+
+                    if self.accept(",") {
+                        self.expression()?;
+                    }
+                } else if self.is(",") || self.is("in") {
+                    while self.accept(",") {
+                        self.name()?;
+                        self.annotation()?;
+                    }
+
+                    self.expect("in")?;
+                    self.expression_list()?;
+                } else {
+                    return Err(self.error("'=' or 'in' expected"));
+                }
+
+                self.expect("do")?;
+                self.loop_block()?;
+                self.expect_closing("end", "for", line)?;
+            }
+            "repeat" => {
+                self.advance();
+                self.loop_block()?;
+                self.expect_closing("until", "repeat", line)?;
+                self.expression()?;
+            }
+            "function" => {
+                self.advance();
+                self.name()?;
+
+                while self.accept(".") {
+                    self.name()?;
+                }
+
+                if self.accept(":") {
+                    self.name()?;
+                }
+
+                self.function_body(line)?;
+            }
+            "local" => {
+                self.advance();
+
+                if self.accept("function") {
+                    self.name()?;
+                    self.function_body(line)?;
+                } else {
+                    self.name()?;
+                    self.annotation()?;
+
+                    while self.accept(",") {
+                        self.name()?;
+                        self.annotation()?;
+                    }
+
+                    if self.accept("=") {
+                        self.expression_list()?;
+                    }
+                }
+            }
+            "return" => {
+                self.advance();
+
+                if !self.block_follow() && !self.is(";") {
+                    self.expression_list()?;
+                }
+
+                return Ok(true);
+            }
+            "break" => {
+                if self.functions.last().map_or(0, |function| function.loops) == 0 {
+                    return Err(self.error("no loop to break"));
+                }
+
+                self.advance();
+                return Ok(true);
+            }
+            _ => return self.expression_statement(),
+        }
+
+        Ok(false)
+    }
+
+    // Assignments, compound assignments and calls. `continue`, `type` and `export` aren't
+    // reserved in Luau, so they're only treated as statements once the name turns out not to
+    // be the start of anything else.
+    fn expression_statement(&mut self) -> Result<bool, Diagnostic> {
+        let expression = self.suffixed_expression()?;
+
+        if self.is("=") || self.is(",") {
+            self.assignable(expression)?;
+
+            while self.accept(",") {
+                let target = self.suffixed_expression()?;
+                self.assignable(target)?;
+            }
+
+            self.expect("=")?;
+            self.expression_list()?;
+
+            return Ok(false);
+        }
+
+        if self.is_any(COMPOUND_OPERATORS) {
+            self.assignable(expression)?;
+            self.advance();
+            self.expression()?;
+
+            return Ok(false);
+        }
+
+        match expression {
+            Expression::Call => Ok(false),
+            Expression::Name(index) => match self.text(&self.tokens[index]).as_ref() {
+                "continue" => {
+                    if self.functions.last().map_or(0, |function| function.loops) == 0 {
+                        return Err(self.error_at(index, "no loop to continue"));
+                    }
+
+                    Ok(true)
+                }
+                "type" if self.is_name_at(0, None) => {
+                    self.type_alias()?;
+                    Ok(false)
+                }
+                "export" if self.is_name_at(0, Some("type")) && self.is_name_at(1, None) => {
+                    self.advance();
+                    self.type_alias()?;
+                    Ok(false)
+                }
+                _ => Err(self.error("'=' expected")),
+            },
+            Expression::Index => Err(self.error("'=' expected")),
+            Expression::Other => Err(self.error("syntax error")),
+        }
+    }
+
+    fn assignable(&self, expression: Expression) -> Result<(), Diagnostic> {
+        match expression {
+            Expression::Name(_) | Expression::Index => Ok(()),
+            _ => Err(self.error("syntax error")),
+        }
+    }
+
+    fn expression_list(&mut self) -> Result<(), Diagnostic> {
+        self.expression()?;
+
+        while self.accept(",") {
+            self.expression()?;
+        }
+
+        Ok(())
+    }
+
+    // Precedence doesn't change whether an expression is valid, so operators are just
+    // skipped over in a loop.
+    fn expression(&mut self) -> Result<(), Diagnostic> {
+        self.enter()?;
+
+        loop {
+            while self.is_any(UNARY_OPERATORS) {
+                self.advance();
+            }
+
+            self.simple_expression()?;
+
+            if !self.is_any(BINARY_OPERATORS) {
+                break;
+            }
+
+            self.advance();
+        }
+
+        self.leave();
+        Ok(())
+    }
+
+    fn simple_expression(&mut self) -> Result<Expression, Diagnostic> {
+        let token = *self.current();
+
+        let expression = match token.kind {
+            TokenKind::Number | TokenKind::String | TokenKind::InterpSimple => {
+                self.advance();
+                Expression::Other
+            }
+            TokenKind::InterpBegin => {
+                self.interpolated_string()?;
+                Expression::Other
+            }
+            _ if self.is_any(&["nil", "true", "false"]) => {
+                self.advance();
+                Expression::Other
+            }
+            _ if self.is("...") => {
+                if !self
+                    .functions
+                    .last()
+                    .map_or(false, |function| function.vararg)
+                {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+
+                self.advance();
+                Expression::Other
+            }
+            _ if self.is("{") => {
+                self.table()?;
+                Expression::Other
+            }
+            _ if self.is("function") => {
+                self.advance();
+                self.function_body(token.line)?;
+                Expression::Other
+            }
+            _ if self.is("if") => {
+                self.if_expression()?;
+                Expression::Other
+            }
+            _ => self.suffixed_expression()?,
+        };
+
+        // Luau type assertion, `value :: Type`.
+        if self.accept("::") {
+            self.type_(false)?;
+            return Ok(Expression::Other);
+        }
+
+        Ok(expression)
+    }
+
+    fn suffixed_expression(&mut self) -> Result<Expression, Diagnostic> {
+        let token = *self.current();
+
+        let mut expression = if token.kind == TokenKind::Name {
+            self.advance();
+            Expression::Name(self.position - 1)
+        } else if self.is("(") {
+            self.advance();
+            self.expression()?;
+            self.expect_closing(")", "(", token.line)?;
+            Expression::Other
+        } else {
+            return Err(self.error("unexpected symbol"));
+        };
+
+        loop {
+            if self.accept(".") {
+                self.name()?;
+                expression = Expression::Index;
+            } else if self.is("[") {
+                self.advance();
+                self.expression()?;
+                self.expect("]")?;
+                expression = Expression::Index;
+            } else if self.accept(":") {
+                self.name()?;
+                self.call_arguments()?;
+                expression = Expression::Call;
+            } else if self.is("(") || self.is("{") || self.current().kind == TokenKind::String {
+                self.call_arguments()?;
+                expression = Expression::Call;
+            } else {
+                return Ok(expression);
+            }
+        }
+    }
+
+    fn call_arguments(&mut self) -> Result<(), Diagnostic> {
+        let token = *self.current();
+
+        if token.kind == TokenKind::String {
+            self.advance();
+            return Ok(());
+        }
+
+        if self.is("{") {
+            return self.table();
+        }
+
+        if !self.is("(") {
+            return Err(self.error("function arguments expected"));
+        }
+
+        // `f` then `(g)()` on the next line reads as a call of `f`, which is rarely what was meant.
+        if self.position > 0 && self.tokens[self.position - 1].end_line != token.line {
+            return Err(self.error("ambiguous syntax (function call x new statement)"));
+        }
+
+        self.advance();
+
+        if !self.is(")") {
+            self.expression_list()?;
+        }
+
+        self.expect_closing(")", "(", token.line)
+    }
+
+    fn table(&mut self) -> Result<(), Diagnostic> {
+        let line = self.current().line;
+        self.expect("{")?;
+
+        while !self.is("}") {
+            if self.accept("[") {
+                self.expression()?;
+                self.expect("]")?;
+                self.expect("=")?;
+                self.expression()?;
+            } else if self.is_name_at(0, None) && self.is_at(1, "=") {
+                self.advance();
+                self.advance();
+                self.expression()?;
+            } else {
+                self.expression()?;
+            }
+
+            if !self.accept(",") && !self.accept(";") {
+                break;
+            }
+        }
+
+        self.expect_closing("}", "{", line)
+    }
+
+    fn function_body(&mut self, line: u32) -> Result<(), Diagnostic> {
+        if self.is("<") {
+            self.generic_parameters()?;
+        }
+
+        let parameters_line = self.current().line;
+        self.expect("(")?;
+
+        let mut vararg = false;
+
+        if !self.is(")") {
+            loop {
+                if self.accept("...") {
+                    vararg = true;
+
+                    if self.accept(":") {
+                        self.type_(true)?;
+                    }
+
+                    break;
+                }
+
+                self.name()?;
+                self.annotation()?;
+
+                if !self.accept(",") {
+                    break;
+                }
+            }
+        }
+
+        self.expect_closing(")", "(", parameters_line)?;
+
+        if self.accept(":") {
+            self.type_(true)?;
+        }
+
+        self.functions.push(FunctionState { vararg, loops: 0 });
+        self.block()?;
+        self.functions.pop();
+
+        self.expect_closing("end", "function", line)
+    }
+
+    fn if_expression(&mut self) -> Result<(), Diagnostic> {
+        self.expect("if")?;
+        self.expression()?;
+        self.expect("then")?;
+        self.expression()?;
+
+        while self.accept("elseif") {
+            self.expression()?;
+            self.expect("then")?;
+            self.expression()?;
+        }
+
+        // Unlike the statement, an if expression always needs its else branch.
+        self.expect("else")?;
+        self.expression()
+    }
+
+    fn interpolated_string(&mut self) -> Result<(), Diagnostic> {
+        let line = self.current().line;
+        self.advance();
+
+        loop {
+            if matches!(
+                self.current().kind,
+                TokenKind::InterpMid | TokenKind::InterpEnd
+            ) {
+                return Err(self.error("expression expected inside '{}'"));
+            }
+
+            self.expression()?;
+
+            match self.current().kind {
+                TokenKind::InterpMid => self.advance(),
+                TokenKind::InterpEnd => {
+                    self.advance();
+                    return Ok(());
+                }
+                _ => return self.expect_closing("}", "`", line),
+            }
+        }
+    }
+
+    fn annotation(&mut self) -> Result<(), Diagnostic> {
+        if self.accept(":") {
+            self.type_(false)?;
+        }
+
+        Ok(())
+    }
+
+    // `type Name<T> = ...`, with `type` (and `export`) already consumed.
+    fn type_alias(&mut self) -> Result<(), Diagnostic> {
+        self.name()?;
+
+        if self.is("<") {
+            self.generic_parameters()?;
+        }
+
+        self.expect("=")?;
+        self.type_(false)
+    }
+
+    // `<T, U..., V = string>` on functions and type aliases.
+    fn generic_parameters(&mut self) -> Result<(), Diagnostic> {
+        self.expect("<")?;
+
+        loop {
+            self.name()?;
+            self.accept("...");
+
+            if self.accept("=") {
+                self.type_(true)?;
+            }
+
+            if !self.accept(",") {
+                break;
+            }
+        }
+
+        self.expect(">")
+    }
+
+    // Type packs such as `(number, string)` or `...number` are only allowed where several values
+    // can go: return types, variadics and generic arguments.
+    fn type_(&mut self, allow_pack: bool) -> Result<(), Diagnostic> {
+        self.enter()?;
+
+        // A leading separator is allowed so long unions can be lined up.
+        if !self.accept("|") {
+            self.accept("&");
+        }
+
+        loop {
+            self.simple_type(allow_pack)?;
+
+            while self.accept("?") {}
+
+            if !self.accept("|") && !self.accept("&") {
+                break;
+            }
+        }
+
+        self.leave();
+        Ok(())
+    }
+
+    fn simple_type(&mut self, allow_pack: bool) -> Result<(), Diagnostic> {
+        let token = *self.current();
+
+        if token.kind == TokenKind::String || self.is_any(&["nil", "true", "false"]) {
+            self.advance();
+            return Ok(());
+        }
+
+        if self.is_name_at(0, Some("typeof")) && self.is_at(1, "(") {
+            self.advance();
+            let line = self.current().line;
+            self.advance();
+            self.expression()?;
+            return self.expect_closing(")", "(", line);
+        }
+
+        if token.kind == TokenKind::Name {
+            self.advance();
+
+            if self.accept(".") {
+                self.name()?;
+            }
+
+            if self.is("<") {
+                self.type_arguments()?;
+            }
+
+            // A generic pack, `T...`.
+            if allow_pack {
+                self.accept("...");
+            }
+
+            return Ok(());
+        }
+
+        if self.is("{") {
+            return self.table_type();
+        }
+
+        if self.is("(") || self.is("<") {
+            return self.function_type(allow_pack);
+        }
+
+        if allow_pack && self.accept("...") {
+            return self.type_(false);
+        }
+
+        Err(self.error("type expected"))
+    }
+
+    fn type_arguments(&mut self) -> Result<(), Diagnostic> {
+        self.expect("<")?;
+
+        if !self.is(">") {
+            loop {
+                self.type_(true)?;
+
+                if !self.accept(",") {
+                    break;
+                }
+            }
+        }
+
+        self.expect(">")
+    }
+
+    // `(A, B) -> C`, or just a type in parentheses. Without the arrow the parentheses have to
+    // hold exactly one plain type unless a pack is allowed here.
+    fn function_type(&mut self, allow_pack: bool) -> Result<(), Diagnostic> {
+        let generic = self.is("<");
+
+        if generic {
+            self.generic_parameters()?;
+        }
+
+        let line = self.current().line;
+        self.expect("(")?;
+
+        let mut count = 0;
+        let mut plain = true;
+
+        if !self.is(")") {
+            loop {
+                // Named arguments, `(name: string) -> ()`.
+                if self.is_name_at(0, None) && self.is_at(1, ":") {
+                    self.advance();
+                    self.advance();
+                    plain = false;
+                }
+
+                if self.is("...") {
+                    plain = false;
+                }
+
+                self.type_(true)?;
+                count += 1;
+
+                if !self.accept(",") {
+                    break;
+                }
+            }
+        }
+
+        self.expect_closing(")", "(", line)?;
+
+        if self.accept("->") {
+            return self.type_(true);
+        }
+
+        if generic || (!allow_pack && (count != 1 || !plain)) {
+            return Err(self.error("'->' expected"));
+        }
+
+        Ok(())
+    }
+
+    fn table_type(&mut self) -> Result<(), Diagnostic> {
+        let line = self.current().line;
+        self.expect("{")?;
+
+        // `{T}` is an array of T.
+        if !self.is("}") && !self.is("[") && !self.is_property_at(0) && !self.is_modifier() {
+            self.type_(false)?;
+            return self.expect_closing("}", "{", line);
+        }
+
+        while !self.is("}") {
+            if self.is_modifier() {
+                self.advance();
+            }
+
+            if self.accept("[") {
+                self.type_(false)?;
+                self.expect("]")?;
+            } else {
+                self.name()?;
+            }
+
+            self.expect(":")?;
+            self.type_(false)?;
+
+            if !self.accept(",") && !self.accept(";") {
+                break;
+            }
+        }
+
+        self.expect_closing("}", "{", line)
+    }
+
+    fn is_property_at(&self, offset: usize) -> bool {
+        self.is_name_at(offset, None) && self.is_at(offset + 1, ":")
+    }
+
+    // `read` or `write` in front of a table type property, as opposed to a property with that name.
+    fn is_modifier(&self) -> bool {
+        (self.is_name_at(0, Some("read")) || self.is_name_at(0, Some("write")))
+            && (self.is_property_at(1) || self.is_at(1, "["))
+    }
+}
@@ -0,0 +1,60 @@
+use colored::*;
+
+// Sends one of the Mailgun templates configured on email.psu.dev. Template variables are
+// passed without the `v:` prefix.
+pub fn send_template(
+    to: &str,
+    subject: &str,
+    template: &str,
+    variables: &[(&str, &str)],
+) -> Result<String, String> {
+    let api_key = match std::env::var("MAILGUN_KEY") {
+        Ok(data) => data,
+        Err(_err) => {
+            println!("[{}] MAILGUN_KEY is not set, not sending {}", "MAIL".red(), template);
+            return Err(String::from("ERR_MAIL_NOT_CONFIGURED"));
+        }
+    };
+
+    let recipient = format!("<{}>", to);
+    let variable_names: Vec<String> = variables
+        .iter()
+        .map(|(name, _value)| format!("v:{}", name))
+        .collect();
+
+    let mut form: Vec<(&str, &str)> = vec![
+        ("from", "PSU <donotreply@email.psu.dev>"),
+        ("to", &recipient),
+        ("subject", subject),
+        ("template", template),
+    ];
+
+    for (name, (_name, value)) in variable_names.iter().zip(variables.iter()) {
+        form.push((name, value));
+    }
+
+    let response = ureq::post("https://api.mailgun.net/v3/email.psu.dev/messages")
+        .set(
+            "Authorization",
+            &format!("Basic {}", base64::encode(format!("api:{}", api_key))),
+        )
+        .send_form(&form);
+
+    if let Some(err) = response.synthetic_error() {
+        println!("[{}] Failed to reach Mailgun: {}", "MAIL".red(), err);
+        return Err(String::from("ERR_MAIL_FAILED"));
+    }
+
+    if !response.ok() {
+        println!(
+            "[{}] Mailgun rejected {} with status {}: {}",
+            "MAIL".red(),
+            template,
+            response.status(),
+            response.into_string().unwrap_or_default()
+        );
+        return Err(String::from("ERR_MAIL_FAILED"));
+    }
+
+    Ok(String::from("SUCCESS"))
+}
@@ -1,5 +1,14 @@
 pub mod account_services;
+pub mod audit_log;
+pub mod captcha;
+pub mod config;
+pub mod credentials;
+pub mod discord_sync;
+pub mod lua_syntax;
+pub mod mailer;
+pub mod object_store;
 pub mod paypal;
+pub mod scheduler;
 pub mod script_services;
 pub mod stripe_additions;
 pub mod user;
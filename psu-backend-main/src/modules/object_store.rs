@@ -0,0 +1,239 @@
+use crate::modules::config;
+
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+// Returned by `get` when the key doesn't exist, so callers can tell it apart from an outage.
+pub const ERR_NOT_FOUND: &str = "ERR_OBJECT_NOT_FOUND";
+
+#[derive(Debug, Default, Clone)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// One bucket's worth of objects. Deleting a key that doesn't exist is not an error, same as S3.
+pub trait ObjectStore: Send + Sync {
+    fn put(&self, key: &str, data: Vec<u8>, options: &PutOptions) -> Result<(), String>;
+    // Uploads exactly `size` bytes from `reader` without holding them all in memory, and returns
+    // the SHA-256 of what was stored as hex.
+    fn put_stream(&self, key: &str, reader: Box<dyn Read + Send>, size: u64, options: &PutOptions) -> Result<String, String>;
+    fn get(&self, key: &str) -> Result<Vec<u8>, String>;
+    // Streams the object, or the inclusive byte range of it, instead of loading it into memory.
+    fn get_range(&self, key: &str, range: Option<(u64, u64)>) -> Result<Box<dyn Read + Send>, String>;
+    fn delete(&self, key: &str) -> Result<(), String>;
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, String>;
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>, String>;
+    // A URL that allows a GET of the object without credentials until it expires.
+    fn presign(&self, key: &str, expires_in: Duration) -> Result<String, String>;
+}
+
+// Hashes everything read through it, so a streamed upload gets its checksum on the way past
+// instead of in a second pass.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    read: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            read: 0,
+        }
+    }
+
+    // Fails if the reader didn't produce the `size` bytes the upload was started with.
+    pub fn finish(self, size: u64) -> Result<String, String> {
+        if self.read != size {
+            println!("STORAGE ERROR: expected {} bytes, read {}", size, self.read);
+            return Err(String::from("ERR_STORAGE_ERR"));
+        }
+
+        Ok(self
+            .hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        self.hasher.update(&buf[..read]);
+        self.read += read as u64;
+
+        Ok(read)
+    }
+}
+
+// Both buckets, built once from the environment. On S3 they share one connection, so there's
+// a single runtime, connection pool and credentials cache for the whole process.
+pub struct Storage {
+    pub scripts: Box<dyn ObjectStore>,
+    pub public: Box<dyn ObjectStore>,
+}
+
+impl Storage {
+    // OBJECT_STORE picks the backend: s3 (default), local for self-hosting and development, or
+    // memory, which forgets everything on restart and is meant for tests.
+    pub fn from_env() -> Self {
+        let scripts_bucket = config::env_or("SCRIPTS_BUCKET", String::from("psu-scripts-bucket"));
+        let public_bucket = config::env_or("PUBLIC_BUCKET", String::from("psu-public"));
+
+        match config::env_or("OBJECT_STORE", String::from("s3")).as_str() {
+            "local" => {
+                let path = config::env_or("LOCAL_STORAGE_PATH", String::from("./storage"));
+
+                Storage {
+                    scripts: Box::new(local::LocalStore::new(path.clone(), &scripts_bucket)),
+                    public: Box::new(local::LocalStore::new(path, &public_bucket)),
+                }
+            }
+            "memory" => Storage {
+                scripts: Box::new(memory::MemoryStore::new(&scripts_bucket)),
+                public: Box::new(memory::MemoryStore::new(&public_bucket)),
+            },
+            _ => {
+                let connection = Arc::new(s3::S3Connection::from_env());
+
+                Storage {
+                    scripts: Box::new(s3::S3Store::new(scripts_bucket, connection.clone())),
+                    public: Box::new(s3::S3Store::new(public_bucket, connection)),
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref STORAGE: Storage = Storage::from_env();
+}
+
+// Builds the storage backend up front so the first request doesn't pay for the runtime and
+// connection setup. Call once at startup.
+pub fn init() {
+    lazy_static::initialize(&STORAGE);
+}
+
+pub fn storage() -> &'static Storage {
+    &STORAGE
+}
+
+// Script sources and their versions. Never served directly.
+pub fn scripts() -> &'static dyn ObjectStore {
+    STORAGE.scripts.as_ref()
+}
+
+// Served as-is through PUBLIC_OBJECT_URL, avatars live here.
+pub fn public() -> &'static dyn ObjectStore {
+    STORAGE.public.as_ref()
+}
+
+pub fn public_url(key: &str) -> String {
+    format!(
+        "{}/{}",
+        config::env_or("PUBLIC_OBJECT_URL", String::from("https://cdn.psu.dev")),
+        key
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nanoid::nanoid;
+    use std::io::Cursor;
+
+    fn read_all(mut reader: Box<dyn Read + Send>) -> Vec<u8> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn keys(objects: Vec<ObjectInfo>) -> Vec<String> {
+        objects.into_iter().map(|object| object.key).collect()
+    }
+
+    // What every backend has to agree on. S3 isn't run here, it needs a real bucket.
+    fn check_contract(store: &dyn ObjectStore) {
+        let options = PutOptions {
+            content_type: Some(String::from("text/plain")),
+            ..Default::default()
+        };
+
+        assert_eq!(store.get("scripts/missing"), Err(String::from(ERR_NOT_FOUND)));
+        assert!(matches!(store.get_range("scripts/missing", None), Err(err) if err == ERR_NOT_FOUND));
+        assert!(store.head("scripts/missing").unwrap().is_none());
+        assert_eq!(store.delete("scripts/missing"), Ok(()));
+
+        store.put("scripts/a", b"first".to_vec(), &options).unwrap();
+        store.put("scripts/a", b"0123456789".to_vec(), &options).unwrap();
+        store.put("scripts/b/c", b"nested".to_vec(), &options).unwrap();
+        store.put("other/d", b"elsewhere".to_vec(), &options).unwrap();
+
+        assert_eq!(store.get("scripts/a"), Ok(b"0123456789".to_vec()));
+        assert_eq!(store.head("scripts/a").unwrap().map(|object| object.size), Some(10));
+
+        assert_eq!(read_all(store.get_range("scripts/a", None).unwrap()), b"0123456789");
+        assert_eq!(read_all(store.get_range("scripts/a", Some((2, 4))).unwrap()), b"234");
+        assert_eq!(read_all(store.get_range("scripts/a", Some((9, 9))).unwrap()), b"9");
+
+        assert_eq!(keys(store.list("scripts/").unwrap()), vec!["scripts/a", "scripts/b/c"]);
+        assert_eq!(keys(store.list("").unwrap()), vec!["other/d", "scripts/a", "scripts/b/c"]);
+        assert!(store.list("nothing/").unwrap().is_empty());
+
+        store.delete("scripts/a").unwrap();
+
+        assert_eq!(store.get("scripts/a"), Err(String::from(ERR_NOT_FOUND)));
+        assert!(store.head("scripts/a").unwrap().is_none());
+        assert_eq!(keys(store.list("scripts/").unwrap()), vec!["scripts/b/c"]);
+
+        let streamed = b"streamed in pieces".to_vec();
+        let sha256 = store
+            .put_stream("scripts/e", Box::new(Cursor::new(streamed.clone())), 18, &options)
+            .unwrap();
+
+        assert_eq!(sha256, format!("{:x}", Sha256::digest(&streamed)));
+        assert_eq!(store.get("scripts/e"), Ok(streamed));
+
+        // A reader that runs out early must not leave a truncated object behind.
+        assert!(store
+            .put_stream("scripts/f", Box::new(Cursor::new(b"short".to_vec())), 10, &options)
+            .is_err());
+        assert!(store.head("scripts/f").unwrap().is_none());
+    }
+
+    #[test]
+    fn memory_store_keeps_the_contract() {
+        check_contract(&memory::MemoryStore::new("test"));
+    }
+
+    #[test]
+    fn local_store_keeps_the_contract() {
+        let base = std::env::temp_dir().join(format!("psu-object-store-{}", nanoid!(10)));
+
+        check_contract(&local::LocalStore::new(base.to_string_lossy().into_owned(), "test"));
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
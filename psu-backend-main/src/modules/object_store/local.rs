@@ -0,0 +1,257 @@
+use crate::modules::object_store::{HashingReader, ObjectInfo, ObjectStore, PutOptions, ERR_NOT_FOUND};
+
+use nanoid::nanoid;
+use std::fs;
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+// Each bucket is a directory under LOCAL_STORAGE_PATH and keys map straight to file paths.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(base_path: String, bucket: &str) -> Self {
+        LocalStore {
+            root: Path::new(&base_path).join(bucket),
+        }
+    }
+
+    // Keys come from our own code, but anything that could step outside the bucket is refused
+    // all the same.
+    fn path(&self, key: &str) -> Result<PathBuf, String> {
+        let relative = Path::new(key);
+
+        if key.is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(String::from("ERR_INVALID_OBJECT_KEY"));
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    fn info(key: String, metadata: &fs::Metadata) -> ObjectInfo {
+        ObjectInfo {
+            key,
+            size: metadata.len(),
+            content_type: None,
+            last_modified: metadata.modified().ok().map(chrono::DateTime::from),
+        }
+    }
+
+    fn walk(&self, directory: &Path, found: &mut Vec<ObjectInfo>) -> Result<(), String> {
+        let entries = match fs::read_dir(directory) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                println!("STORAGE ERROR: {}", err);
+                return Err(String::from("ERR_STORAGE_ERR"));
+            }
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(data) => data,
+                Err(err) => {
+                    println!("STORAGE ERROR: {}", err);
+                    return Err(String::from("ERR_STORAGE_ERR"));
+                }
+            };
+
+            let path = entry.path();
+
+            let metadata = match entry.metadata() {
+                Ok(data) => data,
+                Err(err) => {
+                    println!("STORAGE ERROR: {}", err);
+                    return Err(String::from("ERR_STORAGE_ERR"));
+                }
+            };
+
+            if metadata.is_dir() {
+                self.walk(&path, found)?;
+                continue;
+            }
+
+            // Half written files from `put`.
+            if path.extension().map_or(false, |extension| extension == "partial") {
+                continue;
+            }
+
+            if let Ok(relative) = path.strip_prefix(&self.root) {
+                let key = relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                found.push(Self::info(key, &metadata));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn put(&self, key: &str, data: Vec<u8>, options: &PutOptions) -> Result<(), String> {
+        let size = data.len() as u64;
+
+        self.put_stream(key, Box::new(Cursor::new(data)), size, options).map(|_sha256| ())
+    }
+
+    // Written next to the target and renamed into place, so readers never see half a file.
+    fn put_stream(&self, key: &str, reader: Box<dyn Read + Send>, size: u64, _options: &PutOptions) -> Result<String, String> {
+        let path = self.path(key)?;
+
+        if let Some(parent) = path.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                println!("STORAGE ERROR: {}", err);
+                return Err(String::from("ERR_STORAGE_ERR"));
+            }
+        }
+
+        let partial = path.with_extension(format!("{}.partial", nanoid!(8)));
+        let mut reader = HashingReader::new(reader);
+
+        let written = fs::File::create(&partial).and_then(|mut file| io::copy(&mut reader, &mut file));
+
+        if let Err(err) = written {
+            println!("STORAGE ERROR: {}", err);
+            let _ = fs::remove_file(&partial);
+            return Err(String::from("ERR_STORAGE_ERR"));
+        }
+
+        let sha256 = match reader.finish(size) {
+            Ok(data) => data,
+            Err(err) => {
+                let _ = fs::remove_file(&partial);
+                return Err(err);
+            }
+        };
+
+        if let Err(err) = fs::rename(&partial, &path) {
+            println!("STORAGE ERROR: {}", err);
+            let _ = fs::remove_file(&partial);
+            return Err(String::from("ERR_STORAGE_ERR"));
+        }
+
+        Ok(sha256)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(data),
+            Err(err) if err.kind() == ErrorKind::NotFound => Err(String::from(ERR_NOT_FOUND)),
+            Err(err) => {
+                println!("STORAGE ERROR: {}", err);
+                Err(String::from("ERR_STORAGE_ERR"))
+            }
+        }
+    }
+
+    fn get_range(&self, key: &str, range: Option<(u64, u64)>) -> Result<Box<dyn Read + Send>, String> {
+        let mut file = match fs::File::open(self.path(key)?) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Err(String::from(ERR_NOT_FOUND)),
+            Err(err) => {
+                println!("STORAGE ERROR: {}", err);
+                return Err(String::from("ERR_STORAGE_ERR"));
+            }
+        };
+
+        match range {
+            Some((start, end)) => {
+                if let Err(err) = file.seek(SeekFrom::Start(start)) {
+                    println!("STORAGE ERROR: {}", err);
+                    return Err(String::from("ERR_STORAGE_ERR"));
+                }
+
+                Ok(Box::new(file.take(end - start + 1)))
+            }
+            None => Ok(Box::new(file)),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(_data) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => {
+                println!("STORAGE ERROR: {}", err);
+                Err(String::from("ERR_STORAGE_ERR"))
+            }
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, String> {
+        let mut found: Vec<ObjectInfo> = Default::default();
+        self.walk(&self.root.clone(), &mut found)?;
+
+        found.retain(|object| object.key.starts_with(prefix));
+        found.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(found)
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>, String> {
+        match fs::metadata(self.path(key)?) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(Self::info(key.to_string(), &metadata))),
+            Ok(_metadata) => Ok(None),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => {
+                println!("STORAGE ERROR: {}", err);
+                Err(String::from("ERR_STORAGE_ERR"))
+            }
+        }
+    }
+
+    // There's nothing to sign for plain files, whatever serves the directory decides access.
+    fn presign(&self, _key: &str, _expires_in: Duration) -> Result<String, String> {
+        Err(String::from("ERR_PRESIGN_UNSUPPORTED"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_keys_outside_the_bucket() {
+        let base = std::env::temp_dir().join(format!("psu-local-store-{}", nanoid!(10)));
+        let store = LocalStore::new(base.to_string_lossy().into_owned(), "bucket");
+        let invalid = Some(String::from("ERR_INVALID_OBJECT_KEY"));
+
+        for key in &["", "../escaped", "a/../../escaped", "/tmp/escaped", "./a", "a/.."] {
+            assert_eq!(store.path(key).err(), invalid, "{:?}", key);
+            assert_eq!(store.put(key, b"x".to_vec(), &PutOptions::default()).err(), invalid, "{:?}", key);
+            assert_eq!(store.get(key).err(), invalid, "{:?}", key);
+            assert_eq!(store.get_range(key, None).err(), invalid, "{:?}", key);
+            assert_eq!(store.delete(key).err(), invalid, "{:?}", key);
+            assert_eq!(store.head(key).err(), invalid, "{:?}", key);
+        }
+
+        assert!(!base.join("escaped").exists());
+        assert_eq!(store.path("a/b.lua"), Ok(base.join("bucket").join("a").join("b.lua")));
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn hides_half_written_files() {
+        let base = std::env::temp_dir().join(format!("psu-local-store-{}", nanoid!(10)));
+        let store = LocalStore::new(base.to_string_lossy().into_owned(), "bucket");
+
+        store.put("a", b"done".to_vec(), &PutOptions::default()).unwrap();
+        fs::write(base.join("bucket").join("b.x1y2z3.partial"), b"half").unwrap();
+
+        let keys: Vec<String> = store.list("").unwrap().into_iter().map(|object| object.key).collect();
+        assert_eq!(keys, vec!["a"]);
+
+        let _ = fs::remove_dir_all(&base);
+    }
+}
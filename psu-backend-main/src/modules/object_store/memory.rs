@@ -0,0 +1,117 @@
+use crate::modules::object_store::{HashingReader, ObjectInfo, ObjectStore, PutOptions, ERR_NOT_FOUND};
+
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+use std::sync::Mutex;
+use std::time::Duration;
+
+struct StoredObject {
+    data: Vec<u8>,
+    content_type: Option<String>,
+    last_modified: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct MemoryStore {
+    bucket: String,
+    objects: Mutex<BTreeMap<String, StoredObject>>,
+}
+
+impl MemoryStore {
+    pub fn new(bucket: &str) -> Self {
+        MemoryStore {
+            bucket: bucket.to_string(),
+            objects: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn info(key: &str, object: &StoredObject) -> ObjectInfo {
+        ObjectInfo {
+            key: key.to_string(),
+            size: object.data.len() as u64,
+            content_type: object.content_type.clone(),
+            last_modified: Some(object.last_modified),
+        }
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn put(&self, key: &str, data: Vec<u8>, options: &PutOptions) -> Result<(), String> {
+        self.objects.lock().unwrap().insert(
+            key.to_string(),
+            StoredObject {
+                data,
+                content_type: options.content_type.clone(),
+                last_modified: chrono::Utc::now(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn put_stream(&self, key: &str, reader: Box<dyn Read + Send>, size: u64, options: &PutOptions) -> Result<String, String> {
+        let mut reader = HashingReader::new(reader);
+        let mut data = Vec::new();
+
+        if let Err(err) = reader.read_to_end(&mut data) {
+            println!("STORAGE ERROR: {}", err);
+            return Err(String::from("ERR_STORAGE_ERR"));
+        }
+
+        let sha256 = reader.finish(size)?;
+
+        self.put(key, data, options)?;
+
+        Ok(sha256)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        match self.objects.lock().unwrap().get(key) {
+            Some(object) => Ok(object.data.clone()),
+            None => Err(String::from(ERR_NOT_FOUND)),
+        }
+    }
+
+    fn get_range(&self, key: &str, range: Option<(u64, u64)>) -> Result<Box<dyn Read + Send>, String> {
+        let data = self.get(key)?;
+
+        let slice = match range {
+            Some((start, end)) => {
+                let end = (end as usize).min(data.len().saturating_sub(1));
+                data.get(start as usize..=end).unwrap_or_default().to_vec()
+            }
+            None => data,
+        };
+
+        Ok(Box::new(Cursor::new(slice)))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, String> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _object)| key.starts_with(prefix))
+            .map(|(key, object)| Self::info(key, object))
+            .collect())
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>, String> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|object| Self::info(key, object)))
+    }
+
+    // Nothing can fetch these, but it lets code that hands out links run against this store.
+    fn presign(&self, key: &str, _expires_in: Duration) -> Result<String, String> {
+        Ok(format!("memory://{}/{}", self.bucket, key))
+    }
+}
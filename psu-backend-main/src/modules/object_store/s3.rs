@@ -0,0 +1,392 @@
+use crate::modules::config;
+use crate::modules::object_store::{HashingReader, ObjectInfo, ObjectStore, PutOptions, ERR_NOT_FOUND};
+
+use rusoto_core::credential::{AutoRefreshingProvider, ChainProvider, ProvideAwsCredentials};
+use rusoto_core::request::{HttpClient, HttpConfig};
+use rusoto_core::{ByteStream, Region, RusotoError};
+use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadObjectRequest, ListObjectsV2Request,
+    PutObjectRequest, S3Client, S3,
+};
+
+use futures::stream::{self, TryStreamExt};
+use std::future::Future;
+use std::io::{self, ErrorKind, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, Semaphore};
+
+type Credentials = Arc<AutoRefreshingProvider<ChainProvider>>;
+
+// Streamed uploads are read in chunks this big, with at most a few of them waiting to be sent.
+const UPLOAD_CHUNK_BYTES: usize = 64 * 1024;
+const UPLOAD_CHUNKS_IN_FLIGHT: usize = 4;
+
+// Everything that should live for the whole process: the runtime, the HTTP connection pool and
+// the cached credentials. Rocket routes are synchronous, so calls block on this runtime instead
+// of each spinning up their own, and pooled connections survive between requests.
+pub struct S3Connection {
+    runtime: Runtime,
+    client: S3Client,
+    credentials: Credentials,
+    region: Region,
+    // Caps how many storage calls are in flight, so a burst of uploads can't open a connection
+    // per Rocket worker and starve everything else.
+    permits: Semaphore,
+    timeout: Duration,
+}
+
+fn storage_error<E: std::fmt::Display>(err: E) -> String {
+    println!("AWS ERROR: {}", err);
+    String::from("ERR_STORAGE_ERR")
+}
+
+fn parse_rfc2822(value: Option<String>) -> Option<chrono::DateTime<chrono::Utc>> {
+    value
+        .and_then(|value| chrono::DateTime::parse_from_rfc2822(&value).ok())
+        .map(|date| date.with_timezone(&chrono::Utc))
+}
+
+fn parse_rfc3339(value: Option<String>) -> Option<chrono::DateTime<chrono::Utc>> {
+    value
+        .and_then(|value| chrono::DateTime::parse_from_rfc3339(&value).ok())
+        .map(|date| date.with_timezone(&chrono::Utc))
+}
+
+impl S3Connection {
+    pub fn from_env() -> Self {
+        let region = Region::Custom {
+            name: config::env_or("S3_REGION", String::from("nyc-3")),
+            endpoint: config::env_or(
+                "S3_ENDPOINT",
+                String::from("https://psu.sfo3.digitaloceanspaces.com"),
+            ),
+        };
+
+        // The instance metadata lookup at the end of the chain regularly takes longer than a
+        // couple hundred milliseconds on a cold start. Credentials are cached and refreshed
+        // before they expire, so this is only paid once in a while.
+        let mut chain = ChainProvider::new();
+        chain.set_timeout(Duration::from_millis(config::env_or(
+            "S3_CREDENTIALS_TIMEOUT_MS",
+            5_000,
+        )));
+
+        let credentials: Credentials = Arc::new(
+            AutoRefreshingProvider::new(chain).expect("failed to create credentials provider"),
+        );
+
+        let mut http_config = HttpConfig::new();
+        http_config.pool_idle_timeout(Duration::from_secs(90));
+
+        let client = S3Client::new_with(
+            HttpClient::new_with_config(http_config).expect("failed to create request dispatcher"),
+            credentials.clone(),
+            region.clone(),
+        );
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(config::env_or("S3_WORKER_THREADS", 2))
+            .thread_name("psu-storage")
+            .enable_all()
+            .build()
+            .expect("failed to create storage runtime");
+
+        S3Connection {
+            runtime,
+            client,
+            credentials,
+            region,
+            permits: Semaphore::new(config::env_or("S3_MAX_CONCURRENCY", 32)),
+            timeout: Duration::from_secs(config::env_or("S3_TIMEOUT_SECS", 30)),
+        }
+    }
+
+    // Runs one storage call to completion. The timeout covers waiting for a permit as well,
+    // so callers never hang on a stuck connection.
+    fn run<T, F>(&self, operation: F) -> Result<T, String>
+    where
+        F: Future<Output = Result<T, String>>,
+    {
+        self.runtime.block_on(async {
+            let limited = async {
+                let _permit = match self.permits.acquire().await {
+                    Ok(data) => data,
+                    Err(err) => return Err(storage_error(err)),
+                };
+
+                operation.await
+            };
+
+            match tokio::time::timeout(self.timeout, limited).await {
+                Ok(result) => result,
+                Err(_elapsed) => {
+                    println!("AWS ERROR: storage call timed out after {:?}", self.timeout);
+                    Err(String::from("ERR_STORAGE_TIMEOUT"))
+                }
+            }
+        })
+    }
+}
+
+// One bucket on a shared connection. Any S3-compatible service works, defaults are the
+// DigitalOcean Space PSU runs on.
+pub struct S3Store {
+    bucket: String,
+    connection: Arc<S3Connection>,
+}
+
+impl S3Store {
+    pub fn new(bucket: String, connection: Arc<S3Connection>) -> Self {
+        S3Store { bucket, connection }
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn put(&self, key: &str, data: Vec<u8>, options: &PutOptions) -> Result<(), String> {
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            body: Some(data.into()),
+            content_type: options.content_type.clone(),
+            cache_control: options.cache_control.clone(),
+            ..Default::default()
+        };
+
+        self.connection.run(async {
+            match self.connection.client.put_object(request).await {
+                Ok(_data) => Ok(()),
+                Err(err) => Err(storage_error(err)),
+            }
+        })
+    }
+
+    // The reader is blocking, so it's drained on its own thread into a channel the request body
+    // pulls from. Only the chunks in flight are ever in memory.
+    fn put_stream(&self, key: &str, reader: Box<dyn Read + Send>, size: u64, options: &PutOptions) -> Result<String, String> {
+        let (sender, mut receiver) = mpsc::channel::<io::Result<Vec<u8>>>(UPLOAD_CHUNKS_IN_FLIGHT);
+
+        let producer = thread::spawn(move || {
+            let mut reader = HashingReader::new(reader);
+
+            loop {
+                let mut chunk = vec![0; UPLOAD_CHUNK_BYTES];
+
+                match reader.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        chunk.truncate(read);
+
+                        // The request gave up, it has already reported why.
+                        if sender.blocking_send(Ok(chunk)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        println!("STORAGE ERROR: {}", err);
+                        let _ = sender.blocking_send(Err(err));
+                        return Err(String::from("ERR_STORAGE_ERR"));
+                    }
+                }
+            }
+
+            reader.finish(size)
+        });
+
+        let body = stream::poll_fn(move |cx| receiver.poll_recv(cx)).map_ok(|chunk| chunk.into());
+
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            body: Some(ByteStream::new_with_size(body, size as usize)),
+            content_length: Some(size as i64),
+            content_type: options.content_type.clone(),
+            cache_control: options.cache_control.clone(),
+            ..Default::default()
+        };
+
+        let uploaded = self.connection.run(async {
+            match self.connection.client.put_object(request).await {
+                Ok(_data) => Ok(()),
+                Err(err) => Err(storage_error(err)),
+            }
+        });
+
+        let sha256 = match producer.join() {
+            Ok(data) => data,
+            Err(_panic) => Err(String::from("ERR_STORAGE_ERR")),
+        };
+
+        match (uploaded, sha256) {
+            (Ok(()), Ok(sha256)) => Ok(sha256),
+            (Err(err), _) => Err(err),
+            // S3 took a body that wasn't the one we meant to store.
+            (Ok(()), Err(err)) => {
+                let _ = self.delete(key);
+                Err(err)
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        self.connection.run(async {
+            let output = match self.connection.client.get_object(request).await {
+                Ok(data) => data,
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                    return Err(String::from(ERR_NOT_FOUND))
+                }
+                Err(err) => return Err(storage_error(err)),
+            };
+
+            let byte_stream = match output.body {
+                Some(data) => data,
+                None => return Err(String::from(ERR_NOT_FOUND)),
+            };
+
+            // Read on the runtime too, the body streams over the same pooled connection.
+            let mut buffer = Vec::new();
+
+            match byte_stream.into_async_read().read_to_end(&mut buffer).await {
+                Ok(_data) => Ok(buffer),
+                Err(err) => Err(storage_error(err)),
+            }
+        })
+    }
+
+    // Only the request itself runs under the concurrency limit and timeout. The body is read as
+    // the client downloads it, which can rightly take longer than any single storage call.
+    fn get_range(&self, key: &str, range: Option<(u64, u64)>) -> Result<Box<dyn Read + Send>, String> {
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            range: range.map(|(start, end)| format!("bytes={}-{}", start, end)),
+            ..Default::default()
+        };
+
+        let output = self.connection.run(async {
+            match self.connection.client.get_object(request).await {
+                Ok(data) => Ok(data),
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                    Err(String::from(ERR_NOT_FOUND))
+                }
+                Err(err) => Err(storage_error(err)),
+            }
+        })?;
+
+        match output.body {
+            Some(data) => Ok(Box::new(data.into_blocking_read())),
+            None => Err(String::from(ERR_NOT_FOUND)),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        self.connection.run(async {
+            match self.connection.client.delete_object(request).await {
+                Ok(_data) => Ok(()),
+                Err(err) => Err(storage_error(err)),
+            }
+        })
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, String> {
+        let mut found: Vec<ObjectInfo> = Default::default();
+        let mut continuation_token: Option<String> = None;
+
+        // Results come a page (up to 1000 keys) at a time, each page is its own call.
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(prefix.to_string()),
+                continuation_token: continuation_token.clone(),
+                ..Default::default()
+            };
+
+            let page = self.connection.run(async {
+                match self.connection.client.list_objects_v2(request).await {
+                    Ok(data) => Ok(data),
+                    Err(err) => Err(storage_error(err)),
+                }
+            })?;
+
+            for object in page.contents.unwrap_or_default() {
+                if let Some(key) = object.key {
+                    found.push(ObjectInfo {
+                        key,
+                        size: object.size.unwrap_or(0) as u64,
+                        content_type: None,
+                        last_modified: parse_rfc3339(object.last_modified),
+                    });
+                }
+            }
+
+            match (page.is_truncated, page.next_continuation_token) {
+                (Some(true), Some(token)) => continuation_token = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(found)
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>, String> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        self.connection.run(async {
+            match self.connection.client.head_object(request).await {
+                Ok(data) => Ok(Some(ObjectInfo {
+                    key: key.to_string(),
+                    size: data.content_length.unwrap_or(0) as u64,
+                    content_type: data.content_type,
+                    last_modified: parse_rfc2822(data.last_modified),
+                })),
+                // HEAD responses have no body, so a missing key only shows up as the status code.
+                Err(RusotoError::Unknown(ref response)) if response.status.as_u16() == 404 => {
+                    Ok(None)
+                }
+                Err(err) => Err(storage_error(err)),
+            }
+        })
+    }
+
+    fn presign(&self, key: &str, expires_in: Duration) -> Result<String, String> {
+        let credentials = self.connection.run(async {
+            match self.connection.credentials.credentials().await {
+                Ok(data) => Ok(data),
+                Err(err) => Err(storage_error(err)),
+            }
+        })?;
+
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        Ok(request.get_presigned_url(
+            &self.connection.region,
+            &credentials,
+            &PreSignedRequestOption { expires_in },
+        ))
+    }
+}
@@ -0,0 +1,65 @@
+use crate::modules::account_services::{
+    account_deletion, api_metering, login_alerts, login_protection, passwords, sessions, two_factor,
+};
+use crate::modules::{config, discord_sync};
+
+use postgres::{Connection, TlsMode};
+use std::time::Duration;
+
+use colored::*;
+
+// Periodic work owned by one feature. Each job runs on its own thread and connection, so one
+// that is slow or failing doesn't hold up the rest.
+pub struct Job {
+    // Also names the setting that overrides the interval, `<NAME>_INTERVAL_SECS`.
+    pub name: &'static str,
+    // What the count returned by `run` is, for the log line.
+    pub counts: &'static str,
+    pub default_interval_secs: u64,
+    pub run: fn(&Connection) -> Result<u64, String>,
+}
+
+// Every scheduled job in the backend. New periodic work gets its own entry here.
+const JOBS: &[Job] = &[
+    sessions::PURGE_JOB,
+    two_factor::PURGE_JOB,
+    login_protection::PURGE_JOB,
+    passwords::PURGE_JOB,
+    login_alerts::PURGE_JOB,
+    api_metering::RESET_JOB,
+    account_deletion::DELETION_JOB,
+];
+
+fn spawn(job: &'static Job) {
+    let key = format!("{}_INTERVAL_SECS", job.name);
+    let interval = Duration::from_secs(config::env_or::<u64>(&key, job.default_interval_secs));
+
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+
+        let conn = match Connection::connect(std::env::var("DATABASE_URL").unwrap(), TlsMode::None)
+        {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("[{}] Failed to connect: {}", job.name.red(), err);
+                continue;
+            }
+        };
+
+        match (job.run)(&conn) {
+            Ok(0) => (),
+            Ok(count) => println!("[{}] {}: {}", job.name.blue(), job.counts, count),
+            Err(err) => println!("[{}] Failed: {}", job.name.red(), err),
+        };
+    });
+}
+
+// Starts every scheduled job, plus the Discord role sync which also reacts to its queue.
+// Call once at startup.
+pub fn start() {
+    for job in JOBS {
+        spawn(job);
+    }
+
+    discord_sync::spawn_role_sync();
+}
@@ -1,30 +1,32 @@
 use multipart::server::{
-    save::{SaveResult::*, SavedData, SavedField},
+    save::{Entries, PartialReason, SaveResult::*, SavedData, SavedField, TempDir},
     Multipart,
 };
 use postgres::rows::Rows;
 use rocket::data::Data;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
 
 use lazy_static::lazy_static;
 use regex::Regex;
 
 use std::io::prelude::*;
 
-use rusoto_core::{credential::ChainProvider, request::HttpClient, Region};
-use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3};
-
-use std::io::{Error, ErrorKind};
+use std::io::{BufReader, Cursor, Error, ErrorKind};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::runtime::Runtime;
 
-use crate::modules::account_services;
+use crate::modules::account_services::{self, email_verification};
+use crate::modules::config;
+use crate::modules::lua_syntax::{self, Diagnostic};
+use crate::modules::object_store::{self, PutOptions};
 use crate::MainPGDatabase;
 
 use nanoid::nanoid;
 
+pub mod versions;
+
 pub fn field_to_string(lmao: &SavedField) -> Result<String, Error> {
     let data: String = match &lmao.data {
         SavedData::Text(data) => data.to_owned(),
@@ -35,13 +37,67 @@ pub fn field_to_string(lmao: &SavedField) -> Result<String, Error> {
     Ok(data)
 }
 
-pub fn field_to_file(field: &SavedField) -> Result<Vec<u8>, Error> {
-    let mut buffer: Vec<u8> = Default::default();
-    let mut data = field.data.readable()?;
+pub fn optional_field_string(
+    fields: &HashMap<Arc<str>, Vec<SavedField>>,
+    name: &str,
+) -> Option<String> {
+    match fields.get(name) {
+        Some(data) => field_to_string(&data[0]).ok(),
+        None => None,
+    }
+}
+
+// An uploaded file, left wherever multipart saved it. Anything but the smallest upload is a
+// temporary file, which is read from each time it's needed instead of being copied into memory.
+#[derive(Debug)]
+pub enum Upload {
+    File(PathBuf, u64),
+    Memory(Vec<u8>),
+}
+
+impl Upload {
+    pub fn size(&self) -> u64 {
+        match self {
+            Upload::File(_path, size) => *size,
+            Upload::Memory(data) => data.len() as u64,
+        }
+    }
+
+    // Owned, so the storage backend can read it from another thread.
+    pub fn open(&self) -> Result<Box<dyn Read + Send>, Error> {
+        match self {
+            Upload::File(path, _size) => Ok(Box::new(BufReader::new(fs::File::open(path)?))),
+            Upload::Memory(data) => Ok(Box::new(Cursor::new(data.clone()))),
+        }
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            Upload::File(path, _size) => fs::read(path),
+            Upload::Memory(data) => Ok(data.clone()),
+        }
+    }
+}
+
+pub fn field_to_file(field: &SavedField) -> Upload {
+    match &field.data {
+        SavedData::File(path, size) => Upload::File(path.clone(), *size),
+        SavedData::Bytes(data) => Upload::Memory(data.clone()),
+        SavedData::Text(data) => Upload::Memory(data.clone().into_bytes()),
+    }
+}
 
-    data.read_to_end(&mut buffer)?;
+// Streams an upload into the scripts bucket and returns its SHA-256.
+pub fn store_upload(key: &str, upload: &Upload) -> Result<String, String> {
+    let reader = match upload.open() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("UPLOAD ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
 
-    return Ok(buffer);
+    object_store::scripts().put_stream(key, reader, upload.size(), &PutOptions::default())
 }
 
 pub fn field_to_bool(lmao: &SavedField) -> Result<bool, Error> {
@@ -63,8 +119,7 @@ pub struct Script {
     pub title: String,
     pub description: String,
     pub public: bool,
-    pub token: String,
-    pub file: Vec<u8>,
+    pub file: Upload,
 }
 
 #[derive(Debug, Serialize)]
@@ -105,14 +160,8 @@ impl Script {
                     return Err(Error::new(ErrorKind::Other, "File Field not supplied!"));
                 }
             },
-            token: match fields.get("token") {
-                Some(data) => field_to_string(&data[0])?,
-                None => {
-                    return Err(Error::new(ErrorKind::Other, "File Field not supplied!"));
-                }
-            },
             file: match fields.get("file") {
-                Some(data) => field_to_file(&data[0])?,
+                Some(data) => field_to_file(&data[0]),
                 None => {
                     return Err(Error::new(ErrorKind::Other, "File Field not supplied!"));
                 }
@@ -121,16 +170,69 @@ impl Script {
     }
 }
 
+// Largest script source accepted, by the owner's tier.
+pub fn max_script_bytes(user_id: &String, conn: &MainPGDatabase) -> u64 {
+    match account_services::has_premium(user_id, conn) {
+        Some(_expires_at) => config::env_or("SCRIPT_MAX_BYTES_PREMIUM", 10 * 1024 * 1024),
+        None => config::env_or("SCRIPT_MAX_BYTES_FREE", 1024 * 1024),
+    }
+}
+
+// Uploads are parsed before we know who sent them, so parsing allows the larger tier and
+// `check_size` applies the owner's actual limit afterwards.
+pub fn upload_limit() -> u64 {
+    config::env_or::<u64>("SCRIPT_MAX_BYTES_PREMIUM", 10 * 1024 * 1024)
+        .max(config::env_or("SCRIPT_MAX_BYTES_FREE", 1024 * 1024))
+}
+
+// Why saving a script failed. Syntax errors keep their position so editors can point at it,
+// everything else is one of the usual error codes.
+#[derive(Debug)]
+pub enum SaveError {
+    Message(String),
+    Syntax(Diagnostic),
+}
+
+impl From<String> for SaveError {
+    fn from(message: String) -> Self {
+        SaveError::Message(message)
+    }
+}
+
+// Broken sources used to be stored as-is and only fail once someone tried to obfuscate them.
+fn check_syntax(source: &[u8]) -> Result<(), SaveError> {
+    lua_syntax::check(source).map_err(SaveError::Syntax)
+}
+
+fn check_size(owner_id: &String, size: u64, conn: &MainPGDatabase) -> Result<(), String> {
+    if size > max_script_bytes(owner_id, conn) {
+        return Err(String::from("ERR_SCRIPT_TOO_LARGE"));
+    }
+
+    Ok(())
+}
+
+// The parser needs the whole source, so it's read for the check and dropped again before the
+// upload is streamed into storage.
+fn check_upload(owner_id: &String, upload: &Upload, conn: &MainPGDatabase) -> Result<(), SaveError> {
+    check_size(owner_id, upload.size(), conn)?;
+
+    let source = match upload.read() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{}", err);
+            return Err(String::from("Something went wrong processing the file.").into());
+        }
+    };
+
+    check_syntax(&source)
+}
+
 pub fn delete_script(
     conn: &MainPGDatabase,
     script_id: &str,
-    token: &String,
+    user_id: &String,
 ) -> Result<String, String> {
-    let user_id = match account_services::is_authenticated(token, conn) {
-        Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
     // Get the script and check the user can delete it.
     let rows_recieved: Rows = match conn.query(
         r#"SELECT * FROM lunar_buffxnte_psu.scripts WHERE id = $1 LIMIT 1"#,
@@ -149,7 +251,7 @@ pub fn delete_script(
 
     let script_owner_id: String = rows_recieved.get(0).get("belongs_to");
 
-    if script_owner_id != user_id {
+    if &script_owner_id != user_id {
         return Err(String::from("ERR_INVALID_AUTH"));
     };
 
@@ -165,7 +267,15 @@ pub fn delete_script(
         }
     };
 
-    match delete_object_aws(script_id.to_owned()) {
+    match object_store::scripts().delete(script_id) {
+        Ok(_data) => (),
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(String::from("ERR_AWS_ERR"));
+        }
+    };
+
+    match versions::delete_all(&script_id.to_owned(), conn) {
         Ok(_data) => (),
         Err(err) => {
             println!("{:?}", err);
@@ -178,15 +288,9 @@ pub fn delete_script(
 
 pub fn update_public_script(
     conn: &MainPGDatabase,
-    token: &str,
+    user_id: &String,
     script_id: &str,
 ) -> Result<String, String> {
-    // Check Auth
-    let user_id = match account_services::is_authenticated(&token.to_owned(), conn) {
-        Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
     // Check if UUID is valid
     lazy_static! {
         static ref RE: Regex =
@@ -215,7 +319,7 @@ pub fn update_public_script(
 
     let script_owner: String = rows_recieved.get(0).get("belongs_to");
 
-    if user_id != script_owner {
+    if user_id != &script_owner {
         return Err(String::from("ERR_AUTH_FAILED"));
     };
 
@@ -226,6 +330,8 @@ pub fn update_public_script(
         return Err(String::from("ERR_SCRIPT_NOT_PUBLIC"));
     }
 
+    email_verification::ensure_verified(user_id, conn)?;
+
     // Delete all instances of previous public script with this ID
     match conn.execute(
         "DELETE FROM lunar_buffxnte_psu.public_scripts WHERE id = $1;",
@@ -251,16 +357,7 @@ pub fn update_public_script(
     };
 }
 
-pub fn get_public_scripts(
-    conn: &MainPGDatabase,
-    token: &String,
-) -> Result<Vec<PubSafeScript>, String> {
-    // Check auth
-    match account_services::is_authenticated(&token, conn) {
-        Ok(_) => (),
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
+pub fn get_public_scripts(conn: &MainPGDatabase) -> Result<Vec<PubSafeScript>, String> {
     let raw_public_scripts: Rows = match conn.query(
         "SELECT id, location FROM lunar_buffxnte_psu.public_scripts;",
         &[],
@@ -309,39 +406,41 @@ pub fn get_public_scripts(
 
 pub fn create_new_script(
     conn: &MainPGDatabase,
+    user_id: &String,
     script: Script,
-    _boundary: &str,
-) -> Result<String, String> {
-    // Check auth
-    let user_id = match account_services::is_authenticated(&script.token, conn) {
-        Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
+) -> Result<String, SaveError> {
     // See how many scripts the user already has
-    let current_scripts = match get_private_scripts(&script.token, conn) {
+    let current_scripts = match get_private_scripts(user_id, conn) {
         Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
+        Err(_err) => return Err(String::from("ERR_AUTH_FAILED").into()),
     };
 
     if current_scripts.len() >= 50 {
-        return Err(String::from("ERR_MAX_SCRIPTS_EXCEEDED"));
+        return Err(String::from("ERR_MAX_SCRIPTS_EXCEEDED").into());
     }
 
-    // Upload Script to AWS and get ID
-    let script_id = match process_upload_aws(script.file, None) {
+    if script.public {
+        email_verification::ensure_verified(user_id, conn)?;
+    }
+
+    check_upload(user_id, &script.file, conn)?;
+
+    let script_id = nanoid!();
+
+    // Upload the source first, the database entry is what makes it visible.
+    let sha256 = match store_upload(&script_id, &script.file) {
         Ok(data) => data,
         Err(err) => {
             println!("{}", err);
-            return Err(String::from("ERR_INTERNAL_ERR"));
+            return Err(String::from("ERR_INTERNAL_ERR").into());
         }
     };
 
     // Now create a entry in our database
     match conn.execute(
         r#"INSERT INTO lunar_buffxnte_psu.scripts(
-      title, description, updated_at, created_at, public, "belongs_to", id)
-      VALUES ($1, $2, $3, $4, $5, $6, $7);"#,
+      title, description, updated_at, created_at, public, "belongs_to", id, sha256, size_bytes)
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9);"#,
         &[
             &script.title,
             &script.description,
@@ -350,26 +449,47 @@ pub fn create_new_script(
             &script.public,
             &user_id,
             &script_id,
+            &sha256,
+            &(script.file.size() as i64),
         ],
     ) {
-        Ok(_data) => return Ok(String::from(&script_id)),
+        Ok(_data) => (),
         Err(err) => {
             println!("{}", err);
-            return Err(String::from("Something went wrong creating the script"));
+            return Err(String::from("Something went wrong creating the script").into());
         }
     };
+
+    // The script exists either way, its history starts at the next save if this fails.
+    match versions::record_initial(&script_id, user_id, &script.file, conn) {
+        Ok(_data) => (),
+        Err(err) => println!("Failed to record first version of {}: {}", script_id, err),
+    };
+
+    Ok(script_id)
 }
 
-pub fn process_multipart(
-    boundary: &str,
-    data: Data,
-) -> Result<HashMap<Arc<str>, Vec<SavedField>>, String> {
-    match Multipart::with_body(data.open(), boundary)
+// Larger files are spooled to a temporary directory under ./temp/ instead of memory. That
+// directory belongs to the returned entries and is removed when they're dropped, so keep them
+// around for as long as the fields are used. `size_limit` applies to each file and, with a
+// little room for the other fields, to the request body as a whole.
+pub fn process_multipart(boundary: &str, data: Data, size_limit: u64) -> Result<Entries, String> {
+    let temp_dir = match TempDir::new_in("./temp/") {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Failed to create upload directory: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    match Multipart::with_body(data.open().take(size_limit + 64 * 1024), boundary)
         .save()
+        .size_limit(size_limit)
         .force_text()
-        .with_dir("./temp/")
+        .with_temp_dir(temp_dir)
     {
-        Full(entries) => return Ok(entries.fields),
+        Full(entries) => return Ok(entries),
+        Partial(_partial, PartialReason::SizeLimit) => Err(String::from("ERR_UPLOAD_TOO_LARGE")),
         Partial(partial, reason) => {
             println!("Request partially processed: {:?}", reason);
             if let Some(field) = partial.partial {
@@ -384,119 +504,10 @@ pub fn process_multipart(
     }
 }
 
-pub fn delete_object_aws(script_id: String) -> Result<String, String> {
-    let mut chain = ChainProvider::new();
-    chain.set_timeout(Duration::from_millis(200));
-
-    let s3cli = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        chain,
-        Region::Custom {
-            name: "nyc-3".to_owned(),
-            endpoint: "https://psu.sfo3.digitaloceanspaces.com".to_owned(),
-        },
-    );
-
-    let rt = match Runtime::new() {
-        Ok(runtime) => runtime,
-        Err(err) => {
-            println!("Tokio Runtime Error: {}", err);
-            return Err(String::from(
-                "Tokio Runtime Error. Please notify the administrator.",
-            ));
-        }
-    };
-
-    let result = rt.block_on(async {
-        let test = s3cli
-            .delete_object(DeleteObjectRequest {
-                bucket: String::from("psu-scripts-bucket"),
-                key: format!("{}", script_id),
-                ..Default::default()
-            })
-            .await;
-
-        test
-    });
-
-    match result {
-        Ok(_data) => Ok(script_id),
-        Err(err) => {
-            println!("AWS ERROR: {}", err);
-            Err(String::from(
-                "A AWS Error occoured. Please notify the administrator.",
-            ))
-        }
-    }
-}
-
-pub fn process_upload_aws(data: Vec<u8>, script_id: Option<String>) -> Result<String, String> {
-    println!("testing");
-    let mut chain = ChainProvider::new();
-    chain.set_timeout(Duration::from_millis(200));
-
-    let s3cli = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        chain,
-        Region::Custom {
-            name: "nyc-3".to_owned(),
-            endpoint: "https://psu.sfo3.digitaloceanspaces.com".to_owned(),
-        },
-    );
-
-    let id;
-
-    match script_id {
-        Some(script_id) => id = script_id,
-        None => {
-            id = nanoid!();
-        }
-    }
-
-    // BAD DUMB ASS CODE. RESULT OF NEEDING TO EXECUTE ASYNC CODE IN NON-ASYNC FUNCTIONS. DUE TO ROCKET NOT SUPPORTING ASYNC ROUTES.
-    let rt = match Runtime::new() {
-        Ok(runtime) => runtime,
-        Err(err) => {
-            println!("Tokio Runtime Error: {}", err);
-            return Err(String::from(
-                "Tokio Runtime Error. Please notify the administrator.",
-            ));
-        }
-    };
-
-    let result = rt.block_on(async {
-        let test = s3cli
-            .put_object(PutObjectRequest {
-                bucket: String::from("psu-scripts-bucket"),
-                key: format!("{}", id),
-                body: Some(data.into()),
-                ..Default::default()
-            })
-            .await;
-
-        test
-    });
-
-    match result {
-        Ok(_data) => Ok(id),
-        Err(err) => {
-            println!("AWS ERROR: {}", err);
-            Err(String::from(
-                "A AWS Error occoured. Please notify the administrator.",
-            ))
-        }
-    }
-}
-
 pub fn get_private_scripts(
-    token: &String,
+    user_id: &String,
     conn: &MainPGDatabase,
 ) -> Result<Vec<SafeScript>, String> {
-    let user_id = match account_services::is_authenticated(token, conn) {
-        Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
     let rows_recieved: Rows = match conn.query(
         r#"SELECT * FROM lunar_buffxnte_psu.scripts WHERE "belongs_to" = $1"#,
         &[&user_id],
@@ -523,56 +534,30 @@ pub fn get_private_scripts(
     Ok(scripts)
 }
 
+// Returns the number of the version the upload was saved as.
 pub fn update_script(
-    boundary: &str,
+    user_id: &String,
+    multipart_data: &HashMap<Arc<str>, Vec<SavedField>>,
     conn: &MainPGDatabase,
-    script: Data,
-) -> Result<String, String> {
-    let multipart_data = match process_multipart(boundary, script) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("{}", err);
-            return Err(String::from("Something went wrong parsing multipart data."));
-        }
-    };
-
+) -> Result<i32, SaveError> {
     let file_field = match multipart_data.get("file") {
         Some(data) => data,
-        None => return Err(String::from("No file field was recieved")),
-    };
-
-    let token_field = match multipart_data.get("token") {
-        Some(data) => data,
-        None => return Err(String::from("No token field was recieved")),
-    };
-
-    let token = match field_to_string(&token_field[0]) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("{}", err);
-            return Err(String::from("Something went wrong processing the file."));
-        }
+        None => return Err(String::from("No file field was recieved").into()),
     };
 
     let script_id_field = match multipart_data.get("scriptID") {
         Some(data) => data,
-        None => return Err(String::from("No scriptID field was recieved")),
+        None => return Err(String::from("No scriptID field was recieved").into()),
     };
 
     let script_id = match field_to_string(&script_id_field[0]) {
         Ok(data) => data,
         Err(err) => {
             println!("{}", err);
-            return Err(String::from("Something went wrong processing the file."));
+            return Err(String::from("Something went wrong processing the file.").into());
         }
     };
 
-    // Check user's auth.
-    let user_id = match account_services::is_authenticated(&token, conn) {
-        Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
     let rows_recieved: Rows = match conn.query(
         r#"SELECT * FROM lunar_buffxnte_psu.scripts WHERE id = $1 LIMIT 1"#,
         &[&script_id],
@@ -580,47 +565,32 @@ pub fn update_script(
         Ok(data) => data,
         Err(err) => {
             println!("{:?}", err);
-            return Err(String::from("ERR_INTERNAL_ERR"));
+            return Err(String::from("ERR_INTERNAL_ERR").into());
         }
     };
 
     if rows_recieved.len() < 1 {
-        return Err(String::from("Script doesn't exist"));
+        return Err(String::from("Script doesn't exist").into());
     }
 
     let script_owner: String = rows_recieved.get(0).get("belongs_to");
 
-    if user_id != script_owner {
-        return Err(String::from("ERR_AUTH_FAILED"));
+    if user_id != &script_owner {
+        return Err(String::from("ERR_AUTH_FAILED").into());
     };
 
-    let file = match field_to_file(&file_field[0]) {
-        Ok(data) => data,
-        Err(err) => {
-            println!("{}", err);
-            return Err(String::from("Something went wrong processing the file."));
-        }
-    };
+    let file = field_to_file(&file_field[0]);
 
-    match process_upload_aws(file, Some(script_id)) {
-        Ok(_data) => return Ok(String::from("SUCCESS")),
-        Err(err) => {
-            println!("AWS ERROR: {}", err);
-            return Err(String::from("AWS ERROR! Please contact the administrator."));
-        }
-    };
+    check_upload(&script_owner, &file, conn)?;
+
+    Ok(versions::commit(&script_id, &script_owner, user_id, file, None, conn)?)
 }
 
 pub fn get_script(
-    token: &String,
+    user_id: &String,
     script_id: &String,
     conn: &MainPGDatabase,
 ) -> Result<Vec<u8>, String> {
-    let user_id = match account_services::is_authenticated(token, conn) {
-        Ok(data) => data,
-        Err(_err) => return Err(String::from("ERR_AUTH_FAILED")),
-    };
-
     let rows_recieved: Rows = match conn.query(
         r#"SELECT * FROM lunar_buffxnte_psu.scripts WHERE id = $1 LIMIT 1"#,
         &[&script_id],
@@ -638,70 +608,62 @@ pub fn get_script(
 
     let script_owner: String = rows_recieved.get(0).get("belongs_to");
 
-    if user_id != script_owner {
+    if user_id != &script_owner {
         return Err(String::from("ERR_AUTH_FAILED"));
     };
 
-    let mut chain = ChainProvider::new();
-    chain.set_timeout(Duration::from_millis(200));
+    object_store::scripts().get(script_id)
+}
 
-    let s3cli = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        chain,
-        Region::Custom {
-            name: "nyc-3".to_owned(),
-            endpoint: "https://psu.sfo3.digitaloceanspaces.com".to_owned(),
-        },
-    );
+// What a download needs to know before any bytes are read: where the object is, its size
+// for Range requests and its SHA-256 for the ETag. Older scripts may not have a hash yet.
+pub struct ScriptObject {
+    pub key: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+}
 
-    // Now get the file
-    let rt = match Runtime::new() {
-        Ok(runtime) => runtime,
+pub fn script_object(user_id: &String, script_id: &String, conn: &MainPGDatabase) -> Result<ScriptObject, String> {
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT "belongs_to", sha256, size_bytes FROM lunar_buffxnte_psu.scripts WHERE id = $1 LIMIT 1"#,
+        &[&script_id],
+    ) {
+        Ok(data) => data,
         Err(err) => {
-            println!("Tokio Runtime Error: {}", err);
-            return Err(String::from(
-                "Tokio Runtime Error. Please notify the administrator.",
-            ));
+            println!("{:?}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
         }
     };
 
-    let result = rt.block_on(async {
-        let request = s3cli
-            .get_object(GetObjectRequest {
-                key: script_id.to_owned(),
-                bucket: String::from("psu-scripts-bucket"),
-                ..Default::default()
-            })
-            .await;
+    if rows_recieved.len() < 1 {
+        return Err(String::from("Script doesn't exist"));
+    }
 
-        request
-    });
+    let script_owner: String = rows_recieved.get(0).get("belongs_to");
 
-    let data = match result {
-        Ok(data) => data,
-        Err(err) => {
-            println!("AWS ERROR: {}", err);
-            return Err(String::from(
-                "A AWS Error occoured. Please notify the administrator.",
-            ));
-        }
+    if user_id != &script_owner {
+        return Err(String::from("ERR_AUTH_FAILED"));
     };
 
-    let byte_stream = match data.body {
-        Some(data) => data,
-        None => return Err(String::from("Script doesn't exist")),
-    };
+    let sha256: Option<String> = rows_recieved.get(0).get("sha256");
+    let size_bytes: Option<i64> = rows_recieved.get(0).get("size_bytes");
 
-    let mut buffer = Vec::new();
-    match byte_stream.into_blocking_read().read_to_end(&mut buffer) {
-        Ok(_data) => {}
-        Err(err) => {
-            println!("ERROR: {}", err);
-            return Err(String::from(
-                "Something went wrong recieving the file from AWS",
-            ));
-        }
+    let size = match size_bytes {
+        Some(size) => size as u64,
+        None => match object_store::scripts().head(script_id)? {
+            Some(info) => info.size,
+            None => return Err(String::from("Script doesn't exist")),
+        },
     };
 
-    Ok(buffer.iter().cloned().collect())
+    Ok(ScriptObject {
+        key: script_id.clone(),
+        size,
+        sha256,
+    })
+}
+
+// `range` is inclusive on both ends and has already been checked against the object's size.
+pub fn open_object(object: &ScriptObject, range: Option<(u64, u64)>) -> Result<Box<dyn Read + Send>, String> {
+    object_store::scripts().get_range(&object.key, range)
 }
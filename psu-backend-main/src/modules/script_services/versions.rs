@@ -0,0 +1,406 @@
+use crate::modules::object_store;
+use crate::modules::script_services::{self, ScriptObject, Upload};
+use crate::modules::{account_services, config};
+use crate::MainPGDatabase;
+
+use nanoid::nanoid;
+use postgres::rows::Rows;
+use postgres::Connection;
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+
+// Every save is kept as its own object under versions/, the object at the script's id is
+// always a copy of the newest version so existing readers don't have to know about any of this.
+
+#[derive(Debug, Serialize)]
+pub struct ScriptVersion {
+    pub version: i32,
+    pub author_id: String,
+    pub size_bytes: i64,
+    pub sha256: String,
+    // Set when this version was made by restoring an older one.
+    pub restored_from: Option<i32>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub head: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScriptDiff {
+    pub from: i32,
+    pub to: i32,
+    pub added: usize,
+    pub removed: usize,
+    pub diff: String,
+}
+
+// How many versions are kept per script, oldest are dropped first. The newest one is always kept.
+fn retention_limit(owner_id: &String, conn: &MainPGDatabase) -> i64 {
+    let limit: i64 = match account_services::has_premium(owner_id, conn) {
+        Some(_expires_at) => config::env_or("SCRIPT_VERSIONS_PREMIUM", 100),
+        None => config::env_or("SCRIPT_VERSIONS_FREE", 10),
+    };
+
+    limit.max(1)
+}
+
+// Random rather than numbered, so two saves racing for the same version number can never
+// write over each other's object.
+fn new_object_key(script_id: &String) -> String {
+    format!("versions/{}/{}", script_id, nanoid!())
+}
+
+// Returns the owner, so callers don't have to look the script up twice.
+fn check_owner(user_id: &String, script_id: &String, conn: &MainPGDatabase) -> Result<String, String> {
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT "belongs_to" FROM lunar_buffxnte_psu.scripts WHERE id = $1 LIMIT 1"#,
+        &[&script_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.len() < 1 {
+        return Err(String::from("Script doesn't exist"));
+    }
+
+    let script_owner: String = rows_recieved.get(0).get("belongs_to");
+
+    if user_id != &script_owner {
+        return Err(String::from("ERR_AUTH_FAILED"));
+    };
+
+    Ok(script_owner)
+}
+
+fn latest_version(script_id: &String, conn: &MainPGDatabase) -> Result<i32, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT COALESCE(MAX(version), 0) AS latest FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1;",
+        &[&script_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    Ok(rows_recieved.get(0).get("latest"))
+}
+
+fn store_version(
+    script_id: &String,
+    version: i32,
+    author_id: &String,
+    data: &Upload,
+    restored_from: Option<i32>,
+    conn: &MainPGDatabase,
+) -> Result<String, String> {
+    let key = new_object_key(script_id);
+
+    let sha256 = match script_services::store_upload(&key, data) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("STORAGE ERROR: {}", err);
+            return Err(String::from("AWS ERROR! Please contact the administrator."));
+        }
+    };
+
+    // The unique (script_id, version) key is what stops two saves racing for the same number.
+    match conn.execute(
+        r#"INSERT INTO lunar_buffxnte_psu.script_versions(script_id, version, author_id, size_bytes, sha256, restored_from, object_key, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8);"#,
+        &[
+            &script_id,
+            &version,
+            &author_id,
+            &(data.size() as i64),
+            &sha256,
+            &restored_from,
+            &key,
+            &chrono::Utc::now(),
+        ],
+    ) {
+        Ok(_data) => Ok(key),
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+
+            match object_store::scripts().delete(&key) {
+                Ok(_data) => (),
+                Err(err) => println!("Failed to clean up unused script version: {}", err),
+            };
+
+            Err(String::from("The script was saved somewhere else at the same time, try again"))
+        }
+    }
+}
+
+// New scripts start their history with the uploaded source.
+pub fn record_initial(script_id: &String, author_id: &String, data: &Upload, conn: &MainPGDatabase) -> Result<(), String> {
+    store_version(script_id, 1, author_id, data, None, conn).map(|_key| ())
+}
+
+// Scripts from before versioning have no history, so the current source becomes version 1
+// before it gets replaced.
+fn ensure_history(script_id: &String, owner_id: &String, conn: &MainPGDatabase) -> Result<(), String> {
+    if latest_version(script_id, conn)? > 0 {
+        return Ok(());
+    }
+
+    let current = object_store::scripts().get(script_id)?;
+
+    record_initial(script_id, owner_id, &Upload::Memory(current), conn)
+}
+
+// Stores `data` as the next version and makes it the script's current source.
+pub fn commit(
+    script_id: &String,
+    owner_id: &String,
+    author_id: &String,
+    data: Upload,
+    restored_from: Option<i32>,
+    conn: &MainPGDatabase,
+) -> Result<i32, String> {
+    ensure_history(script_id, owner_id, conn)?;
+
+    let version = latest_version(script_id, conn)? + 1;
+
+    let key = store_version(script_id, version, author_id, &data, restored_from, conn)?;
+    let size_bytes = data.size() as i64;
+
+    let sha256 = match script_services::store_upload(script_id, &data) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("STORAGE ERROR: {}", err);
+
+            // Don't leave a version behind that never became the current source.
+            match conn.execute(
+                "DELETE FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1 AND version = $2;",
+                &[&script_id, &version],
+            ) {
+                Ok(_data) => (),
+                Err(err) => println!("SQL ERROR: {}", err),
+            };
+
+            match object_store::scripts().delete(&key) {
+                Ok(_data) => (),
+                Err(err) => println!("Failed to clean up unused script version: {}", err),
+            };
+
+            return Err(String::from("AWS ERROR! Please contact the administrator."));
+        }
+    };
+
+    match conn.execute(
+        "UPDATE lunar_buffxnte_psu.scripts SET updated_at = $1, sha256 = $2, size_bytes = $3 WHERE id = $4;",
+        &[&chrono::Utc::now(), &sha256, &size_bytes, &script_id],
+    ) {
+        Ok(_data) => (),
+        Err(err) => println!("SQL ERROR: {}", err),
+    };
+
+    match prune(script_id, owner_id, conn) {
+        Ok(_data) => (),
+        Err(err) => println!("Failed to prune old versions of {}: {}", script_id, err),
+    };
+
+    Ok(version)
+}
+
+fn prune(script_id: &String, owner_id: &String, conn: &MainPGDatabase) -> Result<(), String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT version, object_key FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1 ORDER BY version DESC OFFSET $2;",
+        &[&script_id, &retention_limit(owner_id, conn)],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    for row in &rows_recieved {
+        let version: i32 = row.get("version");
+
+        let key: String = row.get("object_key");
+        object_store::scripts().delete(&key)?;
+
+        match conn.execute(
+            "DELETE FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1 AND version = $2;",
+            &[&script_id, &version],
+        ) {
+            Ok(_data) => (),
+            Err(err) => {
+                println!("SQL ERROR: {}", err);
+                return Err(String::from("ERR_INTERNAL_ERR"));
+            }
+        };
+    }
+
+    Ok(())
+}
+
+// Removes every stored version, for when the script itself goes away.
+pub fn delete_all(script_id: &String, conn: &Connection) -> Result<(), String> {
+    let rows_recieved: Rows = match conn.query(
+        "DELETE FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1 RETURNING object_key;",
+        &[&script_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let keys: Vec<String> = rows_recieved.iter().map(|row| row.get("object_key")).collect();
+    delete_objects(&keys);
+
+    Ok(())
+}
+
+// Best effort, the rows pointing at these are already gone. A failure only leaves an
+// unreachable object behind.
+pub fn delete_objects(keys: &Vec<String>) {
+    for key in keys {
+        match object_store::scripts().delete(key) {
+            Ok(_data) => (),
+            Err(err) => println!("STORAGE ERROR: failed to delete {}: {}", key, err),
+        };
+    }
+}
+
+pub fn list_versions(user_id: &String, script_id: &String, conn: &MainPGDatabase) -> Result<Vec<ScriptVersion>, String> {
+    check_owner(user_id, script_id, conn)?;
+
+    let rows_recieved: Rows = match conn.query(
+        r#"SELECT version, author_id, size_bytes, sha256, restored_from, created_at
+        FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1 ORDER BY version DESC;"#,
+        &[&script_id],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    let mut versions: Vec<ScriptVersion> = Default::default();
+
+    for (index, row) in rows_recieved.iter().enumerate() {
+        versions.push(ScriptVersion {
+            version: row.get("version"),
+            author_id: row.get("author_id"),
+            size_bytes: row.get("size_bytes"),
+            sha256: row.get("sha256"),
+            restored_from: row.get("restored_from"),
+            created_at: row.get("created_at"),
+            head: index == 0,
+        });
+    }
+
+    Ok(versions)
+}
+
+fn fetch_version(script_id: &String, version: i32, conn: &MainPGDatabase) -> Result<Vec<u8>, String> {
+    let rows_recieved: Rows = match conn.query(
+        "SELECT object_key FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1 AND version = $2;",
+        &[&script_id, &version],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.len() < 1 {
+        return Err(String::from("ERR_VERSION_NOT_FOUND"));
+    }
+
+    let key: String = rows_recieved.get(0).get("object_key");
+    object_store::scripts().get(&key)
+}
+
+pub fn version_object(
+    user_id: &String,
+    script_id: &String,
+    version: i32,
+    conn: &MainPGDatabase,
+) -> Result<ScriptObject, String> {
+    check_owner(user_id, script_id, conn)?;
+
+    let rows_recieved: Rows = match conn.query(
+        "SELECT object_key, size_bytes, sha256 FROM lunar_buffxnte_psu.script_versions WHERE script_id = $1 AND version = $2;",
+        &[&script_id, &version],
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("SQL ERROR: {}", err);
+            return Err(String::from("ERR_INTERNAL_ERR"));
+        }
+    };
+
+    if rows_recieved.len() < 1 {
+        return Err(String::from("ERR_VERSION_NOT_FOUND"));
+    }
+
+    let size_bytes: i64 = rows_recieved.get(0).get("size_bytes");
+
+    Ok(ScriptObject {
+        key: rows_recieved.get(0).get("object_key"),
+        size: size_bytes as u64,
+        sha256: Some(rows_recieved.get(0).get("sha256")),
+    })
+}
+
+// Restoring never rewrites history, the old source is saved again as a new version.
+pub fn restore(user_id: &String, script_id: &String, version: i32, conn: &MainPGDatabase) -> Result<i32, String> {
+    let owner_id = check_owner(user_id, script_id, conn)?;
+
+    let data = fetch_version(script_id, version, conn)?;
+
+    commit(script_id, &owner_id, user_id, Upload::Memory(data), Some(version), conn)
+}
+
+pub fn diff(
+    user_id: &String,
+    script_id: &String,
+    from: i32,
+    to: i32,
+    conn: &MainPGDatabase,
+) -> Result<ScriptDiff, String> {
+    check_owner(user_id, script_id, conn)?;
+
+    let old = String::from_utf8_lossy(&fetch_version(script_id, from, conn)?).into_owned();
+    let new = String::from_utf8_lossy(&fetch_version(script_id, to, conn)?).into_owned();
+
+    let text_diff = TextDiff::from_lines(&old, &new);
+
+    let mut added = 0;
+    let mut removed = 0;
+
+    for change in text_diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => added += 1,
+            ChangeTag::Delete => removed += 1,
+            ChangeTag::Equal => (),
+        }
+    }
+
+    let unified = text_diff
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("v{}", from), &format!("v{}", to))
+        .to_string();
+
+    Ok(ScriptDiff {
+        from,
+        to,
+        added,
+        removed,
+        diff: unified,
+    })
+}
@@ -23,6 +23,8 @@ pub struct User {
     pub two_factor_country_code: Option<i64>,
     pub two_factor_phone: Option<i64>,
     pub two_factor_options: Option<String>,
+    pub two_factor_secret: Option<String>,
+    pub two_factor_last_step: Option<i64>,
     pub email_verified_at: Option<chrono::DateTime<Utc>>,
     pub remember_token: Option<String>,
     pub created_at: Option<chrono::DateTime<Utc>>,
@@ -51,6 +53,11 @@ pub struct SafeUser {
     pub api_enabled: Option<String>,
     pub api_key: Option<String>,
     pub has_premium: Option<String>,
+    pub perms: Vec<String>,
+    pub two_factor_enabled: bool,
+    pub email_verified: bool,
+    pub email_verified_at: Option<chrono::DateTime<Utc>>,
+    pub deletion_scheduled_for: Option<chrono::DateTime<Utc>>,
     pub discord_ids: Option<Vec<String>>,
     pub discord_username: Option<String>,
     pub discord_avatar: Option<String>,
@@ -60,6 +67,9 @@ pub struct SafeUser {
 impl User {
     pub fn get_safe_user(self: &Self, conn: &crate::MainPGDatabase) -> SafeUser {
         let has_premium = account_services::has_premium(&self.id, &conn);
+        let perms = account_services::permissions::get_user_perms(&self.id, &conn).unwrap_or_default();
+        let deletion_scheduled_for =
+            account_services::account_deletion::pending_deletion(&self.id, conn).unwrap_or(None);
         SafeUser {
             id: self.id.clone(),
             email: self.email.clone(),
@@ -78,6 +88,11 @@ impl User {
             discord_avatar: self.discord_avatar.clone(),
             discord_id: self.discord_id.clone(),
             has_premium: has_premium,
+            perms: perms,
+            two_factor_enabled: account_services::two_factor::is_enabled(self),
+            email_verified: self.email_verified_at.is_some(),
+            email_verified_at: self.email_verified_at.clone(),
+            deletion_scheduled_for: deletion_scheduled_for,
         }
     }
 }
@@ -102,6 +117,8 @@ pub fn row_to_user(user_row: &Row) -> User {
         two_factor_country_code: user_row.get("two_factor_country_code"),
         two_factor_phone: user_row.get("two_factor_phone"),
         two_factor_options: user_row.get("two_factor_options"),
+        two_factor_secret: user_row.get("two_factor_secret"),
+        two_factor_last_step: user_row.get("two_factor_last_step"),
         email_verified_at: user_row.get("email_verified_at"),
         remember_token: user_row.get("remember_token"),
         created_at: user_row.get("created_at"),
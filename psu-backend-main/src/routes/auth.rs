@@ -3,7 +3,10 @@ use rocket::{self, Data};
 use rocket_contrib::json::{Json, JsonValue};
 use serde::Deserialize;
 
-use crate::modules::account_services;
+use crate::modules::account_services::email_verification;
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::{account_services, captcha, script_services};
+use crate::routes::guards::{AuthenticatedUser, OptionalUser};
 use crate::MainPGDatabase;
 
 use std::{net::SocketAddr, unimplemented};
@@ -42,10 +45,11 @@ pub struct RegisterRequest {
 
 #[derive(Deserialize)]
 pub struct MeRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
 }
 
-pub struct UserAgent(String);
+pub struct UserAgent(pub String);
 
 #[derive(Debug)]
 pub enum ApiUserAgentError {
@@ -67,16 +71,24 @@ impl<'a, 'r> FromRequest<'a, 'r> for UserAgent {
     }
 }
 
+pub mod account;
+pub mod activity;
+pub mod admin;
+pub mod api_keys;
 pub mod perm;
 pub mod premium;
+pub mod roles;
+pub mod sessions;
+pub mod two_factor;
 
 #[post("/auth/update_avatar", data = "<data>")]
 // signature requires the request to have a `Content-Type`
 pub fn update_avatar(
     conn: MainPGDatabase,
+    user: OptionalUser,
     cont_type: &ContentType,
     data: Data,
-) -> Result<String, JsonValue> {
+) -> Result<JsonValue, JsonValue> {
     // this and the next check can be implemented as a request guard but it seems like just
     // more boilerplate than necessary
     if !cont_type.is_form_data() {
@@ -95,19 +107,48 @@ pub fn update_avatar(
             })
         })?;
 
-    match account_services::process_avatar_upload(boundary, data, &conn) {
-        Ok(resp) => Ok(resp),
+    let entries = match script_services::process_multipart(
+        boundary,
+        data,
+        account_services::avatars::max_bytes() as u64,
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{}", err);
+            return Err(json!({"success": false, "message": "Something went wrong parsing multipart data."}));
+        }
+    };
+    let multipart_data = &entries.fields;
+
+    let user_id = match user.or_token(
+        script_services::optional_field_string(&multipart_data, "token").as_ref(),
+        &conn,
+    ) {
+        Ok(data) => data,
+        Err(_err) => return Err(json!({"success": false, "message": "ERR_AUTH_FAILED"})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match account_services::avatars::process_avatar_upload(&user_id, &multipart_data, &conn) {
+        Ok(urls) => Ok(json!({
+          "success": true,
+          "avatar": urls.avatar,
+          "sizes": urls.sizes
+        })),
         Err(err) => Err(json!({"success": false, "message": err})),
     }
 }
 
-#[post("/auth/get_apikey", format = "json", data = "<request_data>")]
-pub fn api_key(conn: MainPGDatabase, request_data: Json<MeRequest>) -> JsonValue {
-    match account_services::get_api_key(&request_data.token, conn) {
+// Lists key metadata. Secrets are only shown when a key is created or regenerated.
+pub fn api_key_response(user_id: &String, conn: &MainPGDatabase) -> JsonValue {
+    match account_services::get_api_key(user_id, conn) {
         Ok(data) => {
             return json!({
               "success": true,
-              "api_key": data
+              "keys": data
             });
         }
         Err(errmessage) => {
@@ -119,13 +160,33 @@ pub fn api_key(conn: MainPGDatabase, request_data: Json<MeRequest>) -> JsonValue
     }
 }
 
-#[post("/auth/me", format = "json", data = "<request_data>")]
-pub fn me(conn: MainPGDatabase, request_data: Json<MeRequest>) -> JsonValue {
-    match account_services::get_user(&request_data.token, &conn) {
+#[post("/auth/get_apikey", format = "json", data = "<request_data>")]
+pub fn api_key(conn: MainPGDatabase, user: OptionalUser, request_data: Json<MeRequest>) -> JsonValue {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(_err) => return json!({"success": false, "message": "ERR_INVALID_AUTH"}),
+    };
+
+    match user.require_session() {
+        Ok(_data) => api_key_response(&user_id, &conn),
+        Err(err) => json!({"success": false, "message": err}),
+    }
+}
+
+#[get("/auth/get_apikey")]
+pub fn api_key_header(conn: MainPGDatabase, user: AuthenticatedUser) -> JsonValue {
+    match user.require_session() {
+        Ok(_data) => api_key_response(&user.user_id, &conn),
+        Err(err) => json!({"success": false, "message": err}),
+    }
+}
+
+fn me_response(user_id: &String, conn: &MainPGDatabase) -> JsonValue {
+    match account_services::get_user(user_id, conn) {
         Ok(data) => {
             return json!({
               "success": true,
-              "userData": data.get_safe_user(&conn)
+              "userData": data.get_safe_user(conn)
             });
         }
         Err(errmessage) => {
@@ -137,9 +198,23 @@ pub fn me(conn: MainPGDatabase, request_data: Json<MeRequest>) -> JsonValue {
     }
 }
 
+#[post("/auth/me", format = "json", data = "<request_data>")]
+pub fn me(conn: MainPGDatabase, user: OptionalUser, request_data: Json<MeRequest>) -> JsonValue {
+    match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(user_id) => me_response(&user_id, &conn),
+        Err(err) => json!({"success": false, "message": err}),
+    }
+}
+
+#[get("/auth/me")]
+pub fn me_header(conn: MainPGDatabase, user: AuthenticatedUser) -> JsonValue {
+    me_response(&user.user_id, &conn)
+}
+
 #[derive(Deserialize)]
 pub struct UpdateProfileRequest {
-    token: String,
+    #[serde(default)]
+    token: Option<String>,
     email: String,
     first_name: String,
     last_name: String,
@@ -148,18 +223,25 @@ pub struct UpdateProfileRequest {
 #[post("/auth/me/update", format = "json", data = "<request_data>")]
 pub fn update_profile(
     conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
     request_data: Json<UpdateProfileRequest>,
 ) -> Result<JsonValue, JsonValue> {
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(data) => data,
         Err(_err) => return Err(json!({"success": false, "message": "Authentication Failed."})),
     };
 
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
     match account_services::update_profile(
         &user_id,
         &request_data.email,
         &request_data.first_name,
         &request_data.last_name,
+        &audit,
         &conn,
     ) {
         Ok(data) => Ok(json!({"success": true, "message": data})),
@@ -169,16 +251,18 @@ pub fn update_profile(
 
 #[derive(Deserialize)]
 pub struct DiscordConnectRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub discord_code: String,
 }
 
 #[post("/auth/connnections/discord", format = "json", data = "<request_data>")]
 pub fn discord_connection(
     conn: MainPGDatabase,
+    user: OptionalUser,
     request_data: Json<DiscordConnectRequest>,
 ) -> Result<JsonValue, JsonValue> {
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(data) => data,
         Err(err) => {
             return Err(json!({
@@ -188,6 +272,10 @@ pub fn discord_connection(
         }
     };
 
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
     let discord_user =
         match account_services::link_discord(&user_id, &request_data.discord_code, conn) {
             Ok(data) => data,
@@ -207,9 +295,10 @@ pub fn discord_connection(
 )]
 pub fn discord_delink(
     conn: MainPGDatabase,
+    user: OptionalUser,
     request_data: Json<MeRequest>,
 ) -> Result<JsonValue, JsonValue> {
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(data) => data,
         Err(err) => {
             return Err(json!({
@@ -219,6 +308,10 @@ pub fn discord_delink(
         }
     };
 
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
     match account_services::unlink_discord(&user_id, conn) {
         Ok(data) => data,
         Err(err) => return Err(json!({"success": false, "message": err})),
@@ -231,40 +324,20 @@ pub fn discord_delink(
 }
 
 #[post("/auth/finalise_reset", format = "json", data = "<request_data>")]
-pub fn finalise_reset(conn: MainPGDatabase, request_data: Json<FinaliseRequest>) -> JsonValue {
-    if !verify_recaptcha(&request_data.captcha) {
-        return json!({
-          "success": false,
-          "message": "Captcha validation failed, please try again."
-        });
+pub fn finalise_reset(
+    conn: MainPGDatabase,
+    request_data: Json<FinaliseRequest>,
+    remote_addr: SocketAddr,
+    audit: AuditContext,
+) -> JsonValue {
+    if let Err(err) = check_captcha(&request_data.captcha, &remote_addr) {
+        return err;
     };
-    
-    // Check length
-    if request_data.newPassword.len() < 8 {
-        return json!({"success": false, "message": "Password needs to be more than 8 characters"});
-    }
 
-    // Check password score
-    let score_estimate = zxcvbn::zxcvbn(&request_data.newPassword, &[]).unwrap();
-
-    if score_estimate.score() <= 2 {
-        match score_estimate.feedback() {
-            Some(data) => match data.warning() {
-                Some(warning) => {
-                    return json!({"success": false, "message": format!("Password is too weak, Warning: {}", warning)})
-                }
-                None => {
-                    return json!({"success": false, "message": format!("Password is too weak, Suggestion: {}", data.suggestions()[0])})
-                }
-            },
-            None => return json!({"success": false, "message": "Password is too weak"}),
-        }
-    }
-
-    // Now reset the password
-    match account_services::finalise_password_reset(
+    match account_services::passwords::finalise_password_reset(
         &request_data.resetToken,
         &request_data.newPassword,
+        &audit,
         &conn,
     ) {
         Ok(_data) => return json!({"success": true, "message": "SUCCESS"}),
@@ -273,26 +346,63 @@ pub fn finalise_reset(conn: MainPGDatabase, request_data: Json<FinaliseRequest>)
 }
 
 #[post("/auth/reset_password", format = "json", data = "<request_data>")]
-pub fn reset_password(conn: MainPGDatabase, request_data: Json<ResetPasswordReq>) -> JsonValue {
-    if !verify_recaptcha(&request_data.captcha) {
-        return json!({
-          "success": false,
-          "message": "Captcha validation failed, please try again."
-        });
+pub fn reset_password(
+    conn: MainPGDatabase,
+    request_data: Json<ResetPasswordReq>,
+    remote_addr: SocketAddr,
+) -> JsonValue {
+    if let Err(err) = check_captcha(&request_data.captcha, &remote_addr) {
+        return err;
     };
     
-    match account_services::send_reset_email(&request_data.email, &conn) {
+    match account_services::passwords::send_reset_email(
+        &request_data.email,
+        &remote_addr.ip().to_string(),
+        &conn,
+    ) {
         Ok(_data) => return json!({"success": true, "message": "SUCCESS"}),
         Err(err) => return json!({"success": false, "message": err}),
     }
 }
 
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    pub verificationToken: String,
+}
+
+#[post("/auth/verify_email", format = "json", data = "<request_data>")]
+pub fn verify_email(conn: MainPGDatabase, request_data: Json<VerifyEmailRequest>) -> JsonValue {
+    match email_verification::verify_email(&request_data.verificationToken, &conn) {
+        Ok(_data) => json!({"success": true, "message": "SUCCESS"}),
+        Err(err) => json!({"success": false, "message": err}),
+    }
+}
+
+#[post("/auth/verify_email/resend", format = "json", data = "<request_data>")]
+pub fn resend_verification_email(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    match email_verification::resend_verification(&user_id, &conn) {
+        Ok(_data) => Ok(json!({"success": true, "message": "SUCCESS"})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
 #[post("/auth/regenerate_apikey", format = "json", data = "<request_data>")]
 pub fn regenerate_api_key(
     conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
     request_data: Json<MeRequest>,
 ) -> Result<JsonValue, JsonValue> {
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(data) => data,
         Err(err) => {
             return Err(json!({
@@ -302,11 +412,32 @@ pub fn regenerate_api_key(
         }
     };
 
-    match account_services::regenerate_api_key(&user_id, conn) {
-        Ok(data) => Ok(json!({
-          "success": true,
-          "api_key": data
-        })),
+    match user.require_session() {
+        Ok(_data) => (),
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    match email_verification::ensure_verified(&user_id, &conn) {
+        Ok(_data) => (),
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    match account_services::regenerate_api_key(&user_id, &conn) {
+        Ok(data) => {
+            audit_log::record(
+                Some(&user_id),
+                None,
+                "api_key.regenerate",
+                serde_json::json!({}),
+                &audit,
+                &conn,
+            );
+
+            Ok(json!({
+              "success": true,
+              "api_key": data
+            }))
+        }
         Err(err) => {
             println!("ERROR: {}", err);
             return Err(json!({
@@ -318,12 +449,13 @@ pub fn regenerate_api_key(
 }
 
 #[post("/auth/register", format = "json", data = "<request_data>")]
-pub fn register(conn: MainPGDatabase, request_data: Json<RegisterRequest>) -> JsonValue {
-    if !verify_recaptcha(&request_data.captcha) {
-        return json!({
-          "success": false,
-          "message": "Captcha validation failed, please try again."
-        });
+pub fn register(
+    conn: MainPGDatabase,
+    request_data: Json<RegisterRequest>,
+    remote_addr: SocketAddr,
+) -> JsonValue {
+    if let Err(err) = check_captcha(&request_data.captcha, &remote_addr) {
+        return err;
     };
 
     match account_services::register_user(
@@ -347,17 +479,23 @@ pub fn register(conn: MainPGDatabase, request_data: Json<RegisterRequest>) -> Js
     }
 }
 
-fn verify_recaptcha(response: &String) -> bool {
-    let data = ureq::post("https://www.google.com/recaptcha/api/siteverify").send_form(&[
-        ("secret", &std::env::var("CAPTCHA_KEY").unwrap()),
-        ("response", response),
-    ]);
-
-    let json = data.into_json().unwrap();
-
-    let success: bool = json.get("success").unwrap().as_bool().unwrap();
-
-    success
+// Provider errors are reported separately so users aren't told they failed a captcha
+// when the provider was simply unreachable.
+fn check_captcha(response: &String, remote_addr: &SocketAddr) -> Result<(), JsonValue> {
+    match captcha::verify(response, Some(&remote_addr.ip().to_string())) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(json!({
+          "success": false,
+          "message": "Captcha validation failed, please try again."
+        })),
+        Err(err) => {
+            println!("Captcha verification error: {}", err);
+            Err(json!({
+              "success": false,
+              "message": "Captcha verification is unavailable right now, please try again later."
+            }))
+        }
+    }
 }
 
 #[post("/auth/login", format = "json", data = "<request_data>")]
@@ -367,11 +505,8 @@ pub fn login(
     remote_addr: SocketAddr,
     user_agent: UserAgent,
 ) -> JsonValue {
-    if !verify_recaptcha(&request_data.captcha) {
-        return json!({
-          "success": false,
-          "message": "Captcha validation failed, please try again."
-        });
+    if let Err(err) = check_captcha(&request_data.captcha, &remote_addr) {
+        return err;
     };
 
     let UserAgent(useragent_string) = user_agent;
@@ -379,15 +514,22 @@ pub fn login(
         &request_data.username,
         &request_data.password,
         conn,
-        remote_addr.to_string(),
+        remote_addr.ip().to_string(),
         useragent_string,
     ) {
-        Ok(data) => {
+        Ok(account_services::LoginOutcome::Session(data)) => {
             return json!({
               "success": true,
               "token": data
             });
         }
+        Ok(account_services::LoginOutcome::TwoFactorRequired(challenge)) => {
+            return json!({
+              "success": true,
+              "two_factor_required": true,
+              "challenge": challenge
+            });
+        }
         Err(errmessage) => {
             return json!({
               "success": false,
@@ -396,3 +538,71 @@ pub fn login(
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct DiscordLoginRequest {
+    pub discord_code: String,
+}
+
+// Logs in with a linked Discord account, or signs up when the Discord email is verified
+// and nothing is linked yet. Discord's own flow stands in for the captcha here.
+#[post("/auth/login/discord", format = "json", data = "<request_data>")]
+pub fn discord_login(
+    conn: MainPGDatabase,
+    request_data: Json<DiscordLoginRequest>,
+    remote_addr: SocketAddr,
+    user_agent: UserAgent,
+) -> JsonValue {
+    let UserAgent(useragent_string) = user_agent;
+    match account_services::discord_login::login(
+        &request_data.discord_code,
+        conn,
+        remote_addr.ip().to_string(),
+        useragent_string,
+    ) {
+        Ok(account_services::discord_login::DiscordLogin {
+            outcome: account_services::LoginOutcome::Session(data),
+            registered,
+        }) => {
+            return json!({
+              "success": true,
+              "token": data,
+              "registered": registered
+            });
+        }
+        Ok(account_services::discord_login::DiscordLogin {
+            outcome: account_services::LoginOutcome::TwoFactorRequired(challenge),
+            ..
+        }) => {
+            return json!({
+              "success": true,
+              "two_factor_required": true,
+              "challenge": challenge
+            });
+        }
+        Err(errmessage) => {
+            return json!({
+              "success": false,
+              "message": errmessage
+            });
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReportSignInRequest {
+    pub alertToken: String,
+}
+
+// Target of the "this wasn't me" link in new sign-in emails.
+#[post("/auth/login/not_me", format = "json", data = "<request_data>")]
+pub fn report_sign_in(
+    conn: MainPGDatabase,
+    request_data: Json<ReportSignInRequest>,
+    audit: AuditContext,
+) -> JsonValue {
+    match account_services::login_alerts::report_not_me(&request_data.alertToken, &audit, &conn) {
+        Ok(_data) => json!({"success": true, "message": "SUCCESS"}),
+        Err(err) => json!({"success": false, "message": err}),
+    }
+}
@@ -0,0 +1,193 @@
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+use std::io::Cursor;
+
+use crate::modules::account_services::{account_deletion, data_export, passwords, usernames};
+use crate::modules::audit_log::AuditContext;
+use crate::routes::auth::MeRequest;
+use crate::routes::guards::{AuthenticatedUser, OptionalUser};
+use crate::MainPGDatabase;
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub password: String,
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeUsernameRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub username: String,
+}
+
+pub struct ZipDownload {
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+impl<'r> Responder<'r> for ZipDownload {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("application", "zip"))
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            )
+            .sized_body(Cursor::new(self.data))
+            .ok()
+    }
+}
+
+fn export_response(user_id: &String, conn: &MainPGDatabase) -> Result<ZipDownload, JsonValue> {
+    match data_export::export_user_data(user_id, conn) {
+        Ok(data) => Ok(ZipDownload {
+            filename: format!("psu-export-{}.zip", chrono::Utc::now().format("%Y-%m-%d")),
+            data: data,
+        }),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/me/export", format = "json", data = "<request_data>")]
+pub fn export_data(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<ZipDownload, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    export_response(&user_id, &conn)
+}
+
+#[get("/auth/me/export")]
+pub fn export_data_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+) -> Result<ZipDownload, JsonValue> {
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    export_response(&user.user_id, &conn)
+}
+
+#[post("/auth/me/delete", format = "json", data = "<request_data>")]
+pub fn request_deletion(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<DeleteAccountRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match account_deletion::request_deletion(
+        &user_id,
+        &request_data.password,
+        request_data.code.as_ref(),
+        &conn,
+    ) {
+        Ok(scheduled_for) => Ok(json!({"success": true, "scheduled_for": scheduled_for})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/me/delete/cancel", format = "json", data = "<request_data>")]
+pub fn cancel_deletion(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match account_deletion::cancel_deletion(&user_id, &conn) {
+        Ok(data) => Ok(json!({"success": true, "message": data})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+// The session making the change stays signed in, every other one is revoked.
+#[post("/auth/me/change_password", format = "json", data = "<request_data>")]
+pub fn change_password(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
+    request_data: Json<ChangePasswordRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match passwords::change_password(
+        &user_id,
+        &request_data.current_password,
+        &request_data.new_password,
+        user.session_token(request_data.token.as_ref()),
+        &audit,
+        &conn,
+    ) {
+        Ok(revoked) => Ok(json!({"success": true, "message": "SUCCESS", "sessions_revoked": revoked})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/me/username", format = "json", data = "<request_data>")]
+pub fn change_username(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
+    request_data: Json<ChangeUsernameRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match usernames::rename(&user_id, &request_data.username, &audit, &conn) {
+        Ok(data) => Ok(json!({"success": true, "message": data})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
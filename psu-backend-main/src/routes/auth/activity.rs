@@ -0,0 +1,71 @@
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+
+use crate::modules::audit_log;
+use crate::routes::guards::{AuthenticatedUser, OptionalUser};
+use crate::MainPGDatabase;
+
+fn default_page() -> i64 {
+    1
+}
+
+fn default_per_page() -> i64 {
+    25
+}
+
+#[derive(Deserialize)]
+pub struct ActivityRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default = "default_page")]
+    pub page: i64,
+    #[serde(default = "default_per_page")]
+    pub per_page: i64,
+}
+
+pub fn activity_response(
+    user_id: &String,
+    page: i64,
+    per_page: i64,
+    conn: &MainPGDatabase,
+) -> Result<JsonValue, JsonValue> {
+    match audit_log::list_events(user_id, page, per_page, conn) {
+        Ok((events, total)) => Ok(json!({
+          "success": true,
+          "data": events,
+          "page": page.max(1),
+          "per_page": per_page.max(1).min(audit_log::MAX_PAGE_SIZE),
+          "total": total
+        })),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/activity", format = "json", data = "<request_data>")]
+pub fn get_activity(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<ActivityRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    activity_response(&user_id, request_data.page, request_data.per_page, &conn)
+}
+
+#[get("/auth/activity?<page>&<per_page>")]
+pub fn get_activity_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<JsonValue, JsonValue> {
+    activity_response(
+        &user.user_id,
+        page.unwrap_or(default_page()),
+        per_page.unwrap_or(default_per_page()),
+        &conn,
+    )
+}
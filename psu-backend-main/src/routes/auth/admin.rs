@@ -0,0 +1,123 @@
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+
+use crate::modules::account_services::{impersonation, login_protection};
+use crate::modules::audit_log::AuditContext;
+use crate::routes::auth::activity;
+use crate::routes::guards::{require_permission, OptionalUser, PermissionCache};
+use crate::MainPGDatabase;
+
+#[derive(Deserialize)]
+pub struct targetUserReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub target: String,
+}
+
+#[post("/auth/admin/unlock_account", format = "json", data = "<request_data>")]
+pub fn unlock_account(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<targetUserReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(_err) => {
+            return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
+        }
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
+
+    require_permission(&user_id, "admin.users.unlock", perms, &conn)?;
+
+    match login_protection::unlock_account(&request_data.target, &conn) {
+        Ok(_data) => Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct targetActivityReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub target: String,
+    #[serde(default)]
+    pub page: Option<i64>,
+    #[serde(default)]
+    pub per_page: Option<i64>,
+}
+
+#[post("/auth/admin/activity", format = "json", data = "<request_data>")]
+pub fn user_activity(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<targetActivityReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(_err) => {
+            return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
+        }
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
+
+    require_permission(&user_id, "admin.audit.view", perms, &conn)?;
+
+    activity::activity_response(
+        &request_data.target,
+        request_data.page.unwrap_or(1),
+        request_data.per_page.unwrap_or(25),
+        &conn,
+    )
+}
+
+#[derive(Deserialize)]
+pub struct impersonateReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub target: String,
+    pub reason: String,
+    #[serde(default)]
+    pub write_access: bool,
+}
+
+// Returns a short-lived session token for the target account. Read-only unless write access
+// is asked for and the admin holds the separate write permission.
+#[post("/auth/admin/impersonate", format = "json", data = "<request_data>")]
+pub fn impersonate(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
+    request_data: Json<impersonateReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(_err) => {
+            return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
+        }
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
+
+    match impersonation::start(
+        &user_id,
+        &request_data.target,
+        &request_data.reason,
+        request_data.write_access,
+        &audit,
+        &conn,
+    ) {
+        Ok(session) => Ok(json!({"success":true, "session": session})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
@@ -0,0 +1,156 @@
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+
+use crate::modules::account_services::{api_keys, api_metering};
+use crate::modules::audit_log::{self, AuditContext};
+use crate::routes::auth::{api_key_response, MeRequest};
+use crate::routes::guards::{AuthenticatedUser, OptionalUser};
+use crate::MainPGDatabase;
+
+#[derive(Deserialize)]
+pub struct CreateKeyRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub name: String,
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeKeyRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub key_id: String,
+}
+
+// API keys can't manage API keys, so every route here needs a session.
+fn session_user(
+    user: &OptionalUser,
+    token: Option<&String>,
+    conn: &MainPGDatabase,
+) -> Result<String, JsonValue> {
+    let user_id = match user.or_token(token, conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    match user.require_session() {
+        Ok(_data) => Ok(user_id),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/api_keys", format = "json", data = "<request_data>")]
+pub fn list_keys(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = session_user(&user, request_data.token.as_ref(), &conn)?;
+
+    Ok(api_key_response(&user_id, &conn))
+}
+
+#[get("/auth/api_keys")]
+pub fn list_keys_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+) -> Result<JsonValue, JsonValue> {
+    match user.require_session() {
+        Ok(_data) => Ok(api_key_response(&user.user_id, &conn)),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/api_keys/create", format = "json", data = "<request_data>")]
+pub fn create_key(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
+    request_data: Json<CreateKeyRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = session_user(&user, request_data.token.as_ref(), &conn)?;
+
+    match api_keys::create_key(
+        &user_id,
+        &request_data.name,
+        &request_data.scopes,
+        request_data.expires_at.as_ref(),
+        &conn,
+    ) {
+        Ok((key, secret)) => {
+            audit_log::record(
+                Some(&user_id),
+                None,
+                "api_key.create",
+                serde_json::json!({"key_id": key.id, "name": key.name, "scopes": key.scopes}),
+                &audit,
+                &conn,
+            );
+
+            Ok(json!({"success": true, "key": key, "api_key": secret}))
+        }
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/api_keys/revoke", format = "json", data = "<request_data>")]
+pub fn revoke_key(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
+    request_data: Json<RevokeKeyRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = session_user(&user, request_data.token.as_ref(), &conn)?;
+
+    match api_keys::revoke_key(&user_id, &request_data.key_id, &conn) {
+        Ok(data) => {
+            audit_log::record(
+                Some(&user_id),
+                None,
+                "api_key.revoke",
+                serde_json::json!({"key_id": request_data.key_id}),
+                &audit,
+                &conn,
+            );
+
+            Ok(json!({"success": true, "message": data}))
+        }
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+fn usage_response(user_id: &String, conn: &MainPGDatabase) -> Result<JsonValue, JsonValue> {
+    match api_metering::get_usage(user_id, conn) {
+        Ok(data) => Ok(json!({
+          "success": true,
+          "keys": data,
+          "daily_quota": api_metering::daily_quota(user_id, conn),
+          "resets_at": api_metering::next_reset()
+        })),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/api_keys/usage", format = "json", data = "<request_data>")]
+pub fn key_usage(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = session_user(&user, request_data.token.as_ref(), &conn)?;
+
+    usage_response(&user_id, &conn)
+}
+
+#[get("/auth/api_keys/usage")]
+pub fn key_usage_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+) -> Result<JsonValue, JsonValue> {
+    match user.require_session() {
+        Ok(_data) => usage_response(&user.user_id, &conn),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
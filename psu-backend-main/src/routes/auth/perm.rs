@@ -0,0 +1,131 @@
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+
+use crate::modules::account_services::permissions;
+use crate::routes::guards::{require_permission, OptionalUser, PermissionCache};
+use crate::MainPGDatabase;
+
+#[derive(Deserialize)]
+pub struct getUserPermsReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub target: String,
+}
+
+#[derive(Deserialize)]
+pub struct modifyPermReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub permission_id: String,
+    pub target: String,
+}
+
+fn authorise(
+    user: &OptionalUser,
+    token: Option<&String>,
+    permission: &str,
+    perms: &PermissionCache,
+    conn: &MainPGDatabase,
+) -> Result<String, JsonValue> {
+    let user_id = match user.or_token(token, conn) {
+        Ok(data) => data,
+        Err(_err) => {
+            return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
+        }
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
+
+    require_permission(&user_id, permission, perms, conn)?;
+
+    Ok(user_id)
+}
+
+// Staff can only hand out (or take away) nodes they hold themselves, so the perms page
+// can't be used to escalate to system.admin. Roles go through this too.
+pub(crate) fn require_holds(
+    user_id: &String,
+    permission: &String,
+    perms: &PermissionCache,
+    conn: &MainPGDatabase,
+) -> Result<(), JsonValue> {
+    let node = permission.trim_start_matches('-');
+
+    if !permissions::is_valid_node(node) {
+        return Err(json!({"success":false, "message": String::from("ERR_INVALID_PERMISSION")}));
+    }
+
+    require_permission(user_id, node, perms, conn)
+}
+
+#[post("/auth/perms/get_user_perms", format = "json", data = "<request_data>")]
+pub fn get_user_perms(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<getUserPermsReq>,
+) -> Result<JsonValue, JsonValue> {
+    authorise(
+        &user,
+        request_data.token.as_ref(),
+        "permission.user_perms.get",
+        perms,
+        &conn,
+    )?;
+
+    match permissions::get_user_overrides(&request_data.target, &conn) {
+        Ok(data) => Ok(json!({"success":true, "data": data})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[post("/auth/perms/add_perm", format = "json", data = "<request_data>")]
+pub fn add_perm(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<modifyPermReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = authorise(
+        &user,
+        request_data.token.as_ref(),
+        "permission.user_perms.set",
+        perms,
+        &conn,
+    )?;
+    require_holds(&user_id, &request_data.permission_id, perms, &conn)?;
+
+    match permissions::add_perm(
+        &request_data.target,
+        &request_data.permission_id,
+        &user_id,
+        &conn,
+    ) {
+        Ok(_data) => Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[post("/auth/perms/remove_perm", format = "json", data = "<request_data>")]
+pub fn remove_perm(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<modifyPermReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = authorise(
+        &user,
+        request_data.token.as_ref(),
+        "permission.user_perms.set",
+        perms,
+        &conn,
+    )?;
+    require_holds(&user_id, &request_data.permission_id, perms, &conn)?;
+
+    match permissions::remove_perm(&request_data.target, &request_data.permission_id, &conn) {
+        Ok(_data) => Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
@@ -4,49 +4,45 @@ use crate::MainPGDatabase;
 use serde::Deserialize;
 
 use crate::modules::account_services;
+use crate::modules::audit_log::{self, AuditContext};
+use crate::routes::guards::{require_permission, OptionalUser, PermissionCache};
 
 #[derive(Deserialize)]
 pub struct addPremiumReq {
     pub orderType: i8,
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub target: String,
 }
 
 #[derive(Deserialize)]
 pub struct removePremiumReq {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub target: String,
 }
 
 #[post("/auth/premium/get_premium", format = "json", data = "<request_data>")]
 pub fn get_prem(
     conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
     request_data: Json<removePremiumReq>,
 ) -> Result<JsonValue, JsonValue> {
     // Check user is logged in
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(data) => data,
         Err(_err) => {
             return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
         }
     };
 
-    // Check user has permission to add premium
-    let has_permission = match account_services::permissions::has_perms(
-        &user_id,
-        &"user.premium.get".to_string(),
-        &conn,
-        false,
-    ) {
-        Ok(data) => data,
-        Err(_err) => {
-            return Err(json!({"success":false, "message": String::from("ERR_INTERNAL_ERR")}))
-        }
-    };
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
 
-    if !has_permission {
-        return Err(json!({"success":false, "message": String::from("PERMISSION_DENIED")}));
-    };
+    // Check user has permission to add premium
+    require_permission(&user_id, "user.premium.get", perms, &conn)?;
 
     match account_services::has_premium(&request_data.target, &conn) {
         Some(data) => return Ok(json!({"success":true, "data": data})),
@@ -61,35 +57,38 @@ pub fn get_prem(
 )]
 pub fn remove_prem(
     conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    audit: AuditContext,
     request_data: Json<removePremiumReq>,
 ) -> Result<JsonValue, JsonValue> {
     // Check user is logged in
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(data) => data,
         Err(_err) => {
             return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
         }
     };
 
-    // Check user has permission to add premium
-    let has_permission = match account_services::permissions::has_perms(
-        &user_id,
-        &"user.premium.set".to_string(),
-        &conn,
-        false,
-    ) {
-        Ok(data) => data,
-        Err(_err) => {
-            return Err(json!({"success":false, "message": String::from("ERR_INTERNAL_ERR")}))
-        }
-    };
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
 
-    if !has_permission {
-        return Err(json!({"success":false, "message": String::from("PERMISSION_DENIED")}));
-    };
+    // Check user has permission to add premium
+    require_permission(&user_id, "user.premium.set", perms, &conn)?;
 
     match account_services::remove_premium(&request_data.target, &conn) {
-        Ok(_data) => return Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Ok(_data) => {
+            audit_log::record(
+                Some(&request_data.target),
+                Some(&user_id),
+                "premium.remove",
+                serde_json::json!({}),
+                &audit,
+                &conn,
+            );
+            return Ok(json!({"success":true, "message": String::from("SUCCESS")}));
+        }
         Err(err) => return Err(json!({"success":false, "message": String::from(err)})),
     }
 }
@@ -97,32 +96,25 @@ pub fn remove_prem(
 #[post("/auth/premium/add_premium", format = "json", data = "<request_data>")]
 pub fn add_prem(
     conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    audit: AuditContext,
     request_data: Json<addPremiumReq>,
 ) -> Result<JsonValue, JsonValue> {
     // Check user is logged in
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(data) => data,
         Err(_err) => {
             return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
         }
     };
 
-    // Check user has permission to add premium
-    let has_permission = match account_services::permissions::has_perms(
-        &user_id,
-        &"user.premium.set".to_string(),
-        &conn,
-        false,
-    ) {
-        Ok(data) => data,
-        Err(_err) => {
-            return Err(json!({"success":false, "message": String::from("ERR_INTERNAL_ERR")}))
-        }
-    };
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
 
-    if !has_permission {
-        return Err(json!({"success":false, "message": String::from("PERMISSION_DENIED")}));
-    };
+    // Check user has permission to add premium
+    require_permission(&user_id, "user.premium.set", perms, &conn)?;
 
     match account_services::remove_premium(&request_data.target, &conn) {
         Ok(_data) => (),
@@ -136,7 +128,17 @@ pub fn add_prem(
         &request_data.orderType,
         &conn,
     ) {
-        Ok(_data) => return Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Ok(_data) => {
+            audit_log::record(
+                Some(&request_data.target),
+                Some(&user_id),
+                "premium.grant",
+                serde_json::json!({"order_type": request_data.orderType}),
+                &audit,
+                &conn,
+            );
+            return Ok(json!({"success":true, "message": String::from("SUCCESS")}));
+        }
         Err(err) => return Err(json!({"success":false, "message": String::from(err)})),
     }
 }
@@ -0,0 +1,208 @@
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+
+use crate::modules::account_services::roles;
+use crate::routes::auth::perm::require_holds;
+use crate::routes::auth::MeRequest;
+use crate::routes::guards::{require_permission, OptionalUser, PermissionCache};
+use crate::MainPGDatabase;
+
+#[derive(Deserialize)]
+pub struct CreateRoleReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub desc: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub level: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ModifyRoleReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub role_id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub desc: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub level: i64,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRoleReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub role_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleReq {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub target: String,
+    pub role_id: i64,
+}
+
+fn authorise(
+    user: &OptionalUser,
+    token: Option<&String>,
+    permission: &str,
+    perms: &PermissionCache,
+    conn: &MainPGDatabase,
+) -> Result<String, JsonValue> {
+    let user_id = match user.or_token(token, conn) {
+        Ok(data) => data,
+        Err(_err) => {
+            return Err(json!({"success":false, "message": String::from("ERR_AUTH_FAILED")}))
+        }
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success":false, "message": err}));
+    }
+
+    require_permission(&user_id, permission, perms, conn)?;
+
+    Ok(user_id)
+}
+
+// Staff can only touch roles below their own level.
+fn require_level(actor_id: &String, level: i64, conn: &MainPGDatabase) -> Result<(), JsonValue> {
+    match roles::can_manage(actor_id, level, conn) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(json!({"success":false, "message": String::from("PERMISSION_DENIED")})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[post("/auth/roles/get_roles", format = "json", data = "<request_data>")]
+pub fn get_roles(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    authorise(&user, request_data.token.as_ref(), "admin.roles.view", perms, &conn)?;
+
+    match roles::get_roles(&conn) {
+        Ok(data) => Ok(json!({"success":true, "data": data})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[post("/auth/roles/create_role", format = "json", data = "<request_data>")]
+pub fn create_role(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<CreateRoleReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = authorise(&user, request_data.token.as_ref(), "admin.roles.manage", perms, &conn)?;
+    require_level(&user_id, request_data.level, &conn)?;
+
+    for permission in &request_data.permissions {
+        require_holds(&user_id, permission, perms, &conn)?;
+    }
+
+    match roles::create_role(
+        &request_data.name,
+        &request_data.desc,
+        &request_data.permissions,
+        &request_data.level,
+        &conn,
+    ) {
+        Ok(role_id) => Ok(json!({"success":true, "role_id": role_id})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[post("/auth/roles/modify_role", format = "json", data = "<request_data>")]
+pub fn modify_role(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<ModifyRoleReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = authorise(&user, request_data.token.as_ref(), "admin.roles.manage", perms, &conn)?;
+
+    let role = match roles::get_role(&request_data.role_id, &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success":false, "message": err})),
+    };
+
+    require_level(&user_id, role.level, &conn)?;
+    require_level(&user_id, request_data.level, &conn)?;
+
+    for permission in &request_data.permissions {
+        require_holds(&user_id, permission, perms, &conn)?;
+    }
+
+    match roles::modify_role(
+        &request_data.role_id,
+        &request_data.name,
+        &request_data.desc,
+        &request_data.permissions,
+        &request_data.level,
+        &conn,
+    ) {
+        Ok(_data) => Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[post("/auth/roles/delete_role", format = "json", data = "<request_data>")]
+pub fn delete_role(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<DeleteRoleReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = authorise(&user, request_data.token.as_ref(), "admin.roles.manage", perms, &conn)?;
+
+    let role = match roles::get_role(&request_data.role_id, &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success":false, "message": err})),
+    };
+
+    require_level(&user_id, role.level, &conn)?;
+
+    match roles::delete_role(&request_data.role_id, &conn) {
+        Ok(_data) => Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
+
+#[post("/auth/roles/set_role", format = "json", data = "<request_data>")]
+pub fn set_role(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    perms: &PermissionCache,
+    request_data: Json<SetRoleReq>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = authorise(&user, request_data.token.as_ref(), "admin.roles.assign", perms, &conn)?;
+
+    let new_role = match roles::get_role(&request_data.role_id, &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success":false, "message": err})),
+    };
+
+    let current_role = match roles::get_user_role(&request_data.target, &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success":false, "message": err})),
+    };
+
+    // Both the role being handed out and the one being taken away must be below the actor.
+    require_level(&user_id, new_role.level, &conn)?;
+    require_level(&user_id, current_role.level, &conn)?;
+
+    match roles::set_role(&request_data.target, &request_data.role_id, &conn) {
+        Ok(_data) => Ok(json!({"success":true, "message": String::from("SUCCESS")})),
+        Err(err) => Err(json!({"success":false, "message": err})),
+    }
+}
@@ -0,0 +1,122 @@
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+
+use crate::modules::account_services::sessions;
+use crate::routes::auth::MeRequest;
+use crate::routes::guards::{AuthenticatedUser, OptionalUser};
+use crate::MainPGDatabase;
+
+#[derive(Deserialize)]
+pub struct RevokeSessionRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub all_others: bool,
+}
+
+#[post("/auth/logout", format = "json", data = "<request_data>")]
+pub fn logout(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    // Resolving first makes sure we only ever delete a session that is actually valid.
+    match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(_user_id) => (),
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    let token = match user.session_token(request_data.token.as_ref()) {
+        Some(token) => token,
+        None => return Err(json!({"success": false, "message": "ERR_NOT_A_SESSION"})),
+    };
+
+    match sessions::logout(token, &conn) {
+        Ok(data) => Ok(json!({"success": true, "message": data})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+fn sessions_response(
+    user_id: &String,
+    current_token: Option<&String>,
+    conn: &MainPGDatabase,
+) -> Result<JsonValue, JsonValue> {
+    match sessions::list_sessions(user_id, current_token, conn) {
+        Ok(data) => Ok(json!({"success": true, "data": data})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/sessions", format = "json", data = "<request_data>")]
+pub fn list_sessions(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    sessions_response(
+        &user_id,
+        user.session_token(request_data.token.as_ref()),
+        &conn,
+    )
+}
+
+#[get("/auth/sessions")]
+pub fn list_sessions_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+) -> Result<JsonValue, JsonValue> {
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    sessions_response(&user.user_id, user.session_token(), &conn)
+}
+
+#[post("/auth/sessions/revoke", format = "json", data = "<request_data>")]
+pub fn revoke_session(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<RevokeSessionRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    if request_data.all_others {
+        let current_token = user.session_token(request_data.token.as_ref());
+
+        return match sessions::revoke_other_sessions(&user_id, current_token, &conn) {
+            Ok(count) => Ok(json!({"success": true, "revoked": count})),
+            Err(err) => Err(json!({"success": false, "message": err})),
+        };
+    }
+
+    let session_id = match &request_data.session_id {
+        Some(data) => data,
+        None => {
+            return Err(json!({"success": false, "message": "Either session_id or all_others is required"}))
+        }
+    };
+
+    match sessions::revoke_session(&user_id, session_id, &conn) {
+        Ok(_data) => Ok(json!({"success": true, "revoked": 1})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
@@ -0,0 +1,140 @@
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+use crate::modules::account_services::two_factor;
+use crate::routes::auth::{MeRequest, UserAgent};
+use crate::routes::guards::OptionalUser;
+use crate::MainPGDatabase;
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodeRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[post("/auth/2fa/enrol", format = "json", data = "<request_data>")]
+pub fn begin_enrolment(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<MeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match two_factor::begin_enrolment(&user_id, &conn) {
+        Ok((secret, uri)) => Ok(json!({"success": true, "secret": secret, "otpauth_uri": uri})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/2fa/confirm", format = "json", data = "<request_data>")]
+pub fn confirm_enrolment(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<TwoFactorCodeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match two_factor::confirm_enrolment(&user_id, &request_data.code, &conn) {
+        Ok(codes) => Ok(json!({"success": true, "recovery_codes": codes})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/2fa/recovery_codes", format = "json", data = "<request_data>")]
+pub fn regenerate_recovery_codes(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<TwoFactorCodeRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match two_factor::verify_second_factor(&user_id, &request_data.code, &conn) {
+        Ok(true) => (),
+        Ok(false) => return Err(json!({"success": false, "message": "ERR_INVALID_2FA_CODE"})),
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    match two_factor::generate_recovery_codes(&user_id, &conn) {
+        Ok(codes) => Ok(json!({"success": true, "recovery_codes": codes})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/2fa/disable", format = "json", data = "<request_data>")]
+pub fn disable(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<DisableTwoFactorRequest>,
+) -> Result<JsonValue, JsonValue> {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
+        Ok(data) => data,
+        Err(err) => return Err(json!({"success": false, "message": err})),
+    };
+
+    if let Err(err) = user.require_session() {
+        return Err(json!({"success": false, "message": err}));
+    }
+
+    match two_factor::disable(&user_id, &request_data.password, &request_data.code, &conn) {
+        Ok(data) => Ok(json!({"success": true, "message": data})),
+        Err(err) => Err(json!({"success": false, "message": err})),
+    }
+}
+
+#[post("/auth/login/2fa", format = "json", data = "<request_data>")]
+pub fn login_second_factor(
+    conn: MainPGDatabase,
+    request_data: Json<TwoFactorLoginRequest>,
+    remote_addr: SocketAddr,
+    user_agent: UserAgent,
+) -> JsonValue {
+    let UserAgent(useragent_string) = user_agent;
+
+    match two_factor::complete_challenge(
+        &request_data.challenge,
+        &request_data.code,
+        remote_addr.ip().to_string(),
+        useragent_string,
+        conn,
+    ) {
+        Ok(data) => json!({"success": true, "token": data}),
+        Err(err) => json!({"success": false, "message": err}),
+    }
+}
@@ -0,0 +1,467 @@
+use colored::*;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, Response, Route};
+use rocket_contrib::json::JsonValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::modules::account_services::api_keys::{self, KeyAuth};
+use crate::modules::account_services::api_metering::{self, QuotaStatus};
+use crate::modules::account_services::permissions::{self, PermissionSet};
+use crate::modules::audit_log::AuditContext;
+use crate::modules::{account_services, config};
+use crate::MainPGDatabase;
+
+// How the caller proved who they are. The session token is kept so routes such as
+// logout can act on the exact session that made the request.
+#[derive(Clone)]
+pub enum AuthMethod {
+    Session(String),
+    ApiKey(KeyAuth),
+}
+
+// The session token is a live credential, keep it out of anything that logs a user.
+impl fmt::Debug for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthMethod::Session(_token) => f.write_str("Session(<redacted>)"),
+            AuthMethod::ApiKey(key) => f.debug_tuple("ApiKey").field(key).finish(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub method: AuthMethod,
+    // The admin behind an impersonation session, if this is one.
+    pub impersonator_id: Option<String>,
+}
+
+// Same as AuthenticatedUser but never fails the request, used by routes that still accept
+// the deprecated `token` body field.
+pub struct OptionalUser(pub Option<AuthenticatedUser>);
+
+#[derive(Clone, Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Internal,
+    QuotaExceeded,
+    ReadOnly,
+    KeyNotAllowed,
+}
+
+// The only routes an API key may call, and the scope each one needs. Anything not listed
+// here is sessions only, so a new route stays out of reach of API keys until it is added.
+const API_KEY_ROUTES: &[(Method, &str, &str)] = &[
+    (Method::Post, "/scripts/createScript", api_keys::SCOPE_SCRIPTS_WRITE),
+    (Method::Post, "/scripts/updateScript", api_keys::SCOPE_SCRIPTS_WRITE),
+    (Method::Post, "/scripts/updatePublicScript", api_keys::SCOPE_SCRIPTS_WRITE),
+    (Method::Post, "/scripts/deleteScript", api_keys::SCOPE_SCRIPTS_WRITE),
+    (Method::Post, "/scripts/versions/restore", api_keys::SCOPE_SCRIPTS_WRITE),
+    (Method::Post, "/scripts/private/getAllScripts", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Get, "/scripts/private/getAllScripts", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Post, "/scripts/public/getAllScripts", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Get, "/scripts/public/getAllScripts", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Post, "/scripts/getScript", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Get, "/scripts/getScript/<script_id>", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Post, "/scripts/versions", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Get, "/scripts/<script_id>/versions", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Post, "/scripts/versions/get", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Get, "/scripts/<script_id>/versions/<version>", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Post, "/scripts/versions/diff", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Get, "/scripts/<script_id>/diff/<from>/<to>", api_keys::SCOPE_SCRIPTS_READ),
+    (Method::Post, "/scripts/validate", api_keys::SCOPE_SCRIPTS_READ),
+];
+
+// The route's path as declared, without the base it was mounted under, so the tables here
+// don't depend on where the API is mounted.
+fn route_path(route: &Route) -> &str {
+    let path = route.uri.path();
+
+    match route.base() {
+        "/" => path,
+        base => match path.strip_prefix(base) {
+            Some("") => "/",
+            Some(rest) => rest,
+            None => path,
+        },
+    }
+}
+
+// The scope the matched route declares for API keys, if it accepts them at all.
+fn route_scope(request: &Request) -> Option<&'static str> {
+    let route = request.route()?;
+
+    API_KEY_ROUTES
+        .iter()
+        .find(|(method, path, _scope)| *method == route.method && *path == route_path(route))
+        .map(|(_method, _path, scope)| *scope)
+}
+
+fn bearer_token(request: &Request) -> Option<String> {
+    let header = request.headers().get_one("Authorization")?;
+    let mut parts = header.splitn(2, ' ');
+
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+            Some(token.trim().to_string())
+        }
+        _ => None,
+    }
+}
+
+// Read-only sessions may look but not touch. Many reads here are POSTs that carry a body
+// token, so these sessions are limited to GETs, plus logout so support can end them.
+fn allowed_read_only(request: &Request) -> bool {
+    match request.method() {
+        Method::Get | Method::Head => true,
+        _ => request.route().map_or(false, |route| route_path(route) == "/auth/logout"),
+    }
+}
+
+fn resolve_user(request: &Request) -> Result<AuthenticatedUser, AuthError> {
+    let bearer = bearer_token(request);
+    let api_key = request.headers().get_one("X-API-Key");
+
+    if bearer.is_none() && api_key.is_none() {
+        return Err(AuthError::Missing);
+    }
+
+    let conn = match request.guard::<MainPGDatabase>() {
+        Outcome::Success(conn) => conn,
+        _ => return Err(AuthError::Internal),
+    };
+
+    let result = match (bearer, api_key) {
+        (Some(token), _) => account_services::authenticate_session(&token, &conn).and_then(|session| {
+            if session.read_only && !allowed_read_only(request) {
+                return Err(String::from("ERR_READ_ONLY_SESSION"));
+            }
+
+            Ok(AuthenticatedUser {
+                user_id: session.user_id,
+                method: AuthMethod::Session(token),
+                impersonator_id: session.impersonator_id,
+            })
+        }),
+        (None, Some(key)) => api_keys::authenticate(&key.to_string(), &conn).and_then(|key| {
+            // Checked before metering so a refused call doesn't eat into the quota.
+            match route_scope(request) {
+                Some(scope) if key.has_scope(scope) => {}
+                _ => return Err(String::from("ERR_API_KEY_SCOPE")),
+            }
+
+            let quota = api_metering::meter_request(&key, &conn)?;
+            let exceeded = quota.exceeded;
+
+            // Picked up by the RateLimitHeaders fairing once the response is built.
+            request.local_cache(|| Some(quota));
+
+            if exceeded {
+                return Err(String::from("ERR_QUOTA_EXCEEDED"));
+            }
+
+            Ok(AuthenticatedUser {
+                user_id: key.user_id.clone(),
+                method: AuthMethod::ApiKey(key),
+                impersonator_id: None,
+            })
+        }),
+        (None, None) => return Err(AuthError::Missing),
+    };
+
+    match result {
+        Ok(user) => Ok(user),
+        Err(err) if err == "ERR_INTERNAL_ERR" => Err(AuthError::Internal),
+        Err(err) if err == "ERR_QUOTA_EXCEEDED" => Err(AuthError::QuotaExceeded),
+        Err(err) if err == "ERR_READ_ONLY_SESSION" => Err(AuthError::ReadOnly),
+        Err(err) if err == "ERR_API_KEY_SCOPE" => Err(AuthError::KeyNotAllowed),
+        Err(_err) => Err(AuthError::Invalid),
+    }
+}
+
+// Resolves the user once per request, no matter how many guards ask for it.
+fn cached_user<'a>(request: &'a Request) -> &'a Result<AuthenticatedUser, AuthError> {
+    request.local_cache(|| resolve_user(request))
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
+    type Error = AuthError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match cached_user(request) {
+            Ok(user) => Outcome::Success(user.clone()),
+            Err(AuthError::Internal) => {
+                Outcome::Failure((Status::InternalServerError, AuthError::Internal))
+            }
+            Err(AuthError::QuotaExceeded) => {
+                Outcome::Failure((Status::TooManyRequests, AuthError::QuotaExceeded))
+            }
+            Err(AuthError::ReadOnly) => Outcome::Failure((Status::Forbidden, AuthError::ReadOnly)),
+            Err(AuthError::KeyNotAllowed) => {
+                Outcome::Failure((Status::Forbidden, AuthError::KeyNotAllowed))
+            }
+            Err(err) => Outcome::Failure((Status::Unauthorized, err.clone())),
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for OptionalUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match cached_user(request) {
+            Ok(user) => Outcome::Success(OptionalUser(Some(user.clone()))),
+            // Falling back to the body token here would hide the 429 behind a 401.
+            Err(AuthError::QuotaExceeded) => Outcome::Failure((Status::TooManyRequests, ())),
+            Err(AuthError::ReadOnly) => Outcome::Failure((Status::Forbidden, ())),
+            // Same for a key on a route it may not call, the body token is no way around that.
+            Err(AuthError::KeyNotAllowed) => Outcome::Failure((Status::Forbidden, ())),
+            Err(_err) => Outcome::Success(OptionalUser(None)),
+        }
+    }
+}
+
+impl AuthenticatedUser {
+    pub fn session_token(&self) -> Option<&String> {
+        match &self.method {
+            AuthMethod::Session(token) => Some(token),
+            AuthMethod::ApiKey(_key) => None,
+        }
+    }
+
+    // Sessions can do anything the account can. API keys only what their scopes allow. The
+    // guard already refuses keys outside API_KEY_ROUTES, this keeps each route honest on its own.
+    pub fn require_scope(&self, scope: &str) -> Result<(), String> {
+        match &self.method {
+            AuthMethod::Session(_token) => Ok(()),
+            AuthMethod::ApiKey(key) if key.has_scope(scope) => Ok(()),
+            AuthMethod::ApiKey(_key) => Err(String::from("ERR_API_KEY_SCOPE")),
+        }
+    }
+
+    // For account management routes that an API key must never reach, such as creating keys.
+    // Support sessions are kept out too, even with write access.
+    pub fn require_session(&self) -> Result<(), String> {
+        if self.impersonator_id.is_some() {
+            return Err(String::from("ERR_NOT_ALLOWED_WHILE_IMPERSONATING"));
+        }
+
+        match &self.method {
+            AuthMethod::Session(_token) => Ok(()),
+            AuthMethod::ApiKey(_key) => Err(String::from("ERR_SESSION_REQUIRED")),
+        }
+    }
+}
+
+impl OptionalUser {
+    // The session token behind this request, whether it came from the header or the body.
+    pub fn session_token<'a>(&'a self, token: Option<&'a String>) -> Option<&'a String> {
+        match &self.0 {
+            Some(user) => user.session_token(),
+            None => token,
+        }
+    }
+
+    // Body tokens are always sessions, so only header API keys are ever restricted.
+    pub fn require_scope(&self, scope: &str) -> Result<(), String> {
+        match &self.0 {
+            Some(user) => user.require_scope(scope),
+            None => Ok(()),
+        }
+    }
+
+    pub fn require_session(&self) -> Result<(), String> {
+        match &self.0 {
+            Some(user) => user.require_session(),
+            None => Ok(()),
+        }
+    }
+
+    // Falls back to the legacy `token` body/form field while the deprecation window is open.
+    // Disable it with ALLOW_BODY_TOKENS=false once every client sends headers.
+    pub fn or_token(&self, token: Option<&String>, conn: &MainPGDatabase) -> Result<String, String> {
+        if let Some(user) = &self.0 {
+            return Ok(user.user_id.clone());
+        }
+
+        let token = match token {
+            Some(token) if config::env_or("ALLOW_BODY_TOKENS", true) => token,
+            _ => return Err(String::from("ERR_AUTH_FAILED")),
+        };
+
+        println!(
+            "[{}] Request authenticated with a body token. This is deprecated, use the Authorization header.",
+            "AUTH".yellow()
+        );
+
+        match account_services::is_authenticated(token, conn) {
+            Ok(user_id) => Ok(user_id),
+            Err(_err) => Err(String::from("ERR_AUTH_FAILED")),
+        }
+    }
+}
+
+// Resolved permission sets for the lifetime of one request, so a route that checks several
+// nodes (or checks for several users) only hits the database once per user.
+pub struct PermissionCache {
+    resolved: Mutex<HashMap<String, PermissionSet>>,
+}
+
+impl PermissionCache {
+    pub fn has_perms(
+        &self,
+        user_id: &String,
+        permission: &str,
+        conn: &MainPGDatabase,
+        exact: bool,
+    ) -> Result<bool, String> {
+        let mut resolved = self
+            .resolved
+            .lock()
+            .expect("PermissionCache mutex poisoned");
+
+        if !resolved.contains_key(user_id) {
+            resolved.insert(user_id.clone(), permissions::resolve(user_id, conn)?);
+        }
+
+        Ok(resolved[user_id].has(permission, exact))
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for &'a PermissionCache {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(|| PermissionCache {
+            resolved: Mutex::new(HashMap::new()),
+        }))
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AuditContext {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let impersonator_id = match cached_user(request) {
+            Ok(user) => user.impersonator_id.clone(),
+            Err(_err) => None,
+        };
+
+        Outcome::Success(AuditContext {
+            ip_address: request.remote().map(|addr| addr.ip().to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+            impersonator_id,
+        })
+    }
+}
+
+// Shared by the admin routes: resolves to the same error bodies premium.rs has always used.
+pub fn require_permission(
+    user_id: &String,
+    permission: &str,
+    perms: &PermissionCache,
+    conn: &MainPGDatabase,
+) -> Result<(), JsonValue> {
+    match perms.has_perms(user_id, permission, conn, false) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(json!({"success":false, "message": String::from("PERMISSION_DENIED")})),
+        Err(_err) => Err(json!({"success":false, "message": String::from("ERR_INTERNAL_ERR")})),
+    }
+}
+
+// Adds X-RateLimit-* headers to every response for a request made with an API key.
+// Attach with `.attach(RateLimitHeaders)` when building the rocket.
+pub struct RateLimitHeaders;
+
+impl Fairing for RateLimitHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "API key rate limit headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let quota = match request.local_cache(|| None::<QuotaStatus>) {
+            Some(quota) => quota,
+            None => return,
+        };
+
+        response.set_raw_header("X-RateLimit-Limit", quota.limit.to_string());
+        response.set_raw_header("X-RateLimit-Remaining", quota.remaining.to_string());
+        response.set_raw_header("X-RateLimit-Reset", quota.reset.timestamp().to_string());
+
+        if quota.exceeded {
+            let retry_after = (quota.reset - chrono::Utc::now()).num_seconds().max(1);
+            response.set_raw_header("Retry-After", retry_after.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::ContentType;
+    use rocket::local::Client;
+
+    // Reports what the guard sees for whichever route it runs on.
+    struct Matched(Option<&'static str>, bool);
+
+    impl<'a, 'r> FromRequest<'a, 'r> for Matched {
+        type Error = ();
+
+        fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+            Outcome::Success(Matched(route_scope(request), allowed_read_only(request)))
+        }
+    }
+
+    #[post("/scripts/createScript")]
+    fn create_script(matched: Matched) -> String {
+        format!("{:?}", matched.0)
+    }
+
+    // Named like the real route, the table matches on the declared path.
+    #[get("/scripts/<script_id>/versions")]
+    fn script_versions(script_id: String, matched: Matched) -> String {
+        let _ = script_id;
+        format!("{:?}", matched.0)
+    }
+
+    #[post("/auth/logout")]
+    fn logout(matched: Matched) -> String {
+        format!("{:?} {}", matched.0, matched.1)
+    }
+
+    fn client(base: &str) -> Client {
+        let rocket = rocket::custom(rocket::Config::development())
+            .mount(base, routes![create_script, script_versions, logout]);
+
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn route_checks_ignore_the_mount_base() {
+        for base in &["/", "/api/v2"] {
+            let client = client(base);
+            let prefix = base.trim_end_matches('/');
+
+            let mut response = client
+                .post(format!("{}/scripts/createScript", prefix))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(response.body_string(), Some(format!("{:?}", Some(api_keys::SCOPE_SCRIPTS_WRITE))));
+
+            let mut response = client.get(format!("{}/scripts/abc/versions", prefix)).dispatch();
+            assert_eq!(response.body_string(), Some(format!("{:?}", Some(api_keys::SCOPE_SCRIPTS_READ))));
+
+            let mut response = client.post(format!("{}/auth/logout", prefix)).dispatch();
+            assert_eq!(response.body_string(), Some(String::from("None true")));
+        }
+    }
+}
@@ -1,3 +1,5 @@
 pub mod auth;
+pub mod guards;
 pub mod payments;
 pub mod scripts;
+pub mod users;
@@ -8,18 +8,21 @@ use serde::Deserialize;
 use stripe;
 
 use crate::{
-    modules::{account_services, paypal, stripe_additions},
+    modules::{account_services::email_verification, paypal, stripe_additions},
+    routes::guards::OptionalUser,
     MainPGDatabase,
 };
 #[derive(Deserialize)]
 pub struct CreateOrderIDRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub orderType: i8,
 }
 
 #[derive(Deserialize)]
 pub struct GetOrderIDRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub orderID: String,
     pub orderType: i8,
 }
@@ -32,13 +35,14 @@ pub struct GetOrderIDRequest {
 pub fn paypal_create_order_id(
     conn: MainPGDatabase,
     shared_state: State<crate::SharedState>,
+    user: OptionalUser,
     request_data: Json<CreateOrderIDRequest>,
 ) -> Result<JsonValue, Custom<JsonValue>> {
-    match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(string) => string,
         Err(_err) => {
             return Err(Custom(
-                Status::BadRequest,
+                Status::Unauthorized,
                 json!({
                   "success": false,
                   "message": "ERR_INVALID_AUTH"
@@ -47,6 +51,29 @@ pub fn paypal_create_order_id(
         }
     };
 
+    if let Err(err) = user.require_session() {
+        return Err(Custom(
+            Status::Forbidden,
+            json!({
+              "success": false,
+              "message": err
+            }),
+        ));
+    }
+
+    match email_verification::ensure_verified(&user_id, &conn) {
+        Ok(_data) => (),
+        Err(err) => {
+            return Err(Custom(
+                Status::Forbidden,
+                json!({
+                  "success": false,
+                  "message": err
+                }),
+            ))
+        }
+    };
+
     let order_amount = match request_data.orderType {
         0 => "6.49",
         1 => "29.99",
@@ -89,13 +116,14 @@ pub fn paypal_create_order_id(
 pub fn paypal_get_order_id(
     conn: MainPGDatabase,
     shared_state: State<crate::SharedState>,
+    user: OptionalUser,
     request_data: Json<GetOrderIDRequest>,
 ) -> Result<JsonValue, Custom<JsonValue>> {
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(string) => string,
         Err(_err) => {
             return Err(Custom(
-                Status::BadRequest,
+                Status::Unauthorized,
                 json!({
                   "success": false,
                   "message": "ERR_INVALID_AUTH"
@@ -104,6 +132,16 @@ pub fn paypal_get_order_id(
         }
     };
 
+    if let Err(err) = user.require_session() {
+        return Err(Custom(
+            Status::Forbidden,
+            json!({
+              "success": false,
+              "message": err
+            }),
+        ));
+    }
+
     let order_amount = match request_data.orderType {
         0 => "6.49",
         1 => "29.99",
@@ -164,13 +202,14 @@ pub fn paypal_get_order_id(
 pub fn stripe_create_order_id(
     conn: MainPGDatabase,
     shared_state: State<crate::SharedState>,
+    user: OptionalUser,
     request_data: Json<CreateOrderIDRequest>,
 ) -> Result<JsonValue, Custom<JsonValue>> {
-    let user_id = match account_services::is_authenticated(&request_data.token, &conn) {
+    let user_id = match user.or_token(request_data.token.as_ref(), &conn) {
         Ok(string) => string,
         Err(_err) => {
             return Err(Custom(
-                Status::BadRequest,
+                Status::Unauthorized,
                 json!({
                   "success": false,
                   "message": "ERR_INVALID_AUTH"
@@ -179,6 +218,29 @@ pub fn stripe_create_order_id(
         }
     };
 
+    if let Err(err) = user.require_session() {
+        return Err(Custom(
+            Status::Forbidden,
+            json!({
+              "success": false,
+              "message": err
+            }),
+        ));
+    }
+
+    match email_verification::ensure_verified(&user_id, &conn) {
+        Ok(_data) => (),
+        Err(err) => {
+            return Err(Custom(
+                Status::Forbidden,
+                json!({
+                  "success": false,
+                  "message": err
+                }),
+            ))
+        }
+    };
+
     let priceID = match request_data.orderType {
         0 => "price_1IK90rEnq0tzcOdN219ShCgm",
         1 => "price_1IK90nEnq0tzcOdNWXozTZ4p",
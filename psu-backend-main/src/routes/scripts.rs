@@ -1,10 +1,16 @@
 use rocket::http::{ContentType, Status};
-use rocket::response::status::Custom;
+use rocket::request::Request;
+use rocket::response::{self, status::Custom, Responder, Response};
 use rocket::Data;
 use rocket_contrib::json::{Json, JsonValue};
 use script_services::create_new_script;
 use serde::Deserialize;
 
+use crate::routes::guards::{AuthenticatedUser, OptionalUser};
+use crate::modules::account_services::api_keys as scopes;
+use crate::modules::audit_log::{self, AuditContext};
+use crate::modules::lua_syntax;
+use crate::modules::script_services::SaveError;
 use crate::{modules::script_services, MainPGDatabase};
 
 // #[post("/upload", data = "<data>")]
@@ -35,14 +41,13 @@ use crate::{modules::script_services, MainPGDatabase};
 //     }
 // }
 
-// having a streaming output would be nice; there's one for returning a `Read` impl
-// but not one that you can `write()` to
-
 #[post("/scripts/updateScript", data = "<data>")]
 pub fn update_script(
     cont_type: &ContentType,
     data: Data,
     conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
 ) -> Result<JsonValue, Custom<JsonValue>> {
     if !cont_type.is_form_data() {
         return Err(Custom(
@@ -67,22 +72,57 @@ pub fn update_script(
             )
         })?;
 
-    let script_id = match script_services::update_script(&boundary, &conn, data) {
-        Ok(result) => result,
+    let entries = match script_services::process_multipart(boundary, data, script_services::upload_limit()) {
+        Ok(data) => data,
         Err(err) => {
+            println!("{}", err);
             return Err(Custom(
                 Status::BadRequest,
                 json!({
                   "success": false,
-                  "message": err
+                  "message": multipart_error(err)
                 }),
+            ));
+        }
+    };
+    let form_fields = &entries.fields;
+
+    let user_id = match user.or_token(
+        script_services::optional_field_string(form_fields, "token").as_ref(),
+        &conn,
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            return Err(Custom(
+                Status::Unauthorized,
+                json!({"success": false, "message": err}),
             ))
         }
     };
 
+    user.require_scope(scopes::SCOPE_SCRIPTS_WRITE).map_err(forbidden)?;
+
+    let script_id = script_services::optional_field_string(form_fields, "scriptID");
+
+    let version = match script_services::update_script(&user_id, form_fields, &conn) {
+        Ok(result) => {
+            audit_log::record(
+                Some(&user_id),
+                None,
+                "script.update",
+                serde_json::json!({"script_id": script_id, "version": result}),
+                &audit,
+                &conn,
+            );
+            result
+        }
+        Err(err) => return Err(save_error(err)),
+    };
+
     Ok(json!({
       "success": true,
-      "scriptID": script_id
+      "scriptID": script_id,
+      "version": version
     }))
 }
 
@@ -91,6 +131,8 @@ pub fn create_script(
     cont_type: &ContentType,
     data: Data,
     conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
 ) -> Result<JsonValue, Custom<JsonValue>> {
     if !cont_type.is_form_data() {
         return Err(Custom(
@@ -116,7 +158,7 @@ pub fn create_script(
         })?;
 
     // Convert our data into a field hashmap.
-    let form_fields = match script_services::process_multipart(boundary, data) {
+    let entries = match script_services::process_multipart(boundary, data, script_services::upload_limit()) {
         Ok(data) => data,
         Err(err) => {
             println!("{}", err);
@@ -124,17 +166,17 @@ pub fn create_script(
                 Status::BadRequest,
                 json!({
                   "success": false,
-                  "message": "Failed to process multidata. Is it corrupted?"
+                  "message": multipart_error(err)
                 }),
             ));
         }
     };
+    let form_fields = &entries.fields;
 
     // Perform checks on fields
     if !(form_fields.contains_key("title")
         && form_fields.contains_key("description")
-        && form_fields.contains_key("public")
-        && form_fields.contains_key("token"))
+        && form_fields.contains_key("public"))
     {
         return Err(Custom(
             Status::BadRequest,
@@ -145,7 +187,22 @@ pub fn create_script(
         ));
     }
 
-    let script = match script_services::Script::fields_to_self(&form_fields) {
+    let user_id = match user.or_token(
+        script_services::optional_field_string(form_fields, "token").as_ref(),
+        &conn,
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            return Err(Custom(
+                Status::Unauthorized,
+                json!({"success": false, "message": err}),
+            ))
+        }
+    };
+
+    user.require_scope(scopes::SCOPE_SCRIPTS_WRITE).map_err(forbidden)?;
+
+    let script = match script_services::Script::fields_to_self(form_fields) {
         Ok(data) => data,
         Err(err) => {
             println!("{}", err);
@@ -159,17 +216,22 @@ pub fn create_script(
         }
     };
 
-    let script_id = match create_new_script(&conn, script, boundary) {
-        Ok(result) => result,
-        Err(err) => {
-            return Err(Custom(
-                Status::BadRequest,
-                json!({
-                  "success": false,
-                  "message": err
-                }),
-            ))
+    let title = script.title.clone();
+    let public = script.public;
+
+    let script_id = match create_new_script(&conn, &user_id, script) {
+        Ok(result) => {
+            audit_log::record(
+                Some(&user_id),
+                None,
+                "script.create",
+                serde_json::json!({"script_id": result, "title": title, "public": public}),
+                &audit,
+                &conn,
+            );
+            result
         }
+        Err(err) => return Err(save_error(err)),
     };
 
     Ok(json!({
@@ -180,7 +242,163 @@ pub fn create_script(
 
 #[derive(Deserialize)]
 pub struct UpdateScriptRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+fn unauthorized(err: String) -> Custom<JsonValue> {
+    Custom(
+        Status::Unauthorized,
+        json!({
+          "success": false,
+          "message": err
+        }),
+    )
+}
+
+fn forbidden(err: String) -> Custom<JsonValue> {
+    Custom(
+        Status::Forbidden,
+        json!({
+          "success": false,
+          "message": err
+        }),
+    )
+}
+
+// Syntax errors come back with where they are, in the same shape `/scripts/validate` uses.
+fn save_error(err: SaveError) -> Custom<JsonValue> {
+    match err {
+        SaveError::Message(message) => bad_request(message),
+        SaveError::Syntax(diagnostic) => Custom(
+            Status::BadRequest,
+            json!({
+              "success": false,
+              "message": "ERR_SYNTAX_ERROR",
+              "diagnostics": [diagnostic]
+            }),
+        ),
+    }
+}
+
+// Too large is worth telling the client about, anything else is most likely a broken body.
+fn multipart_error(err: String) -> String {
+    if err == "ERR_UPLOAD_TOO_LARGE" {
+        err
+    } else {
+        String::from("Failed to process multidata. Is it corrupted?")
+    }
+}
+
+// A script or version body, streamed straight from storage. Answers `If-None-Match` with a
+// 304 when the stored hash matches, and a single `Range` with a 206. Multiple ranges are
+// answered with the whole body, which the spec allows.
+pub struct ScriptDownload(pub script_services::ScriptObject);
+
+enum RangeRequest {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return RangeRequest::Full,
+    };
+
+    let mut parts = spec.splitn(2, '-');
+    let (start, end) = match (parts.next(), parts.next()) {
+        (Some(start), Some(end)) => (start.trim(), end.trim()),
+        _ => return RangeRequest::Full,
+    };
+
+    // `-n` asks for the last n bytes.
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_suffix) if size == 0 => RangeRequest::Unsatisfiable,
+            Ok(suffix) => RangeRequest::Partial(size - suffix.min(size), size - 1),
+            Err(_err) => RangeRequest::Full,
+        };
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(data) => data,
+        Err(_err) => return RangeRequest::Full,
+    };
+
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    let end = if end.is_empty() {
+        size - 1
+    } else {
+        match end.parse::<u64>() {
+            Ok(data) if data >= start => data.min(size - 1),
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    RangeRequest::Partial(start, end)
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+impl<'r> Responder<'r> for ScriptDownload {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let object = self.0;
+        let etag = object.sha256.as_ref().map(|sha256| format!("\"{}\"", sha256));
+
+        let mut response = Response::build();
+        response.raw_header("Accept-Ranges", "bytes");
+
+        if let Some(etag) = &etag {
+            response.raw_header("ETag", etag.clone());
+
+            if let Some(header) = request.headers().get_one("If-None-Match") {
+                if etag_matches(header, etag) {
+                    return response.status(Status::NotModified).ok();
+                }
+            }
+        }
+
+        let range = match parse_range(request.headers().get_one("Range"), object.size) {
+            RangeRequest::Full => None,
+            RangeRequest::Partial(start, end) => Some((start, end)),
+            RangeRequest::Unsatisfiable => {
+                return response
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", object.size))
+                    .ok();
+            }
+        };
+
+        let body = match script_services::open_object(&object, range) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("{}", err);
+                return Err(Status::InternalServerError);
+            }
+        };
+
+        if let Some((start, end)) = range {
+            response
+                .status(Status::PartialContent)
+                .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, object.size));
+        }
+
+        response
+            .header(ContentType::Binary)
+            .streamed_body(body)
+            .ok()
+    }
 }
 
 // #[post("/scripts/updateScript", format = "json", data = "<request_data>")]
@@ -191,16 +409,11 @@ pub struct UpdateScriptRequest {
 //   }))
 // }
 
-#[post(
-    "/scripts/private/getAllScripts",
-    format = "json",
-    data = "<request_data>"
-)]
-pub fn get_all_scripts(
-    conn: MainPGDatabase,
-    request_data: Json<UpdateScriptRequest>,
+fn private_scripts_response(
+    user_id: &String,
+    conn: &MainPGDatabase,
 ) -> Result<JsonValue, Custom<JsonValue>> {
-    let data = match script_services::get_private_scripts(&request_data.token, &conn) {
+    let data = match script_services::get_private_scripts(user_id, conn) {
         Ok(data) => data,
         Err(err) => {
             return Err(Custom(
@@ -219,9 +432,38 @@ pub fn get_all_scripts(
     }))
 }
 
+#[post(
+    "/scripts/private/getAllScripts",
+    format = "json",
+    data = "<request_data>"
+)]
+pub fn get_all_scripts(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<UpdateScriptRequest>,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    private_scripts_response(&user_id, &conn)
+}
+
+#[get("/scripts/private/getAllScripts")]
+pub fn get_all_scripts_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    private_scripts_response(&user.user_id, &conn)
+}
+
 #[derive(Deserialize)]
 pub struct updatePubScriptRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub script_id: String,
     pub new_location: String,
 }
@@ -233,14 +475,28 @@ pub struct updatePubScriptRequest {
 )]
 pub fn update_pub_script(
     conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
     request_data: Json<updatePubScriptRequest>,
 ) -> Result<JsonValue, Custom<JsonValue>> {
-    return match script_services::update_public_script(
-        &conn,
-        &request_data.token,
-        &request_data.script_id,
-    ) {
-        Ok(_) => Ok(json!({"success": true, "message": "SUCCESS"})),
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_WRITE).map_err(forbidden)?;
+
+    return match script_services::update_public_script(&conn, &user_id, &request_data.script_id)
+    {
+        Ok(_) => {
+            audit_log::record(
+                Some(&user_id),
+                None,
+                "script.publish",
+                serde_json::json!({"script_id": request_data.script_id}),
+                &audit,
+                &conn,
+            );
+            Ok(json!({"success": true, "message": "SUCCESS"}))
+        }
         Err(err) => Err(Custom(
             Status::BadRequest,
             json!({"success": false, "message": err}),
@@ -248,16 +504,8 @@ pub fn update_pub_script(
     };
 }
 
-#[post(
-    "/scripts/public/getAllScripts",
-    format = "json",
-    data = "<request_data>"
-)]
-pub fn get_all_scripts_pub(
-    conn: MainPGDatabase,
-    request_data: Json<UpdateScriptRequest>,
-) -> Result<JsonValue, Custom<JsonValue>> {
-    let data = match script_services::get_public_scripts(&conn, &request_data.token) {
+fn public_scripts_response(conn: &MainPGDatabase) -> Result<JsonValue, Custom<JsonValue>> {
+    let data = match script_services::get_public_scripts(conn) {
         Ok(data) => data,
         Err(err) => {
             return Err(Custom(
@@ -276,47 +524,110 @@ pub fn get_all_scripts_pub(
     }))
 }
 
+#[post(
+    "/scripts/public/getAllScripts",
+    format = "json",
+    data = "<request_data>"
+)]
+pub fn get_all_scripts_pub(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<UpdateScriptRequest>,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    user.or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    public_scripts_response(&conn)
+}
+
+#[get("/scripts/public/getAllScripts")]
+pub fn get_all_scripts_pub_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    public_scripts_response(&conn)
+}
+
 #[derive(Deserialize)]
 pub struct GetScriptRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub scriptID: String,
 }
 
+fn script_response(
+    user_id: &String,
+    script_id: &String,
+    conn: &MainPGDatabase,
+) -> Result<ScriptDownload, Custom<JsonValue>> {
+    match script_services::script_object(user_id, script_id, conn) {
+        Ok(data) => Ok(ScriptDownload(data)),
+        Err(err) => Err(Custom(
+            Status::BadRequest,
+            json!({
+              "success": false,
+              "message": err.to_string()
+            }),
+        )),
+    }
+}
+
 #[post("/scripts/getScript", format = "json", data = "<request_data>")]
 pub fn get_script(
     conn: MainPGDatabase,
+    user: OptionalUser,
     request_data: Json<GetScriptRequest>,
-) -> Result<Vec<u8>, Custom<JsonValue>> {
-    let data = match script_services::get_script(&request_data.token, &request_data.scriptID, &conn)
-    {
-        Ok(data) => data,
-        Err(err) => {
-            return Err(Custom(
-                Status::BadRequest,
-                json!({
-                  "success": false,
-                  "message": err.to_string()
-                }),
-            ));
-        }
-    };
+) -> Result<ScriptDownload, Custom<JsonValue>> {
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    script_response(&user_id, &request_data.scriptID, &conn)
+}
+
+#[get("/scripts/getScript/<script_id>")]
+pub fn get_script_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+    script_id: String,
+) -> Result<ScriptDownload, Custom<JsonValue>> {
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
 
-    Ok(data)
+    script_response(&user.user_id, &script_id, &conn)
 }
 
 #[derive(Deserialize)]
 pub struct DeleteScriptRequest {
-    pub token: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub scriptID: String,
 }
 
 #[post("/scripts/deleteScript", format = "json", data = "<request_data>")]
 pub fn delete_script(
     conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
     request_data: Json<DeleteScriptRequest>,
 ) -> Result<JsonValue, Custom<JsonValue>> {
-    match script_services::delete_script(&conn, &request_data.scriptID, &request_data.token) {
-        Ok(data) => data,
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_WRITE).map_err(forbidden)?;
+
+    match script_services::delete_script(&conn, &request_data.scriptID, &user_id) {
+        Ok(_data) => audit_log::record(
+            Some(&user_id),
+            None,
+            "script.delete",
+            serde_json::json!({"script_id": request_data.scriptID}),
+            &audit,
+            &conn,
+        ),
         Err(err) => {
             return Err(Custom(
                 Status::BadRequest,
@@ -333,3 +644,223 @@ pub fn delete_script(
       "message": "SUCCESS"
     }))
 }
+
+#[derive(Deserialize)]
+pub struct ScriptVersionsRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub scriptID: String,
+}
+
+fn bad_request(err: String) -> Custom<JsonValue> {
+    Custom(
+        Status::BadRequest,
+        json!({
+          "success": false,
+          "message": err
+        }),
+    )
+}
+
+fn versions_response(
+    user_id: &String,
+    script_id: &String,
+    conn: &MainPGDatabase,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    let data = script_services::versions::list_versions(user_id, script_id, conn).map_err(bad_request)?;
+
+    Ok(json!({
+      "success": true,
+      "data": data
+    }))
+}
+
+#[post("/scripts/versions", format = "json", data = "<request_data>")]
+pub fn list_versions(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<ScriptVersionsRequest>,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    versions_response(&user_id, &request_data.scriptID, &conn)
+}
+
+// Ranked below /scripts/getScript/<script_id>, which overlaps it.
+#[get("/scripts/<script_id>/versions", rank = 2)]
+pub fn list_versions_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+    script_id: String,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    versions_response(&user.user_id, &script_id, &conn)
+}
+
+#[derive(Deserialize)]
+pub struct ScriptVersionRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub scriptID: String,
+    pub version: i32,
+}
+
+#[post("/scripts/versions/get", format = "json", data = "<request_data>")]
+pub fn get_version(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<ScriptVersionRequest>,
+) -> Result<ScriptDownload, Custom<JsonValue>> {
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    script_services::versions::version_object(&user_id, &request_data.scriptID, request_data.version, &conn)
+        .map(ScriptDownload)
+        .map_err(bad_request)
+}
+
+#[get("/scripts/<script_id>/versions/<version>")]
+pub fn get_version_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+    script_id: String,
+    version: i32,
+) -> Result<ScriptDownload, Custom<JsonValue>> {
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    script_services::versions::version_object(&user.user_id, &script_id, version, &conn)
+        .map(ScriptDownload)
+        .map_err(bad_request)
+}
+
+#[post("/scripts/versions/restore", format = "json", data = "<request_data>")]
+pub fn restore_version(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    audit: AuditContext,
+    request_data: Json<ScriptVersionRequest>,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_WRITE).map_err(forbidden)?;
+
+    let version = script_services::versions::restore(&user_id, &request_data.scriptID, request_data.version, &conn)
+        .map_err(bad_request)?;
+
+    audit_log::record(
+        Some(&user_id),
+        None,
+        "script.restore",
+        serde_json::json!({
+            "script_id": request_data.scriptID,
+            "restored_from": request_data.version,
+            "version": version
+        }),
+        &audit,
+        &conn,
+    );
+
+    Ok(json!({
+      "success": true,
+      "scriptID": request_data.scriptID,
+      "version": version
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ScriptDiffRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub scriptID: String,
+    pub from: i32,
+    pub to: i32,
+}
+
+#[post("/scripts/versions/diff", format = "json", data = "<request_data>")]
+pub fn diff_versions(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<ScriptDiffRequest>,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    let data = script_services::versions::diff(
+        &user_id,
+        &request_data.scriptID,
+        request_data.from,
+        request_data.to,
+        &conn,
+    )
+    .map_err(bad_request)?;
+
+    Ok(json!({
+      "success": true,
+      "data": data
+    }))
+}
+
+#[get("/scripts/<script_id>/diff/<from>/<to>")]
+pub fn diff_versions_header(
+    conn: MainPGDatabase,
+    user: AuthenticatedUser,
+    script_id: String,
+    from: i32,
+    to: i32,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    let data = script_services::versions::diff(&user.user_id, &script_id, from, to, &conn).map_err(bad_request)?;
+
+    Ok(json!({
+      "success": true,
+      "data": data
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ValidateScriptRequest {
+    #[serde(default)]
+    pub token: Option<String>,
+    pub source: String,
+}
+
+// Lets editors check a script without saving it. Invalid sources are still a successful
+// request, `valid` says whether it parsed.
+#[post("/scripts/validate", format = "json", data = "<request_data>")]
+pub fn validate_script(
+    conn: MainPGDatabase,
+    user: OptionalUser,
+    request_data: Json<ValidateScriptRequest>,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    let user_id = user
+        .or_token(request_data.token.as_ref(), &conn)
+        .map_err(unauthorized)?;
+    user.require_scope(scopes::SCOPE_SCRIPTS_READ).map_err(forbidden)?;
+
+    if request_data.source.len() as u64 > script_services::max_script_bytes(&user_id, &conn) {
+        return Err(bad_request(String::from("ERR_SCRIPT_TOO_LARGE")));
+    }
+
+    match lua_syntax::check(request_data.source.as_bytes()) {
+        Ok(_data) => Ok(json!({
+          "success": true,
+          "valid": true,
+          "diagnostics": []
+        })),
+        Err(diagnostic) => Ok(json!({
+          "success": true,
+          "valid": false,
+          "diagnostics": [diagnostic]
+        })),
+    }
+}
@@ -0,0 +1,21 @@
+use rocket::http::uri::Uri;
+use rocket::response::Redirect;
+use rocket_contrib::json::JsonValue;
+
+use crate::modules::account_services::usernames::{self, Lookup};
+use crate::MainPGDatabase;
+
+// Public profile by username. Names that were changed recently redirect to the new one so
+// old links keep working.
+#[get("/users/<username>")]
+pub fn get_profile(conn: MainPGDatabase, username: String) -> Result<JsonValue, Redirect> {
+    match usernames::lookup(&username, &conn) {
+        Ok(Lookup::Found(profile)) => Ok(json!({"success": true, "user": profile})),
+        Ok(Lookup::Renamed(current)) => Err(Redirect::permanent(format!(
+            "/users/{}",
+            Uri::percent_encode(&current)
+        ))),
+        Ok(Lookup::NotFound) => Ok(json!({"success": false, "message": "ERR_USER_NOT_FOUND"})),
+        Err(err) => Ok(json!({"success": false, "message": err})),
+    }
+}